@@ -0,0 +1,89 @@
+use crate::utils::app_state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+
+pub async fn list_clients_handler(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    match state.clients.list() {
+        Ok(clients) => {
+            let json_response = serde_json::json!({
+                "status": "success",
+                "data": clients
+            });
+            Ok(Json(json_response))
+        }
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error listing clients: {}", err)
+            });
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json_response)))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EnrollClient {
+    client_id: String,
+    name: String,
+    /// SEC1 P-256 verifying key, hex.
+    verifying_key: String,
+    operations: Vec<String>,
+}
+
+/// Enroll a client; the registry file is written before the client is accepted.
+pub async fn enroll_client_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<EnrollClient>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    match state.clients.enroll(
+        &payload.client_id,
+        &payload.name,
+        &payload.verifying_key,
+        payload.operations,
+    ) {
+        Ok(client) => {
+            let json_response = serde_json::json!({
+                "status": "success",
+                "data": client
+            });
+            Ok(Json(json_response))
+        }
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error enrolling client: {}", err)
+            });
+            Err((StatusCode::BAD_REQUEST, Json(json_response)))
+        }
+    }
+}
+
+/// Revoke a client; its requests are refused as soon as this returns.
+pub async fn revoke_client_handler(
+    State(state): State<AppState>,
+    Path(client_id): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    match state.clients.revoke(&client_id) {
+        Ok(client) => {
+            let json_response = serde_json::json!({
+                "status": "success",
+                "data": client
+            });
+            Ok(Json(json_response))
+        }
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error revoking client: {}", err)
+            });
+            Err((StatusCode::BAD_REQUEST, Json(json_response)))
+        }
+    }
+}
@@ -1,46 +1,81 @@
 use crate::utils::{
+    app_state::AppState,
     encryption,
+    hd_wallet::ETH_ACCOUNT_PATH,
     hsm_utils::{
-        hsm_generate_pk, sign_erc20, sign_raw_tx, verify_signature, SignTx, TxBroadcastRequest,
-        TxRequestTest,
+        decode_signed_tx, hsm_generate_pk, recover_message_signer, sign_batch, sign_contract_call,
+        sign_deploy, sign_erc20, sign_nft_transfer, sign_permit, sign_personal_message,
+        sign_raw_tx, sign_safe_tx, sign_typed_data, sign_unsigned_tx, verify_signature,
+        BatchTransferRequest, ContractCallRequest, DecodeTxRequest, DeployRequest,
+        NftTransferRequest, PermitRequest, RecoverRequest, RequestEnvelope, SafeTxRequest,
+        SignMessageRequest, SignTx, TxBroadcastRequest, TxRequestTest, TypedDataRequest,
+        UnsignedTxRequest,
     },
 };
-use axum::{http::StatusCode, response::IntoResponse, Json};
-use redis::Commands;
-use serde::Deserialize;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub async fn sign_erc20_transaction_handler(
-    Json(payload): Json<TxRequestTest>,
-) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    let deser_payload: SignTx =
-        serde_json::from_str(&payload.sign_tx).expect("Error parsing payload to SignTx Struct");
-    // println!("Deserialized payload: {:?}", deser_payload);
-    let red_path = dotenvy::var("RED_URL").expect("Redis URL not found");
-    let client = redis::Client::open(red_path).expect("Client not found");
-    let mut con = client.get_connection().expect("Connection failed");
-    let sk: Vec<u8> = con.get(payload.pk).expect("Key value not found");
+/// Decrypt a request sent over the confirmed ECDH session `payload.session_id`,
+/// verify the enrolled client's signature for `operation` and reject replays of
+/// it. Returns the request and the server-to-client key responses are encrypted
+/// under.
+fn open_payload<T: DeserializeOwned>(
+    state: &AppState,
+    operation: &str,
+    payload: &TxRequestTest,
+) -> Result<(T, Vec<u8>), Json<serde_json::Value>> {
+    let fail = |error_message: String| {
+        Json(serde_json::json!({
+            "status": "fail",
+            "data": error_message
+        }))
+    };
+    let deser_payload: SignTx = serde_json::from_str(&payload.sign_tx)
+        .map_err(|err| fail(format!("Error parsing payload to SignTx Struct: {}", err)))?;
+    let keys = state
+        .sessions
+        .keys(&payload.session_id)
+        .map_err(|err| fail(format!("Session error: {}", err)))?;
 
-    let decrypted_payload = encryption::decrypt(&deser_payload.message, &sk);
+    let decrypted_payload = encryption::try_decrypt(&deser_payload.message, &keys.client_to_server)
+        .map_err(|err| fail(format!("Error decrypting payload: {}", err)))?;
+    let decrypted_payload = String::from_utf8(decrypted_payload)
+        .map_err(|err| fail(format!("Error decrypting payload: {}", err)))?;
     println!("Decrypted payload: {:?}", decrypted_payload);
-    let tx_field: TxBroadcastRequest = serde_json::from_str(&decrypted_payload)
-        .expect("Error parsing decrypted payload to Broadcast Tx Struct");
-    // println!("Tx field after decrypted: {:?}", tx_field);
+    let envelope: RequestEnvelope<T> = serde_json::from_str(&decrypted_payload)
+        .map_err(|err| fail(format!("Error parsing decrypted payload: {}", err)))?;
+
     // ======== perform verification on the payload
-    if verify_signature(&deser_payload) {
-        println!("Verified Passed")
-    } else {
-        let error_message = "Signature verification failed";
-        let json_response = serde_json::json!({
-            "status": "fail",
-            "data": error_message
-        });
-        return Ok(Json(json_response));
-    }
+    verify_signature(&deser_payload, &state.clients, operation)
+        .map_err(|err| fail(format!("Signature verification failed: {}", err)))?;
+    println!("Verified Passed");
+    state
+        .sessions
+        .next_message(&payload.session_id, envelope.seq, envelope.timestamp)
+        .map_err(|err| fail(format!("Session error: {}", err)))?;
+    Ok((envelope.request, keys.server_to_client.to_vec()))
+}
 
-    let signed_transaction = match sign_erc20(&tx_field).await {
+pub async fn sign_erc20_transaction_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<TxRequestTest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let (tx_field, sk): (TxBroadcastRequest, _) =
+        match open_payload(&state, "sign-erc20-tx", &payload) {
+            Ok(opened) => opened,
+            Err(json_response) => return Ok(json_response),
+        };
+
+    let signed_transaction = match sign_erc20(&tx_field, state.key_store.as_ref()) {
         Ok(tx) => tx,
         Err(err) => {
-            let error_message = format!("Error retrieving origin network: {}", err);
+            let error_message = format!("Error signing ERC-20 transfer: {}", err);
             let json_response = serde_json::json!({
                 "status": "fail",
                 "data": error_message
@@ -68,40 +103,276 @@ pub async fn sign_erc20_transaction_handler(
 }
 
 pub async fn sign_raw_transaction_handler(
+    State(state): State<AppState>,
     Json(payload): Json<TxRequestTest>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
     println!(" ========= Payload: {:#?}", &payload);
-    let deser_payload: SignTx =
-        serde_json::from_str(&payload.sign_tx).expect("Error parsing payload to SignTx Struct");
-    // println!("Deserialized payload: {:?}", deser_payload);
-    let red_path = dotenvy::var("RED_URL").expect("Redis URL not found");
-    let client = redis::Client::open(red_path).expect("Client not found");
-    let mut con = client.get_connection().expect("Connection failed");
-    let sk: Vec<u8> = con.get(payload.pk).expect("Key value not found");
-
-    let decrypted_payload = encryption::decrypt(&deser_payload.message, &sk);
-    println!("Decrypted payload: {:?}", decrypted_payload);
-    let tx_field: TxBroadcastRequest = serde_json::from_str(&decrypted_payload)
-        .expect("Error parsing decrypted payload to Broadcast Tx Struct");
+    let (tx_field, _): (TxBroadcastRequest, _) = match open_payload(&state, "sign-raw-tx", &payload)
+    {
+        Ok(opened) => opened,
+        Err(json_response) => return Ok(json_response),
+    };
+    let signed_transaction = match sign_raw_tx(&tx_field, state.key_store.as_ref()).await {
+        Ok(tx) => tx,
+        Err(err) => {
+            let error_message = format!("Error signing raw transaction: {}", err);
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": error_message
+            });
+            return Ok(Json(json_response));
+        }
+    };
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": signed_transaction
+    });
+    Ok(Json(json_response))
+}
 
-    // ======== perform verification on the payload
-    if verify_signature(&deser_payload) {
-        println!("Verified Passed")
-    } else {
-        let error_message = "Signature verification failed";
-        let json_response = serde_json::json!({
-            "status": "fail",
-            "data": error_message
-        });
-        return Ok(Json(json_response));
+pub async fn sign_batch_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<TxRequestTest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let (batch, _): (BatchTransferRequest, _) = match open_payload(&state, "sign-batch", &payload) {
+        Ok(opened) => opened,
+        Err(json_response) => return Ok(json_response),
+    };
+    let signed_transactions = match sign_batch(&batch, state.key_store.as_ref()) {
+        Ok(txs) => txs,
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error signing batch: {}", err)
+            });
+            return Ok(Json(json_response));
+        }
+    };
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": signed_transactions
+    });
+    Ok(Json(json_response))
+}
+
+pub async fn sign_nft_transfer_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<TxRequestTest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let (transfer, _): (NftTransferRequest, _) =
+        match open_payload(&state, "sign-nft-transfer", &payload) {
+            Ok(opened) => opened,
+            Err(json_response) => return Ok(json_response),
+        };
+    let signed_transaction = match sign_nft_transfer(&transfer, state.key_store.as_ref()) {
+        Ok(tx) => tx,
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error signing NFT transfer: {}", err)
+            });
+            return Ok(Json(json_response));
+        }
+    };
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": signed_transaction
+    });
+    Ok(Json(json_response))
+}
+
+pub async fn sign_contract_call_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<TxRequestTest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let (call, _): (ContractCallRequest, _) =
+        match open_payload(&state, "sign-contract-call", &payload) {
+            Ok(opened) => opened,
+            Err(json_response) => return Ok(json_response),
+        };
+    let signed_transaction =
+        match sign_contract_call(&call, &state.abi_registry, state.key_store.as_ref()) {
+            Ok(tx) => tx,
+            Err(err) => {
+                let json_response = serde_json::json!({
+                    "status": "fail",
+                    "data": format!("Error signing contract call: {}", err)
+                });
+                return Ok(Json(json_response));
+            }
+        };
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": signed_transaction
+    });
+    Ok(Json(json_response))
+}
+
+pub async fn sign_deploy_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<TxRequestTest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let (deploy, _): (DeployRequest, _) = match open_payload(&state, "sign-deploy", &payload) {
+        Ok(opened) => opened,
+        Err(json_response) => return Ok(json_response),
+    };
+    let signed_transaction = match sign_deploy(&deploy, state.key_store.as_ref()) {
+        Ok(tx) => tx,
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error signing deployment: {}", err)
+            });
+            return Ok(Json(json_response));
+        }
+    };
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": signed_transaction
+    });
+    Ok(Json(json_response))
+}
+
+pub async fn sign_typed_data_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<TxRequestTest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let (request, _): (TypedDataRequest, _) =
+        match open_payload(&state, "sign-typed-data", &payload) {
+            Ok(opened) => opened,
+            Err(json_response) => return Ok(json_response),
+        };
+    let signed = match sign_typed_data(&request, &state.eip712_domains, state.key_store.as_ref()) {
+        Ok(signed) => signed,
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error signing typed data: {}", err)
+            });
+            return Ok(Json(json_response));
+        }
+    };
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": signed
+    });
+    Ok(Json(json_response))
+}
+
+pub async fn sign_permit_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<TxRequestTest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let (request, _): (PermitRequest, _) = match open_payload(&state, "sign-permit", &payload) {
+        Ok(opened) => opened,
+        Err(json_response) => return Ok(json_response),
+    };
+    let signed = match sign_permit(
+        &request,
+        &state.eip712_domains,
+        &state.permit_spenders,
+        state.key_store.as_ref(),
+    ) {
+        Ok(signed) => signed,
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error signing permit: {}", err)
+            });
+            return Ok(Json(json_response));
+        }
+    };
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": signed
+    });
+    Ok(Json(json_response))
+}
+
+pub async fn sign_safe_tx_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<TxRequestTest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let (request, _): (SafeTxRequest, _) = match open_payload(&state, "sign-safe-tx", &payload) {
+        Ok(opened) => opened,
+        Err(json_response) => return Ok(json_response),
+    };
+    let signed = match sign_safe_tx(&request, state.key_store.as_ref()) {
+        Ok(signed) => signed,
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error signing Safe transaction: {}", err)
+            });
+            return Ok(Json(json_response));
+        }
+    };
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": signed
+    });
+    Ok(Json(json_response))
+}
+
+pub async fn sign_message_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<TxRequestTest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let (request, _): (SignMessageRequest, _) = match open_payload(&state, "sign-message", &payload)
+    {
+        Ok(opened) => opened,
+        Err(json_response) => return Ok(json_response),
+    };
+    let signed = match sign_personal_message(&request, state.key_store.as_ref()) {
+        Ok(signed) => signed,
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error signing message: {}", err)
+            });
+            return Ok(Json(json_response));
+        }
+    };
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": signed
+    });
+    Ok(Json(json_response))
+}
+
+pub async fn recover_handler(
+    Json(request): Json<RecoverRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    match recover_message_signer(&request) {
+        Ok(address) => Ok(Json(serde_json::json!({
+            "status": "success",
+            "data": address
+        }))),
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error recovering signer: {}", err)
+            });
+            Err((StatusCode::BAD_REQUEST, Json(json_response)))
+        }
     }
-    let signed_transaction = match sign_raw_tx(&tx_field).await {
+}
+
+pub async fn sign_unsigned_tx_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<TxRequestTest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let (request, _): (UnsignedTxRequest, _) =
+        match open_payload(&state, "sign-unsigned-tx", &payload) {
+            Ok(opened) => opened,
+            Err(json_response) => return Ok(json_response),
+        };
+    let signed_transaction = match sign_unsigned_tx(&request, state.key_store.as_ref()) {
         Ok(tx) => tx,
         Err(err) => {
-            let error_message = format!("Error retrieving origin network: {}", err);
             let json_response = serde_json::json!({
                 "status": "fail",
-                "data": error_message
+                "data": format!("Error signing unsigned transaction: {}", err)
             });
             return Ok(Json(json_response));
         }
@@ -113,23 +384,178 @@ pub async fn sign_raw_transaction_handler(
     Ok(Json(json_response))
 }
 
+pub async fn decode_tx_handler(
+    Json(request): Json<DecodeTxRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    match decode_signed_tx(&request) {
+        Ok(decoded) => Ok(Json(serde_json::json!({
+            "status": "success",
+            "data": decoded
+        }))),
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error decoding transaction: {}", err)
+            });
+            Err((StatusCode::BAD_REQUEST, Json(json_response)))
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Pk {
     pk: Vec<u8>,
 }
 
 pub async fn exchange_public_key_handler(
+    State(state): State<AppState>,
     Json(pk): Json<Pk>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
     // let payload: Vec<u8> = serde_json::f(&pk).unwrap();
     println!("Received public key: {:?}", &pk.pk);
-    let pk = hsm_generate_pk(&pk.pk);
-    let pk_hex = format!("0x{}", hex::encode(&pk.0));
+    let client_pk = pk.pk;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let (hsm_pk, keys) = match hsm_generate_pk(&client_pk, timestamp) {
+        Ok(generated) => generated,
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error exchanging keys: {}", err)
+            });
+            return Err((StatusCode::BAD_REQUEST, Json(json_response)));
+        }
+    };
+    let pk_hex = format!("0x{}", hex::encode(&hsm_pk));
 
     println!("PK: {:?}", &pk_hex);
+    let key_confirmation = keys.server_confirmation();
+    let session = match state.sessions.open(&client_pk, keys) {
+        Ok(session) => session,
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error opening session: {}", err)
+            });
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json_response)));
+        }
+    };
+    let signed_exchange = match state.identity.sign_exchange(&hsm_pk, &client_pk, timestamp) {
+        Ok(signed_exchange) => signed_exchange,
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error signing key exchange: {}", err)
+            });
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json_response)));
+        }
+    };
+    let mut exchange = serde_json::json!(signed_exchange);
+    exchange["key_confirmation"] = serde_json::json!(key_confirmation);
+    exchange["session_id"] = serde_json::json!(session.id);
+    exchange["expires_at"] = serde_json::json!(session.expires_at);
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": exchange
+    });
+    Ok(Json(json_response))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeyConfirmation {
+    session_id: String,
+    confirmation: Vec<u8>,
+}
+
+/// Second leg of the exchange: the client proves it derived the same session
+/// keys, after which the session accepts requests.
+pub async fn confirm_key_handler(
+    State(state): State<AppState>,
+    Json(confirmation): Json<KeyConfirmation>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    match state
+        .sessions
+        .confirm(&confirmation.session_id, &confirmation.confirmation)
+    {
+        Ok(()) => {
+            let json_response = serde_json::json!({
+                "status": "success",
+                "data": "Session confirmed"
+            });
+            Ok(Json(json_response))
+        }
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error confirming session: {}", err)
+            });
+            Err((StatusCode::BAD_REQUEST, Json(json_response)))
+        }
+    }
+}
+
+/// Identity public key `/pk` responses are signed with, for the bridge to pin.
+pub async fn identity_handler(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": state.identity.info()
+    });
+    Ok(Json(json_response))
+}
+
+const MAX_ADDRESS_RANGE: u32 = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct AddressRange {
+    base_path: Option<String>,
+    start: Option<u32>,
+    count: Option<u32>,
+}
+
+/// List the addresses derived at `{base_path}/{start..start + count}` so the
+/// bridge can pre-register them.
+pub async fn derived_addresses_handler(
+    State(state): State<AppState>,
+    Query(range): Query<AddressRange>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let base_path = range
+        .base_path
+        .unwrap_or_else(|| ETH_ACCOUNT_PATH.to_string());
+    let start = range.start.unwrap_or(0);
+    let count = range.count.unwrap_or(10);
+    if count > MAX_ADDRESS_RANGE {
+        let json_response = serde_json::json!({
+            "status": "fail",
+            "data": format!("Count must not exceed {}", MAX_ADDRESS_RANGE)
+        });
+        return Err((StatusCode::BAD_REQUEST, Json(json_response)));
+    }
+
+    let mut addresses = Vec::with_capacity(count as usize);
+    for index in start..start.saturating_add(count) {
+        let path = format!("{}/{}", base_path, index);
+        match state.key_store.address(&path) {
+            Ok(address) => addresses.push(serde_json::json!({
+                "path": path,
+                "address": address,
+            })),
+            Err(err) => {
+                let json_response = serde_json::json!({
+                    "status": "fail",
+                    "data": format!("Error deriving address: {}", err)
+                });
+                return Err((StatusCode::BAD_REQUEST, Json(json_response)));
+            }
+        }
+    }
+
     let json_response = serde_json::json!({
         "status": "success",
-        "data": pk.0
+        "data": addresses
     });
     Ok(Json(json_response))
 }
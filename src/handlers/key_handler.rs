@@ -0,0 +1,85 @@
+use crate::utils::{app_state::AppState, key_versions::KeyState};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::Deserialize;
+
+pub async fn list_keys_handler(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": state.key_versions.list()
+    });
+    Ok(Json(json_response))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PromoteKey {
+    key_id: String,
+    key_ref: String,
+    grace_period_secs: Option<u64>,
+}
+
+pub async fn promote_key_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<PromoteKey>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let grace_period = payload.grace_period_secs.unwrap_or(0);
+    match state
+        .key_versions
+        .promote(&payload.key_id, &payload.key_ref, grace_period)
+    {
+        Ok(version) => {
+            let json_response = serde_json::json!({
+                "status": "success",
+                "data": {
+                    "key_id": payload.key_id,
+                    "key_version": version,
+                }
+            });
+            Ok(Json(json_response))
+        }
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error promoting key: {}", err)
+            });
+            Err((StatusCode::BAD_REQUEST, Json(json_response)))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeyStateChange {
+    key_id: String,
+    version: u32,
+    state: KeyState,
+}
+
+pub async fn set_key_state_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<KeyStateChange>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    match state
+        .key_versions
+        .set_state(&payload.key_id, payload.version, payload.state)
+    {
+        Ok(()) => {
+            let json_response = serde_json::json!({
+                "status": "success",
+                "data": {
+                    "key_id": payload.key_id,
+                    "key_version": payload.version,
+                    "state": payload.state,
+                }
+            });
+            Ok(Json(json_response))
+        }
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error changing key state: {}", err)
+            });
+            Err((StatusCode::BAD_REQUEST, Json(json_response)))
+        }
+    }
+}
@@ -1 +1,6 @@
+pub mod client_handler;
 pub mod hsm_handler;
+pub mod key_handler;
+pub mod rpc_handler;
+pub mod seal_handler;
+pub mod session_handler;
@@ -0,0 +1,41 @@
+use crate::utils::{app_state::AppState, rpc_signer};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+
+/// JSON-RPC 2.0 remote signer (`eth_accounts`, `eth_signTransaction`,
+/// `eth_sign`, `eth_signTypedData_v4`, `account_signData`). The body must be
+/// signed by an enrolled client through the `X-Client-Id`,
+/// `X-Client-Timestamp` and `X-Client-Signature` headers.
+pub async fn rpc_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| format!("Missing {} header", name))
+    };
+    let authenticated = header("x-client-id").and_then(|client_id| {
+        let timestamp = header("x-client-timestamp")?
+            .parse::<u64>()
+            .map_err(|err| format!("Invalid client timestamp: {}", err))?;
+        let signature = hex::decode(header("x-client-signature")?.trim_start_matches("0x"))
+            .map_err(|err| format!("Invalid client signature: {}", err))?;
+        rpc_signer::authenticate(&state, client_id, timestamp, &signature, &body)
+            .map_err(|err| format!("Client authentication failed: {}", err))
+    });
+    if let Err(err) = authenticated {
+        let json_response = serde_json::json!({
+            "status": "fail",
+            "data": err
+        });
+        return Err((StatusCode::UNAUTHORIZED, Json(json_response)));
+    }
+    Ok(Json(rpc_signer::handle_body(&state, &body)))
+}
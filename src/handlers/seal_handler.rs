@@ -0,0 +1,86 @@
+use crate::utils::{
+    app_state::AppState,
+    seal::{SealStatus, SealedKeyStore},
+};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::Deserialize;
+use std::sync::Arc;
+
+fn seal_or_fail(
+    state: &AppState,
+) -> Result<Arc<SealedKeyStore>, (StatusCode, Json<serde_json::Value>)> {
+    state.seal.clone().ok_or_else(|| {
+        let json_response = serde_json::json!({
+            "status": "fail",
+            "data": "HSM is not running in sealed mode"
+        });
+        (StatusCode::BAD_REQUEST, Json(json_response))
+    })
+}
+
+fn status_or_fail(
+    seal: &SealedKeyStore,
+) -> Result<SealStatus, (StatusCode, Json<serde_json::Value>)> {
+    seal.status().map_err(|err| {
+        let json_response = serde_json::json!({
+            "status": "fail",
+            "data": format!("Error reading seal status: {}", err)
+        });
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(json_response))
+    })
+}
+
+pub async fn seal_status_handler(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let seal = seal_or_fail(&state)?;
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": status_or_fail(&seal)?
+    });
+    Ok(Json(json_response))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnsealShare {
+    share: String,
+}
+
+pub async fn unseal_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<UnsealShare>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let seal = seal_or_fail(&state)?;
+    let status = match seal.submit_share(&payload.share) {
+        Ok(status) => status,
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error unsealing: {}", err)
+            });
+            return Err((StatusCode::BAD_REQUEST, Json(json_response)));
+        }
+    };
+    if !status.sealed {
+        if let Err(err) = state.key_versions.sync_keys() {
+            println!("Error registering unsealed keys: {}", err);
+        }
+    }
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": status
+    });
+    Ok(Json(json_response))
+}
+
+pub async fn seal_handler(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let seal = seal_or_fail(&state)?;
+    seal.seal();
+    let json_response = serde_json::json!({
+        "status": "success",
+        "data": status_or_fail(&seal)?
+    });
+    Ok(Json(json_response))
+}
@@ -0,0 +1,61 @@
+use crate::utils::app_state::AppState;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::Deserialize;
+
+pub async fn list_sessions_handler(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    match state.sessions.list() {
+        Ok(sessions) => {
+            let json_response = serde_json::json!({
+                "status": "success",
+                "data": sessions
+            });
+            Ok(Json(json_response))
+        }
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error listing sessions: {}", err)
+            });
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json_response)))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevokeSession {
+    session_id: String,
+}
+
+pub async fn revoke_session_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<RevokeSession>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    match state.sessions.revoke(&payload.session_id) {
+        Ok(true) => {
+            let json_response = serde_json::json!({
+                "status": "success",
+                "data": {
+                    "session_id": payload.session_id,
+                    "revoked": true,
+                }
+            });
+            Ok(Json(json_response))
+        }
+        Ok(false) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Unknown or expired session: {}", payload.session_id)
+            });
+            Err((StatusCode::NOT_FOUND, Json(json_response)))
+        }
+        Err(err) => {
+            let json_response = serde_json::json!({
+                "status": "fail",
+                "data": format!("Error revoking session: {}", err)
+            });
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json_response)))
+        }
+    }
+}
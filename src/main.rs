@@ -2,7 +2,21 @@ pub mod handlers;
 pub mod routes;
 pub mod utils;
 
-use crate::routes::hsm_router;
+use crate::routes::{
+    client_router, hsm_router, key_router, rpc_router, seal_router, session_router,
+};
+use crate::utils::{
+    abi_registry::AbiRegistry,
+    app_state::AppState,
+    client_registry::{self, ClientRegistry},
+    eip712::DomainAllowlist,
+    identity::IdentityKey,
+    key_store::{key_store_from_env, KeyStore},
+    key_versions::VersionedKeyStore,
+    permit::PermitSpenders,
+    seal::{self, SealedKeyStore},
+    session_store::Sessions,
+};
 use axum::{
     http::{
         header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
@@ -10,11 +24,20 @@ use axum::{
     },
     routing::Router,
 };
+use std::sync::Arc;
 use tokio::task;
 use tower_http::cors::CorsLayer;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("seal") {
+        return seal::run_cli(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("clients") {
+        return client_registry::run_cli(&args[2..]);
+    }
+
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
         .init();
@@ -32,9 +55,42 @@ async fn main() -> anyhow::Result<()> {
         .allow_methods([Method::GET, Method::POST])
         .allow_credentials(true)
         .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE]);
-    let app = Router::new()
-        .merge(hsm_router::sign_tx_routes())
-        .layer(cors);
+    let seal = match dotenvy::var("KEY_STORE").as_deref() {
+        Ok("sealed") => Some(Arc::new(SealedKeyStore::from_env()?)),
+        _ => None,
+    };
+    let key_store: Arc<dyn KeyStore> = match &seal {
+        Some(seal) => seal.clone(),
+        None => key_store_from_env()?,
+    };
+    let key_versions = Arc::new(VersionedKeyStore::from_env(key_store)?);
+    let state = AppState {
+        key_store: key_versions.clone(),
+        key_versions,
+        seal,
+        abi_registry: Arc::new(AbiRegistry::from_env()?),
+        eip712_domains: Arc::new(DomainAllowlist::from_env()?),
+        permit_spenders: Arc::new(PermitSpenders::from_env()?),
+        identity: Arc::new(IdentityKey::from_env()?),
+        sessions: Arc::new(Sessions::from_env()?),
+        clients: Arc::new(ClientRegistry::from_env()?),
+    };
+    println!(
+        "Identity key fingerprint: {}",
+        state.identity.info().fingerprint
+    );
+    let mut app = Router::new()
+        .merge(hsm_router::sign_tx_routes(&state))
+        .merge(key_router::key_routes())
+        .merge(seal_router::seal_routes())
+        .merge(session_router::session_routes())
+        .merge(client_router::client_routes());
+    // the JSON-RPC signer bypasses the encrypted sessions, so it is opt-in
+    if dotenvy::var("RPC_SIGNER_ENABLED").is_ok_and(|enabled| enabled == "true") {
+        println!("JSON-RPC signer enabled");
+        app = app.merge(rpc_router::rpc_routes(&state));
+    }
+    let app = app.with_state(state).layer(cors);
 
     println!("🚀 HSM Server started successfully, port {}", hsm_port);
 
@@ -0,0 +1,22 @@
+use crate::handlers::client_handler::{
+    enroll_client_handler, list_clients_handler, revoke_client_handler,
+};
+use crate::utils::{app_state::AppState, jwt_auth::auth};
+use axum::middleware;
+use axum::{
+    routing::{delete, get},
+    Router,
+};
+pub fn client_routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/clients",
+            get(list_clients_handler)
+                .post(enroll_client_handler)
+                .route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/clients/:client_id",
+            delete(revoke_client_handler).route_layer(middleware::from_fn(auth)),
+        )
+}
@@ -1,24 +1,139 @@
 use crate::handlers::hsm_handler::{
-    exchange_public_key_handler, sign_erc20_transaction_handler, sign_raw_transaction_handler,
+    confirm_key_handler, decode_tx_handler, derived_addresses_handler, exchange_public_key_handler,
+    identity_handler, recover_handler, sign_batch_handler, sign_contract_call_handler,
+    sign_deploy_handler, sign_erc20_transaction_handler, sign_message_handler,
+    sign_nft_transfer_handler, sign_permit_handler, sign_raw_transaction_handler,
+    sign_safe_tx_handler, sign_typed_data_handler, sign_unsigned_tx_handler,
 };
-use crate::utils::jwt_auth::auth;
+use crate::utils::{app_state::AppState, jwt_auth::auth, seal::require_unsealed};
 use axum::middleware;
 use axum::{
     routing::{get, post},
     Router,
 };
-pub fn sign_tx_routes() -> Router {
+pub fn sign_tx_routes(state: &AppState) -> Router<AppState> {
     Router::new()
         .route(
             "/sign-erc20-tx",
-            post(sign_erc20_transaction_handler).route_layer(middleware::from_fn(auth)),
+            post(sign_erc20_transaction_handler)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_unsealed,
+                ))
+                .route_layer(middleware::from_fn(auth)),
         )
         .route(
             "/sign-raw-tx",
-            post(sign_raw_transaction_handler).route_layer(middleware::from_fn(auth)),
+            post(sign_raw_transaction_handler)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_unsealed,
+                ))
+                .route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/sign-batch",
+            post(sign_batch_handler)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_unsealed,
+                ))
+                .route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/sign-nft-transfer",
+            post(sign_nft_transfer_handler)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_unsealed,
+                ))
+                .route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/sign-contract-call",
+            post(sign_contract_call_handler)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_unsealed,
+                ))
+                .route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/sign-deploy",
+            post(sign_deploy_handler)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_unsealed,
+                ))
+                .route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/sign-typed-data",
+            post(sign_typed_data_handler)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_unsealed,
+                ))
+                .route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/sign-permit",
+            post(sign_permit_handler)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_unsealed,
+                ))
+                .route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/sign-safe-tx",
+            post(sign_safe_tx_handler)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_unsealed,
+                ))
+                .route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/sign-unsigned-tx",
+            post(sign_unsigned_tx_handler)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_unsealed,
+                ))
+                .route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/sign-message",
+            post(sign_message_handler)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_unsealed,
+                ))
+                .route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/recover",
+            post(recover_handler).route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/decode-tx",
+            post(decode_tx_handler).route_layer(middleware::from_fn(auth)),
         )
         .route(
             "/pk",
             get(exchange_public_key_handler).route_layer(middleware::from_fn(auth)),
         )
+        .route(
+            "/pk/confirm",
+            post(confirm_key_handler).route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/identity",
+            get(identity_handler).route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/addresses",
+            get(derived_addresses_handler).route_layer(middleware::from_fn(auth)),
+        )
 }
@@ -0,0 +1,22 @@
+use crate::handlers::key_handler::{list_keys_handler, promote_key_handler, set_key_state_handler};
+use crate::utils::{app_state::AppState, jwt_auth::auth};
+use axum::middleware;
+use axum::{
+    routing::{get, post},
+    Router,
+};
+pub fn key_routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/keys",
+            get(list_keys_handler).route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/keys/promote",
+            post(promote_key_handler).route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/keys/state",
+            post(set_key_state_handler).route_layer(middleware::from_fn(auth)),
+        )
+}
@@ -1 +1,6 @@
+pub mod client_router;
 pub mod hsm_router;
+pub mod key_router;
+pub mod rpc_router;
+pub mod seal_router;
+pub mod session_router;
@@ -0,0 +1,15 @@
+use crate::handlers::rpc_handler::rpc_handler;
+use crate::utils::{app_state::AppState, jwt_auth::auth, seal::require_unsealed};
+use axum::middleware;
+use axum::{routing::post, Router};
+pub fn rpc_routes(state: &AppState) -> Router<AppState> {
+    Router::new().route(
+        "/rpc",
+        post(rpc_handler)
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_unsealed,
+            ))
+            .route_layer(middleware::from_fn(auth)),
+    )
+}
@@ -0,0 +1,22 @@
+use crate::handlers::seal_handler::{seal_handler, seal_status_handler, unseal_handler};
+use crate::utils::{app_state::AppState, jwt_auth::auth};
+use axum::middleware;
+use axum::{
+    routing::{get, post},
+    Router,
+};
+pub fn seal_routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/seal-status",
+            get(seal_status_handler).route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/unseal",
+            post(unseal_handler).route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/seal",
+            post(seal_handler).route_layer(middleware::from_fn(auth)),
+        )
+}
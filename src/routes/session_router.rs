@@ -0,0 +1,18 @@
+use crate::handlers::session_handler::{list_sessions_handler, revoke_session_handler};
+use crate::utils::{app_state::AppState, jwt_auth::auth};
+use axum::middleware;
+use axum::{
+    routing::{get, post},
+    Router,
+};
+pub fn session_routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/sessions",
+            get(list_sessions_handler).route_layer(middleware::from_fn(auth)),
+        )
+        .route(
+            "/sessions/revoke",
+            post(revoke_session_handler).route_layer(middleware::from_fn(auth)),
+        )
+}
@@ -0,0 +1,510 @@
+use anyhow::Error;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, str::FromStr};
+use web3::{
+    ethabi::{Contract, Function, Param, ParamType, Token},
+    types::{Address, U256},
+};
+
+/// Standard ERC-20 interface, used to encode token transfers offline.
+pub const ERC20_ABI: &str = r#"[
+  {"type":"function","name":"transfer","stateMutability":"nonpayable","inputs":[{"name":"to","type":"address"},{"name":"amount","type":"uint256"}],"outputs":[{"name":"","type":"bool"}]},
+  {"type":"function","name":"transferFrom","stateMutability":"nonpayable","inputs":[{"name":"from","type":"address"},{"name":"to","type":"address"},{"name":"amount","type":"uint256"}],"outputs":[{"name":"","type":"bool"}]},
+  {"type":"function","name":"approve","stateMutability":"nonpayable","inputs":[{"name":"spender","type":"address"},{"name":"amount","type":"uint256"}],"outputs":[{"name":"","type":"bool"}]},
+  {"type":"function","name":"allowance","stateMutability":"view","inputs":[{"name":"owner","type":"address"},{"name":"spender","type":"address"}],"outputs":[{"name":"","type":"uint256"}]},
+  {"type":"function","name":"balanceOf","stateMutability":"view","inputs":[{"name":"account","type":"address"}],"outputs":[{"name":"","type":"uint256"}]},
+  {"type":"function","name":"totalSupply","stateMutability":"view","inputs":[],"outputs":[{"name":"","type":"uint256"}]},
+  {"type":"function","name":"decimals","stateMutability":"view","inputs":[],"outputs":[{"name":"","type":"uint8"}]},
+  {"type":"function","name":"symbol","stateMutability":"view","inputs":[],"outputs":[{"name":"","type":"string"}]},
+  {"type":"function","name":"name","stateMutability":"view","inputs":[],"outputs":[{"name":"","type":"string"}]},
+  {"type":"event","name":"Transfer","anonymous":false,"inputs":[{"name":"from","type":"address","indexed":true},{"name":"to","type":"address","indexed":true},{"name":"value","type":"uint256","indexed":false}]},
+  {"type":"event","name":"Approval","anonymous":false,"inputs":[{"name":"owner","type":"address","indexed":true},{"name":"spender","type":"address","indexed":true},{"name":"value","type":"uint256","indexed":false}]}
+]"#;
+
+pub fn erc20_abi() -> Contract {
+    serde_json::from_str(ERC20_ABI).expect("built-in ERC-20 ABI is valid")
+}
+
+/// ERC-721 transfer and approval functions.
+pub const ERC721_ABI: &str = r#"[
+  {"type":"function","name":"safeTransferFrom","stateMutability":"nonpayable","inputs":[{"name":"from","type":"address"},{"name":"to","type":"address"},{"name":"tokenId","type":"uint256"}],"outputs":[]},
+  {"type":"function","name":"safeTransferFrom","stateMutability":"nonpayable","inputs":[{"name":"from","type":"address"},{"name":"to","type":"address"},{"name":"tokenId","type":"uint256"},{"name":"data","type":"bytes"}],"outputs":[]},
+  {"type":"function","name":"transferFrom","stateMutability":"nonpayable","inputs":[{"name":"from","type":"address"},{"name":"to","type":"address"},{"name":"tokenId","type":"uint256"}],"outputs":[]},
+  {"type":"function","name":"approve","stateMutability":"nonpayable","inputs":[{"name":"to","type":"address"},{"name":"tokenId","type":"uint256"}],"outputs":[]},
+  {"type":"function","name":"setApprovalForAll","stateMutability":"nonpayable","inputs":[{"name":"operator","type":"address"},{"name":"approved","type":"bool"}],"outputs":[]},
+  {"type":"function","name":"ownerOf","stateMutability":"view","inputs":[{"name":"tokenId","type":"uint256"}],"outputs":[{"name":"","type":"address"}]}
+]"#;
+
+pub fn erc721_abi() -> Contract {
+    serde_json::from_str(ERC721_ABI).expect("built-in ERC-721 ABI is valid")
+}
+
+/// ERC-1155 transfer and approval functions.
+pub const ERC1155_ABI: &str = r#"[
+  {"type":"function","name":"safeTransferFrom","stateMutability":"nonpayable","inputs":[{"name":"from","type":"address"},{"name":"to","type":"address"},{"name":"id","type":"uint256"},{"name":"amount","type":"uint256"},{"name":"data","type":"bytes"}],"outputs":[]},
+  {"type":"function","name":"safeBatchTransferFrom","stateMutability":"nonpayable","inputs":[{"name":"from","type":"address"},{"name":"to","type":"address"},{"name":"ids","type":"uint256[]"},{"name":"amounts","type":"uint256[]"},{"name":"data","type":"bytes"}],"outputs":[]},
+  {"type":"function","name":"setApprovalForAll","stateMutability":"nonpayable","inputs":[{"name":"operator","type":"address"},{"name":"approved","type":"bool"}],"outputs":[]},
+  {"type":"function","name":"balanceOf","stateMutability":"view","inputs":[{"name":"account","type":"address"},{"name":"id","type":"uint256"}],"outputs":[{"name":"","type":"uint256"}]}
+]"#;
+
+pub fn erc1155_abi() -> Contract {
+    serde_json::from_str(ERC1155_ABI).expect("built-in ERC-1155 ABI is valid")
+}
+
+#[derive(Debug, Deserialize)]
+struct ContractEntry {
+    address: Address,
+    abi: Value,
+}
+
+pub struct RegisteredContract {
+    pub name: String,
+    pub address: Address,
+    pub abi: Contract,
+}
+
+/// Contracts the HSM is allowed to sign calls to, loaded from a JSON file of
+/// `{"<name>": {"address": "0x..", "abi": [..]}}`.
+#[derive(Default)]
+pub struct AbiRegistry {
+    contracts: Vec<RegisteredContract>,
+}
+
+impl AbiRegistry {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| Error::msg(format!("Error reading ABI registry {}: {}", path, err)))?;
+        let entries: HashMap<String, ContractEntry> = serde_json::from_str(&content)
+            .map_err(|err| Error::msg(format!("Error parsing ABI registry {}: {}", path, err)))?;
+        let mut contracts = Vec::with_capacity(entries.len());
+        for (name, entry) in entries {
+            let abi: Contract = serde_json::from_value(entry.abi)
+                .map_err(|err| Error::msg(format!("Error parsing ABI of {}: {}", name, err)))?;
+            contracts.push(RegisteredContract {
+                name,
+                address: entry.address,
+                abi,
+            });
+        }
+        println!(
+            "Registered contracts: {:?}",
+            contracts.iter().map(|c| &c.name).collect::<Vec<_>>()
+        );
+        Ok(AbiRegistry { contracts })
+    }
+
+    /// Load `ABI_REGISTRY_PATH`; without it no contract call can be signed.
+    pub fn from_env() -> Result<Self, Error> {
+        match dotenvy::var("ABI_REGISTRY_PATH") {
+            Ok(path) => Self::load(&path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Find a registered contract by name or address.
+    pub fn get(&self, contract: &str) -> Result<&RegisteredContract, Error> {
+        let address = Address::from_str(contract).ok();
+        self.contracts
+            .iter()
+            .find(|c| c.name == contract || Some(c.address) == address)
+            .ok_or_else(|| Error::msg(format!("Contract not registered: {}", contract)))
+    }
+}
+
+impl RegisteredContract {
+    /// Find a function by name, or by signature such as `mint(address,uint256)`
+    /// when the name is overloaded.
+    pub fn function(&self, function: &str) -> Result<&Function, Error> {
+        let name = function.split('(').next().unwrap_or_default();
+        let candidates: Vec<&Function> = self
+            .abi
+            .functions_by_name(name)
+            .map_err(|_| Error::msg(format!("Function not found in {}: {}", self.name, function)))?
+            .iter()
+            .filter(|f| !function.contains('(') || function_signature(f) == function)
+            .collect();
+        match candidates.as_slice() {
+            [function] => Ok(function),
+            [] => Err(Error::msg(format!(
+                "Function not found in {}: {}",
+                self.name, function
+            ))),
+            _ => Err(Error::msg(format!(
+                "Function {} is overloaded, use its signature",
+                function
+            ))),
+        }
+    }
+}
+
+fn function_signature(function: &Function) -> String {
+    let inputs: Vec<String> = function
+        .inputs
+        .iter()
+        .map(|param| param.kind.to_string())
+        .collect();
+    format!("{}({})", function.name, inputs.join(","))
+}
+
+/// ABI-encode a call of `function` with JSON arguments: numbers as JSON numbers
+/// or decimal/`0x` strings, addresses and bytes as `0x` hex, arrays and tuples as
+/// JSON arrays.
+pub fn encode_call(function: &Function, args: &[Value]) -> Result<Vec<u8>, Error> {
+    let tokens = json_to_args(&function_signature(function), &function.inputs, args)?;
+    function
+        .encode_input(&tokens)
+        .map_err(|err| Error::msg(format!("Error encoding call: {}", err)))
+}
+
+/// Append the ABI-encoded constructor arguments of `abi` to `bytecode`.
+pub fn encode_constructor(
+    abi: &Contract,
+    bytecode: Vec<u8>,
+    args: &[Value],
+) -> Result<Vec<u8>, Error> {
+    match abi.constructor() {
+        Some(constructor) => {
+            let tokens = json_to_args("constructor", &constructor.inputs, args)?;
+            constructor
+                .encode_input(bytecode, &tokens)
+                .map_err(|err| Error::msg(format!("Error encoding constructor: {}", err)))
+        }
+        None if args.is_empty() => Ok(bytecode),
+        None => Err(Error::msg(
+            "ABI has no constructor but arguments were given",
+        )),
+    }
+}
+
+fn json_to_args(name: &str, params: &[Param], args: &[Value]) -> Result<Vec<Token>, Error> {
+    if args.len() != params.len() {
+        return Err(Error::msg(format!(
+            "{} expects {} arguments, got {}",
+            name,
+            params.len(),
+            args.len()
+        )));
+    }
+    params
+        .iter()
+        .zip(args)
+        .map(|(param, arg)| {
+            json_to_token(&param.kind, arg)
+                .map_err(|err| Error::msg(format!("Invalid argument {}: {}", param.name, err)))
+        })
+        .collect()
+}
+
+pub fn json_to_token(kind: &ParamType, value: &Value) -> Result<Token, Error> {
+    let token = match kind {
+        ParamType::Address => Token::Address(Address::from_str(as_str(value)?)?),
+        ParamType::Bool => Token::Bool(
+            value
+                .as_bool()
+                .ok_or_else(|| Error::msg(format!("Expected bool, got {}", value)))?,
+        ),
+        ParamType::String => Token::String(as_str(value)?.to_string()),
+        ParamType::Bytes => Token::Bytes(decode_hex(value)?),
+        ParamType::FixedBytes(len) => {
+            let bytes = decode_hex(value)?;
+            if bytes.len() != *len {
+                return Err(Error::msg(format!("Expected {} bytes", len)));
+            }
+            Token::FixedBytes(bytes)
+        }
+        ParamType::Uint(bits) => {
+            let number = parse_number(value)?;
+            if *bits < 256 && number.bits() > *bits {
+                return Err(Error::msg(format!(
+                    "{} does not fit in uint{}",
+                    number, bits
+                )));
+            }
+            Token::Uint(number)
+        }
+        ParamType::Int(bits) => Token::Int(parse_int(value, *bits)?),
+        ParamType::Array(inner) => Token::Array(json_to_tokens(inner, value, None)?),
+        ParamType::FixedArray(inner, len) => {
+            Token::FixedArray(json_to_tokens(inner, value, Some(*len))?)
+        }
+        ParamType::Tuple(kinds) => {
+            let items = as_array(value, Some(kinds.len()))?;
+            Token::Tuple(
+                kinds
+                    .iter()
+                    .zip(items)
+                    .map(|(kind, item)| json_to_token(kind, item))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+    };
+    Ok(token)
+}
+
+fn json_to_tokens(
+    kind: &ParamType,
+    value: &Value,
+    len: Option<usize>,
+) -> Result<Vec<Token>, Error> {
+    as_array(value, len)?
+        .iter()
+        .map(|item| json_to_token(kind, item))
+        .collect()
+}
+
+fn as_array(value: &Value, len: Option<usize>) -> Result<&Vec<Value>, Error> {
+    let items = value
+        .as_array()
+        .ok_or_else(|| Error::msg(format!("Expected array, got {}", value)))?;
+    match len {
+        Some(len) if items.len() != len => Err(Error::msg(format!(
+            "Expected {} items, got {}",
+            len,
+            items.len()
+        ))),
+        _ => Ok(items),
+    }
+}
+
+fn as_str(value: &Value) -> Result<&str, Error> {
+    value
+        .as_str()
+        .ok_or_else(|| Error::msg(format!("Expected string, got {}", value)))
+}
+
+fn decode_hex(value: &Value) -> Result<Vec<u8>, Error> {
+    Ok(hex::decode(as_str(value)?.trim_start_matches("0x"))?)
+}
+
+fn parse_number(value: &Value) -> Result<U256, Error> {
+    let number = match value {
+        Value::Number(number) if number.is_u64() => {
+            return Ok(U256::from(number.as_u64().unwrap()))
+        }
+        Value::String(number) => number.as_str(),
+        _ => {
+            return Err(Error::msg(format!(
+                "Expected unsigned integer, got {}",
+                value
+            )))
+        }
+    };
+    // Both parsers read an empty string as zero.
+    if number.trim_start_matches("0x").is_empty() {
+        return Err(Error::msg(format!("Invalid number {:?}", number)));
+    }
+    let parsed = match number.strip_prefix("0x") {
+        Some(hex) => U256::from_str_radix(hex, 16).map_err(|err| Error::msg(err.to_string())),
+        None => U256::from_dec_str(number).map_err(|err| Error::msg(format!("{:?}", err))),
+    };
+    parsed.map_err(|err| Error::msg(format!("Invalid number {}: {}", number, err)))
+}
+
+/// Two's complement encoding of a signed `int<bits>` argument.
+fn parse_int(value: &Value, bits: usize) -> Result<U256, Error> {
+    let (negative, abs) = match value {
+        Value::Number(number) if number.is_i64() => {
+            let number = number.as_i64().unwrap();
+            (number < 0, U256::from(number.unsigned_abs()))
+        }
+        Value::String(number) => match number.strip_prefix('-') {
+            Some(abs) => (true, parse_number(&Value::String(abs.to_string()))?),
+            None => (false, parse_number(value)?),
+        },
+        _ => return Err(Error::msg(format!("Expected integer, got {}", value))),
+    };
+    let limit = U256::one() << (bits - 1);
+    if (negative && abs > limit) || (!negative && abs >= limit) {
+        return Err(Error::msg(format!("Value does not fit in int{}", bits)));
+    }
+    Ok(if negative && !abs.is_zero() {
+        !abs + 1
+    } else {
+        abs
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn contract(name: &str, address: u64, abi: Contract) -> RegisteredContract {
+        RegisteredContract {
+            name: name.to_string(),
+            address: Address::from_low_u64_be(address),
+            abi,
+        }
+    }
+
+    #[test]
+    fn resolves_functions_and_overloads() {
+        let registry = AbiRegistry {
+            contracts: vec![
+                contract("token", 1, erc20_abi()),
+                contract("nft", 2, erc721_abi()),
+            ],
+        };
+        let token = registry.get("token").unwrap();
+        assert_eq!(
+            token.function("transfer").unwrap().short_signature(),
+            [0xa9, 0x05, 0x9c, 0xbb]
+        );
+        assert_eq!(
+            token
+                .function("transfer(address,uint256)")
+                .unwrap()
+                .short_signature(),
+            [0xa9, 0x05, 0x9c, 0xbb]
+        );
+        assert!(token.function("transfer(address)").is_err());
+        assert!(token.function("mint").is_err());
+
+        let by_address = registry.get("0x0000000000000000000000000000000000000002");
+        assert_eq!(by_address.unwrap().name, "nft");
+        let nft = registry.get("nft").unwrap();
+        assert!(nft.function("safeTransferFrom").is_err());
+        assert_eq!(
+            nft.function("safeTransferFrom(address,address,uint256)")
+                .unwrap()
+                .short_signature(),
+            [0x42, 0x84, 0x2e, 0x0e]
+        );
+        assert_eq!(
+            nft.function("safeTransferFrom(address,address,uint256,bytes)")
+                .unwrap()
+                .short_signature(),
+            [0xb8, 0x8d, 0x4f, 0xde]
+        );
+        assert!(registry.get("unknown").is_err());
+    }
+
+    #[test]
+    fn encodes_erc20_transfer() {
+        let abi = erc20_abi();
+        let transfer = abi.function("transfer").unwrap();
+        let data = encode_call(
+            transfer,
+            &[
+                json!("0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f"),
+                json!("1000"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(data),
+            "a9059cbb\
+             0000000000000000000000009d8a62f656a8d1615c1294fd71e9cfb3e4855a4f\
+             00000000000000000000000000000000000000000000000000000000000003e8"
+        );
+        assert!(encode_call(
+            transfer,
+            &[json!("0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f")]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn converts_json_to_tokens() {
+        let address = Address::from_str("0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f").unwrap();
+        let cases = [
+            (
+                ParamType::Address,
+                json!(format!("{:?}", address)),
+                Token::Address(address),
+            ),
+            (ParamType::Bool, json!(true), Token::Bool(true)),
+            (
+                ParamType::String,
+                json!("hsm"),
+                Token::String("hsm".to_string()),
+            ),
+            (ParamType::Bytes, json!("0x0102"), Token::Bytes(vec![1, 2])),
+            (
+                ParamType::FixedBytes(2),
+                json!("0x0102"),
+                Token::FixedBytes(vec![1, 2]),
+            ),
+            (ParamType::Uint(256), json!(42), Token::Uint(U256::from(42))),
+            (
+                ParamType::Uint(256),
+                json!("42"),
+                Token::Uint(U256::from(42)),
+            ),
+            (
+                ParamType::Uint(8),
+                json!("0xff"),
+                Token::Uint(U256::from(255)),
+            ),
+            (ParamType::Int(8), json!(-1), Token::Int(U256::MAX)),
+            (
+                ParamType::Int(8),
+                json!("-128"),
+                Token::Int(U256::MAX - 127),
+            ),
+            (ParamType::Int(8), json!("127"), Token::Int(U256::from(127))),
+            (
+                ParamType::Array(Box::new(ParamType::Uint(256))),
+                json!([1, "2"]),
+                Token::Array(vec![Token::Uint(U256::from(1)), Token::Uint(U256::from(2))]),
+            ),
+            (
+                ParamType::FixedArray(Box::new(ParamType::Bool), 2),
+                json!([true, false]),
+                Token::FixedArray(vec![Token::Bool(true), Token::Bool(false)]),
+            ),
+            (
+                ParamType::Tuple(vec![
+                    ParamType::Address,
+                    ParamType::Array(Box::new(ParamType::String)),
+                ]),
+                json!([format!("{:?}", address), ["a", "b"]]),
+                Token::Tuple(vec![
+                    Token::Address(address),
+                    Token::Array(vec![
+                        Token::String("a".to_string()),
+                        Token::String("b".to_string()),
+                    ]),
+                ]),
+            ),
+        ];
+        for (kind, value, expected) in cases {
+            assert_eq!(
+                json_to_token(&kind, &value).unwrap(),
+                expected,
+                "{} {}",
+                kind,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        let cases = [
+            (ParamType::Address, json!("0x1234")),
+            (ParamType::Bool, json!("true")),
+            (ParamType::FixedBytes(2), json!("0x010203")),
+            (ParamType::Uint(256), json!("")),
+            (ParamType::Uint(256), json!("0x")),
+            (ParamType::Uint(256), json!(-1)),
+            (ParamType::Uint(8), json!(256)),
+            (ParamType::Int(8), json!("-")),
+            (ParamType::Int(8), json!("")),
+            (ParamType::Int(8), json!(128)),
+            (ParamType::Int(8), json!("-129")),
+            (
+                ParamType::FixedArray(Box::new(ParamType::Bool), 2),
+                json!([true]),
+            ),
+            (
+                ParamType::Tuple(vec![ParamType::Bool, ParamType::Bool]),
+                json!([true]),
+            ),
+        ];
+        for (kind, value) in cases {
+            assert!(json_to_token(&kind, &value).is_err(), "{} {}", kind, value);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use crate::utils::{
+    abi_registry::AbiRegistry, client_registry::ClientRegistry, eip712::DomainAllowlist,
+    identity::IdentityKey, key_store::KeyStore, key_versions::VersionedKeyStore,
+    permit::PermitSpenders, seal::SealedKeyStore, session_store::Sessions,
+};
+use std::sync::Arc;
+
+/// Shared state injected into every route.
+#[derive(Clone)]
+pub struct AppState {
+    /// Signing view of `key_versions`.
+    pub key_store: Arc<dyn KeyStore>,
+    pub key_versions: Arc<VersionedKeyStore>,
+    /// Set when the keys are sealed under Shamir shares (`KEY_STORE=sealed`).
+    pub seal: Option<Arc<SealedKeyStore>>,
+    /// Contracts `/sign-contract-call` may sign calls to.
+    pub abi_registry: Arc<AbiRegistry>,
+    /// EIP-712 domains `/sign-typed-data` may sign for.
+    pub eip712_domains: Arc<DomainAllowlist>,
+    /// Spenders `/sign-permit` may approve.
+    pub permit_spenders: Arc<PermitSpenders>,
+    /// Signs the ephemeral keys handed out by `/pk`.
+    pub identity: Arc<IdentityKey>,
+    /// Key exchange sessions encrypted requests are sent over.
+    pub sessions: Arc<Sessions>,
+    /// Clients whose signatures encrypted requests must carry.
+    pub clients: Arc<ClientRegistry>,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State over `key_store` with in-memory sessions and empty allowlists.
+    pub fn for_tests(key_store: Arc<dyn KeyStore>, seal: Option<Arc<SealedKeyStore>>) -> Self {
+        use crate::utils::session_store::InMemorySessionStore;
+        let key_versions = Arc::new(VersionedKeyStore::load(key_store, None).unwrap());
+        AppState {
+            key_store: key_versions.clone(),
+            key_versions,
+            seal,
+            abi_registry: Arc::new(AbiRegistry::default()),
+            eip712_domains: Arc::new(DomainAllowlist::default()),
+            permit_spenders: Arc::new(PermitSpenders::default()),
+            identity: Arc::new(IdentityKey::random()),
+            sessions: Arc::new(Sessions::new(
+                Arc::new(InMemorySessionStore::new()),
+                3600,
+                1000,
+                120,
+            )),
+            clients: Arc::new(ClientRegistry::load(None).unwrap()),
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use anyhow::Error;
+use p256::ecdsa::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Operation name granting every operation.
+pub const ALL_OPERATIONS: &str = "*";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientStatus {
+    Active,
+    /// Refused on every request, listed for audit.
+    Revoked,
+}
+
+/// Client allowed to send encrypted requests, identified by `client_id` in
+/// `SignTx`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientKey {
+    pub client_id: String,
+    pub name: String,
+    /// SEC1 P-256 verifying key, `0x` hex.
+    pub verifying_key: String,
+    pub status: ClientStatus,
+    /// Endpoints the client may call, e.g. `sign-raw-tx`, or `*`.
+    pub operations: Vec<String>,
+    pub created_at: u64,
+    pub revoked_at: Option<u64>,
+}
+
+impl ClientKey {
+    pub fn allows(&self, operation: &str) -> bool {
+        self.operations
+            .iter()
+            .any(|allowed| allowed == ALL_OPERATIONS || allowed == operation)
+    }
+}
+
+/// Enrolled client verifying keys, persisted as JSON at `path` when set.
+pub struct ClientRegistry {
+    clients: RwLock<HashMap<String, ClientKey>>,
+    path: Option<String>,
+}
+
+impl ClientRegistry {
+    pub fn load(path: Option<String>) -> Result<Self, Error> {
+        let clients = match &path {
+            Some(path) if std::path::Path::new(path).exists() => {
+                let content = std::fs::read_to_string(path)?;
+                serde_json::from_str(&content).map_err(|err| {
+                    Error::msg(format!("Error parsing client registry {}: {}", path, err))
+                })?
+            }
+            _ => HashMap::new(),
+        };
+        Ok(ClientRegistry {
+            clients: RwLock::new(clients),
+            path,
+        })
+    }
+
+    /// Load `CLIENT_REGISTRY_PATH`. With no enrolled client every encrypted
+    /// request is refused.
+    pub fn from_env() -> Result<Self, Error> {
+        Self::load(dotenvy::var("CLIENT_REGISTRY_PATH").ok())
+    }
+
+    pub fn list(&self) -> Result<Vec<ClientKey>, Error> {
+        let mut clients: Vec<ClientKey> = self.read()?.values().cloned().collect();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        Ok(clients)
+    }
+
+    pub fn enroll(
+        &self,
+        client_id: &str,
+        name: &str,
+        verifying_key: &str,
+        operations: Vec<String>,
+    ) -> Result<ClientKey, Error> {
+        if client_id.is_empty() {
+            return Err(Error::msg("Client id must not be empty"));
+        }
+        if operations.is_empty() {
+            return Err(Error::msg("At least one operation must be allowed"));
+        }
+        let key_bytes = hex::decode(verifying_key.trim_start_matches("0x"))
+            .map_err(|err| Error::msg(format!("Error parsing verifying key: {}", err)))?;
+        let key = VerifyingKey::from_sec1_bytes(&key_bytes)
+            .map_err(|err| Error::msg(format!("Invalid verifying key: {}", err)))?;
+        let mut clients = self.write()?;
+        if clients.contains_key(client_id) {
+            return Err(Error::msg(format!(
+                "Client {} is already enrolled",
+                client_id
+            )));
+        }
+        let client = ClientKey {
+            client_id: client_id.to_string(),
+            name: name.to_string(),
+            verifying_key: format!("0x{}", hex::encode(key.to_encoded_point(false).as_bytes())),
+            status: ClientStatus::Active,
+            operations,
+            created_at: now(),
+            revoked_at: None,
+        };
+        let mut updated = clients.clone();
+        updated.insert(client_id.to_string(), client.clone());
+        self.persist(&updated)?;
+        *clients = updated;
+        Ok(client)
+    }
+
+    pub fn revoke(&self, client_id: &str) -> Result<ClientKey, Error> {
+        let mut clients = self.write()?;
+        let mut updated = clients.clone();
+        let client = updated
+            .get_mut(client_id)
+            .ok_or_else(|| Error::msg(format!("Unknown client: {}", client_id)))?;
+        if client.status != ClientStatus::Revoked {
+            client.status = ClientStatus::Revoked;
+            client.revoked_at = Some(now());
+        }
+        let client = client.clone();
+        self.persist(&updated)?;
+        *clients = updated;
+        Ok(client)
+    }
+
+    /// Verifying key of the active client `client_id`, if it may call `operation`.
+    pub fn verifying_key(&self, client_id: &str, operation: &str) -> Result<VerifyingKey, Error> {
+        let clients = self.read()?;
+        let client = clients
+            .get(client_id)
+            .ok_or_else(|| Error::msg(format!("Unknown client: {}", client_id)))?;
+        if client.status != ClientStatus::Active {
+            return Err(Error::msg(format!("Client {} is revoked", client_id)));
+        }
+        if !client.allows(operation) {
+            return Err(Error::msg(format!(
+                "Client {} may not call {}",
+                client_id, operation
+            )));
+        }
+        let key_bytes = hex::decode(client.verifying_key.trim_start_matches("0x"))?;
+        VerifyingKey::from_sec1_bytes(&key_bytes)
+            .map_err(|err| Error::msg(format!("Invalid verifying key: {}", err)))
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, HashMap<String, ClientKey>>, Error> {
+        self.clients
+            .read()
+            .map_err(|_| Error::msg("Client registry lock poisoned"))
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, HashMap<String, ClientKey>>, Error> {
+        self.clients
+            .write()
+            .map_err(|_| Error::msg("Client registry lock poisoned"))
+    }
+
+    /// Write `clients` to `path`; callers swap them in only once this succeeds.
+    fn persist(&self, clients: &HashMap<String, ClientKey>) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            let tmp_path = format!("{}.tmp", path);
+            std::fs::write(&tmp_path, serde_json::to_vec_pretty(clients)?)?;
+            std::fs::rename(&tmp_path, path)?;
+        }
+        Ok(())
+    }
+}
+
+const CLI_USAGE: &str = "usage:
+  hsm-jaamlong clients list
+  hsm-jaamlong clients enroll <client_id> <name> <verifying_key> <operation>[,<operation>..]
+  hsm-jaamlong clients revoke <client_id>";
+
+/// `clients` subcommand: manage `CLIENT_REGISTRY_PATH` while the HSM is
+/// stopped. A running HSM only reads the file at startup and rewrites it on its
+/// own changes, so use its `/clients` routes instead.
+pub fn run_cli(args: &[String]) -> Result<(), Error> {
+    let path = dotenvy::var("CLIENT_REGISTRY_PATH")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .ok_or_else(|| Error::msg("CLIENT_REGISTRY_PATH must be set"))?;
+    let registry = ClientRegistry::load(Some(path.clone()))?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let changed = match args.as_slice() {
+        ["list"] => {
+            println!("{}", serde_json::to_string_pretty(&registry.list()?)?);
+            return Ok(());
+        }
+        ["enroll", client_id, name, verifying_key, operations] => {
+            let operations = operations
+                .split(',')
+                .map(str::trim)
+                .filter(|operation| !operation.is_empty())
+                .map(str::to_string)
+                .collect();
+            registry.enroll(client_id, name, verifying_key, operations)?
+        }
+        ["revoke", client_id] => registry.revoke(client_id)?,
+        _ => return Err(Error::msg(CLI_USAGE)),
+    };
+    println!("{}", serde_json::to_string_pretty(&changed)?);
+    println!("Client registry written to {}", path);
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::SigningKey;
+    use rand_core::OsRng;
+
+    fn verifying_key() -> String {
+        let key = VerifyingKey::from(&SigningKey::random(&mut OsRng));
+        hex::encode(key.to_encoded_point(true).as_bytes())
+    }
+
+    #[test]
+    fn refuses_unknown_revoked_and_unallowed_clients() {
+        let registry = ClientRegistry::load(None).unwrap();
+        let key = verifying_key();
+        registry
+            .enroll("bridge", "Bridge", &key, vec!["sign-raw-tx".to_string()])
+            .unwrap();
+        registry
+            .enroll(
+                "ops",
+                "Ops",
+                &verifying_key(),
+                vec![ALL_OPERATIONS.to_string()],
+            )
+            .unwrap();
+
+        let enrolled = registry.verifying_key("bridge", "sign-raw-tx").unwrap();
+        assert_eq!(hex::encode(enrolled.to_encoded_point(true).as_bytes()), key);
+        assert!(registry.verifying_key("ops", "rpc").is_ok());
+        assert!(registry.verifying_key("unknown", "sign-raw-tx").is_err());
+        assert!(registry.verifying_key("bridge", "rpc").is_err());
+        assert!(registry
+            .enroll(
+                "bridge",
+                "Bridge",
+                &verifying_key(),
+                vec!["rpc".to_string()]
+            )
+            .is_err());
+
+        let revoked = registry.revoke("bridge").unwrap();
+        assert_eq!(revoked.status, ClientStatus::Revoked);
+        assert!(revoked.revoked_at.is_some());
+        assert!(registry.verifying_key("bridge", "sign-raw-tx").is_err());
+        assert!(registry.revoke("unknown").is_err());
+        // Revoked clients stay listed for audit
+        let listed: Vec<(String, ClientStatus)> = registry
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|client| (client.client_id, client.status))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("bridge".to_string(), ClientStatus::Revoked),
+                ("ops".to_string(), ClientStatus::Active),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_enrollments() {
+        let registry = ClientRegistry::load(None).unwrap();
+        let ops = vec![ALL_OPERATIONS.to_string()];
+        assert!(registry
+            .enroll("", "Empty", &verifying_key(), ops.clone())
+            .is_err());
+        assert!(registry
+            .enroll("bridge", "Bridge", &verifying_key(), vec![])
+            .is_err());
+        assert!(registry.enroll("bridge", "Bridge", "0x04", ops).is_err());
+        assert!(registry.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn persists_before_applying_changes() {
+        let dir = std::env::temp_dir().join(format!("client-registry-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("clients.json").to_str().unwrap().to_string();
+        let registry = ClientRegistry::load(Some(path.clone())).unwrap();
+        registry
+            .enroll(
+                "bridge",
+                "Bridge",
+                &verifying_key(),
+                vec!["rpc".to_string()],
+            )
+            .unwrap();
+        let reloaded = ClientRegistry::load(Some(path.clone())).unwrap();
+        assert!(reloaded.verifying_key("bridge", "rpc").is_ok());
+
+        // With the directory gone nothing can be written, so nothing changes
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(registry.revoke("bridge").is_err());
+        assert!(registry.verifying_key("bridge", "rpc").is_ok());
+        assert!(registry
+            .enroll("ops", "Ops", &verifying_key(), vec!["rpc".to_string()])
+            .is_err());
+        assert_eq!(registry.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reports_poisoned_lock() {
+        let registry = std::sync::Arc::new(ClientRegistry::load(None).unwrap());
+        let poisoner = registry.clone();
+        let _ = std::thread::spawn(move || {
+            let _clients = poisoner.clients.write().unwrap();
+            panic!("poison the client registry lock");
+        })
+        .join();
+        let ops = vec![ALL_OPERATIONS.to_string()];
+        assert!(registry.list().is_err());
+        assert!(registry
+            .enroll("ops", "Ops", &verifying_key(), ops)
+            .is_err());
+        assert!(registry.revoke("ops").is_err());
+        assert!(registry.verifying_key("ops", "rpc").is_err());
+    }
+}
@@ -0,0 +1,381 @@
+use crate::utils::abi_registry::json_to_token;
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    str::FromStr,
+};
+use web3::{
+    ethabi::{self, param_type::Reader},
+    signing::keccak256,
+    types::{Address, H256, U256},
+};
+
+const DOMAIN_TYPE: &str = "EIP712Domain";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypedField {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+/// EIP-712 typed-data document as accepted by `eth_signTypedData_v4`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypedData {
+    pub types: BTreeMap<String, Vec<TypedField>>,
+    pub primary_type: String,
+    pub domain: Value,
+    pub message: Value,
+}
+
+impl TypedData {
+    /// `keccak256(0x1901 ‖ domainSeparator ‖ hashStruct(message))`.
+    pub fn digest(&self) -> Result<H256, Error> {
+        let domain_separator = self.domain_separator()?;
+        let message_hash = self.hash_struct(&self.primary_type, &self.message)?;
+        let digest = keccak256(&[&[0x19, 0x01], &domain_separator[..], &message_hash[..]].concat());
+        Ok(digest.into())
+    }
+
+    pub fn domain_separator(&self) -> Result<[u8; 32], Error> {
+        self.hash_struct(DOMAIN_TYPE, &self.domain)
+    }
+
+    /// Chain id and verifying contract of the domain, when present.
+    pub fn domain_binding(&self) -> Result<(Option<u64>, Option<Address>), Error> {
+        let chain_id = match self.domain.get("chainId") {
+            Some(chain_id) => match json_to_token(&ethabi::ParamType::Uint(256), chain_id)? {
+                ethabi::Token::Uint(chain_id) if chain_id <= U256::from(u64::MAX) => {
+                    Some(chain_id.as_u64())
+                }
+                _ => return Err(Error::msg("Domain chainId is out of range")),
+            },
+            None => None,
+        };
+        let verifying_contract = match self.domain.get("verifyingContract") {
+            Some(Value::String(address)) => Some(Address::from_str(address)?),
+            Some(other) => return Err(Error::msg(format!("Invalid verifyingContract: {}", other))),
+            None => None,
+        };
+        Ok((chain_id, verifying_contract))
+    }
+
+    fn fields(&self, type_name: &str) -> Result<Vec<TypedField>, Error> {
+        if let Some(fields) = self.types.get(type_name) {
+            return Ok(fields.clone());
+        }
+        if type_name != DOMAIN_TYPE {
+            return Err(Error::msg(format!("Unknown type: {}", type_name)));
+        }
+        // Domain type omitted from `types`: derive it from the fields present
+        let domain = self
+            .domain
+            .as_object()
+            .ok_or_else(|| Error::msg("Domain must be an object"))?;
+        Ok([
+            ("name", "string"),
+            ("version", "string"),
+            ("chainId", "uint256"),
+            ("verifyingContract", "address"),
+            ("salt", "bytes32"),
+        ]
+        .iter()
+        .filter(|(name, _)| domain.contains_key(*name))
+        .map(|(name, kind)| TypedField {
+            name: name.to_string(),
+            kind: kind.to_string(),
+        })
+        .collect())
+    }
+
+    /// `keccak256(encodeType(type_name))`, the `*_TYPEHASH` constant of contracts.
+    pub fn type_hash(&self, type_name: &str) -> Result<[u8; 32], Error> {
+        Ok(keccak256(self.encode_type(type_name)?.as_bytes()))
+    }
+
+    /// `Mail(Person from,Person to,string contents)Person(string name,address wallet)`.
+    fn encode_type(&self, type_name: &str) -> Result<String, Error> {
+        let mut dependencies = BTreeSet::new();
+        self.collect_dependencies(type_name, &mut dependencies)?;
+        dependencies.remove(type_name);
+        let mut encoded = String::new();
+        for name in std::iter::once(type_name).chain(dependencies.iter().map(String::as_str)) {
+            let fields: Vec<String> = self
+                .fields(name)?
+                .iter()
+                .map(|field| format!("{} {}", field.kind, field.name))
+                .collect();
+            encoded.push_str(&format!("{}({})", name, fields.join(",")));
+        }
+        Ok(encoded)
+    }
+
+    fn collect_dependencies(
+        &self,
+        type_name: &str,
+        found: &mut BTreeSet<String>,
+    ) -> Result<(), Error> {
+        if !found.insert(type_name.to_string()) {
+            return Ok(());
+        }
+        for field in self.fields(type_name)? {
+            let base = base_type(&field.kind);
+            if self.types.contains_key(base) {
+                self.collect_dependencies(base, found)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn hash_struct(&self, type_name: &str, value: &Value) -> Result<[u8; 32], Error> {
+        let object = value
+            .as_object()
+            .ok_or_else(|| Error::msg(format!("Expected {} object, got {}", type_name, value)))?;
+        let mut encoded = self.type_hash(type_name)?.to_vec();
+        for field in self.fields(type_name)? {
+            let field_value = object
+                .get(&field.name)
+                .ok_or_else(|| Error::msg(format!("Missing field {}.{}", type_name, field.name)))?;
+            let word = self
+                .encode_value(&field.kind, field_value)
+                .map_err(|err| Error::msg(format!("{}.{}: {}", type_name, field.name, err)))?;
+            encoded.extend_from_slice(&word);
+        }
+        Ok(keccak256(&encoded))
+    }
+
+    fn encode_value(&self, kind: &str, value: &Value) -> Result<[u8; 32], Error> {
+        if let Some(item_kind) = array_item_type(kind) {
+            let items = value
+                .as_array()
+                .ok_or_else(|| Error::msg(format!("Expected array, got {}", value)))?;
+            let mut encoded = Vec::with_capacity(items.len() * 32);
+            for item in items {
+                encoded.extend_from_slice(&self.encode_value(item_kind, item)?);
+            }
+            return Ok(keccak256(&encoded));
+        }
+        if self.types.contains_key(kind) {
+            return self.hash_struct(kind, value);
+        }
+        match kind {
+            "string" => {
+                let string = value
+                    .as_str()
+                    .ok_or_else(|| Error::msg(format!("Expected string, got {}", value)))?;
+                Ok(keccak256(string.as_bytes()))
+            }
+            "bytes" => match json_to_token(&ethabi::ParamType::Bytes, value)? {
+                ethabi::Token::Bytes(bytes) => Ok(keccak256(&bytes)),
+                _ => unreachable!(),
+            },
+            _ => {
+                let param = Reader::read(kind)
+                    .map_err(|err| Error::msg(format!("Unknown type {}: {}", kind, err)))?;
+                let token = json_to_token(&param, value)?;
+                let encoded = ethabi::encode(&[token]);
+                let mut word = [0u8; 32];
+                word.copy_from_slice(&encoded[..32]);
+                Ok(word)
+            }
+        }
+    }
+}
+
+fn base_type(kind: &str) -> &str {
+    kind.split('[').next().unwrap_or(kind)
+}
+
+/// `Person[]` or `uint256[3]` -> `Person` / `uint256`.
+fn array_item_type(kind: &str) -> Option<&str> {
+    kind.strip_suffix(']')
+        .and_then(|kind| kind.rfind('[').map(|index| &kind[..index]))
+}
+
+/// Domains the HSM signs typed data for, as `chainId:verifyingContract` pairs.
+#[derive(Debug, Default)]
+pub struct DomainAllowlist {
+    domains: HashSet<(u64, Address)>,
+}
+
+impl DomainAllowlist {
+    pub fn new(domains: HashSet<(u64, Address)>) -> Self {
+        DomainAllowlist { domains }
+    }
+
+    /// Parse `EIP712_ALLOWED_DOMAINS`, e.g. `1:0xabc..,137:0xdef..`. With no
+    /// entries every typed-data request is refused.
+    pub fn from_env() -> Result<Self, Error> {
+        Ok(DomainAllowlist {
+            domains: chain_addresses_from_env("EIP712_ALLOWED_DOMAINS")?,
+        })
+    }
+
+    /// Allow signing for `contract` on `chain_id`.
+    pub fn check_contract(&self, chain_id: u64, contract: Address) -> Result<(), Error> {
+        if self.domains.contains(&(chain_id, contract)) {
+            Ok(())
+        } else {
+            Err(Error::msg(format!(
+                "Domain {}:{:?} is not allowed",
+                chain_id, contract
+            )))
+        }
+    }
+
+    /// Allow `typed_data` when its domain is allowlisted. The chain id and
+    /// contract must also be declared in the domain type, since only declared
+    /// fields go into the domain separator.
+    pub fn check(&self, typed_data: &TypedData) -> Result<(), Error> {
+        let domain_fields = typed_data.fields(DOMAIN_TYPE)?;
+        for (name, kind) in [("chainId", "uint256"), ("verifyingContract", "address")] {
+            if !domain_fields
+                .iter()
+                .any(|field| field.name == name && field.kind == kind)
+            {
+                return Err(Error::msg(format!(
+                    "{} must declare {} {}",
+                    DOMAIN_TYPE, kind, name
+                )));
+            }
+        }
+        match typed_data.domain_binding()? {
+            (Some(chain_id), Some(contract)) => self.check_contract(chain_id, contract),
+            _ => Err(Error::msg("Domain must set chainId and verifyingContract")),
+        }
+    }
+}
+
+/// Parse the environment variable `name` as comma separated
+/// `chainId:0xaddress` pairs; unset is empty.
+pub fn chain_addresses_from_env(name: &str) -> Result<HashSet<(u64, Address)>, Error> {
+    let mut pairs = HashSet::new();
+    let listed = dotenvy::var(name).unwrap_or_default();
+    for entry in listed.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let invalid = || Error::msg(format!("Invalid {} entry: {}", name, entry));
+        let (chain_id, address) = entry.split_once(':').ok_or_else(invalid)?;
+        let chain_id = chain_id.parse().map_err(|_| invalid())?;
+        let address = Address::from_str(address).map_err(|_| invalid())?;
+        pairs.insert((chain_id, address));
+    }
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const MAIL_CONTRACT: &str = "0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC";
+
+    /// `Mail` example of the EIP-712 specification.
+    fn mail(domain_type: Value) -> TypedData {
+        serde_json::from_value(json!({
+            "types": {
+                "EIP712Domain": domain_type,
+                "Person": [
+                    { "name": "name", "type": "string" },
+                    { "name": "wallet", "type": "address" }
+                ],
+                "Mail": [
+                    { "name": "from", "type": "Person" },
+                    { "name": "to", "type": "Person" },
+                    { "name": "contents", "type": "string" }
+                ]
+            },
+            "primaryType": "Mail",
+            "domain": {
+                "name": "Ether Mail",
+                "version": "1",
+                "chainId": 1,
+                "verifyingContract": MAIL_CONTRACT
+            },
+            "message": {
+                "from": { "name": "Cow", "wallet": "0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826" },
+                "to": { "name": "Bob", "wallet": "0xbBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB" },
+                "contents": "Hello, Bob!"
+            }
+        }))
+        .unwrap()
+    }
+
+    fn full_domain_type() -> Value {
+        json!([
+            { "name": "name", "type": "string" },
+            { "name": "version", "type": "string" },
+            { "name": "chainId", "type": "uint256" },
+            { "name": "verifyingContract", "type": "address" }
+        ])
+    }
+
+    fn allowlist() -> DomainAllowlist {
+        DomainAllowlist {
+            domains: HashSet::from([(1, Address::from_str(MAIL_CONTRACT).unwrap())]),
+        }
+    }
+
+    #[test]
+    fn hashes_mail_example() {
+        let typed_data = mail(full_domain_type());
+        assert_eq!(
+            typed_data.encode_type("Mail").unwrap(),
+            "Mail(Person from,Person to,string contents)Person(string name,address wallet)"
+        );
+        assert_eq!(
+            hex::encode(typed_data.domain_separator().unwrap()),
+            "f2cee375fa42b42143804025fc449deafd50cc031ca257e0b194a650a912090f"
+        );
+        assert_eq!(
+            hex::encode(typed_data.hash_struct("Mail", &typed_data.message).unwrap()),
+            "c52c0ee5d84264471806290a3f2c4cecfc5490626bf912d01f240d7a274b371e"
+        );
+        assert_eq!(
+            typed_data.digest().unwrap(),
+            H256::from_str("0xbe609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn derives_omitted_domain_type() {
+        let mut typed_data = mail(full_domain_type());
+        typed_data.types.remove(DOMAIN_TYPE);
+        assert_eq!(
+            typed_data.digest().unwrap(),
+            mail(full_domain_type()).digest().unwrap()
+        );
+        assert!(allowlist().check(&typed_data).is_ok());
+    }
+
+    #[test]
+    fn allows_listed_domain() {
+        assert!(allowlist().check(&mail(full_domain_type())).is_ok());
+        assert!(DomainAllowlist::default()
+            .check(&mail(full_domain_type()))
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_domain_type_leaving_out_binding_fields() {
+        let unbound = json!([
+            { "name": "name", "type": "string" },
+            { "name": "version", "type": "string" }
+        ]);
+        let no_contract = json!([
+            { "name": "name", "type": "string" },
+            { "name": "chainId", "type": "uint256" }
+        ]);
+        let wrong_kind = json!([
+            { "name": "chainId", "type": "uint64" },
+            { "name": "verifyingContract", "type": "address" }
+        ]);
+        for domain_type in [unbound, no_contract, wrong_kind] {
+            let typed_data = mail(domain_type);
+            assert!(typed_data.digest().is_ok());
+            assert!(allowlist().check(&typed_data).is_err());
+        }
+    }
+}
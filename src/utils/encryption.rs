@@ -21,3 +21,17 @@ pub fn decrypt(obsf: &[u8], key: &[u8]) -> String {
         .expect("Error decrypting ciphertext");
     String::from_utf8(plaintext).unwrap()
 }
+
+/// Like [`decrypt`], but reports a wrong key or tampered ciphertext as an error.
+pub fn try_decrypt(obsf: &[u8], key: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    type NonceSize = <ChaCha20Poly1305 as AeadCore>::NonceSize;
+    if obsf.len() < NonceSize::to_usize() || key.len() != 32 {
+        return Err(anyhow::Error::msg("Invalid ciphertext"));
+    }
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(key));
+    let (nonce, ciphertext) = obsf.split_at(NonceSize::to_usize());
+    let nonce = GenericArray::from_slice(nonce);
+    cipher
+        .decrypt(nonce, ciphertext)
+        .map_err(|_| anyhow::Error::msg("Error decrypting ciphertext"))
+}
@@ -0,0 +1,234 @@
+use crate::utils::key_store::{public_key_address, sign_with_secret, KeyStore};
+use anyhow::Error;
+use bip39::Mnemonic;
+use hmac::{Hmac, Mac};
+use secp256k1::{All, PublicKey, Scalar, Secp256k1, SecretKey};
+use sha2::Sha512;
+use web3::{signing::Signature, types::Address};
+use zeroize::Zeroizing;
+
+const HARDENED_OFFSET: u32 = 1 << 31;
+/// BIP-44 account path for Ethereum; child `i` is `m/44'/60'/0'/0/i`.
+pub const ETH_ACCOUNT_PATH: &str = "m/44'/60'/0'/0";
+
+/// BIP-32 extended private key.
+struct ExtendedKey {
+    key: SecretKey,
+    chain_code: Zeroizing<[u8; 32]>,
+}
+
+/// Parse a BIP-32 path such as `m/44'/60'/0'/0/7` (`h` is accepted for hardened).
+pub fn parse_derivation_path(path: &str) -> Result<Vec<u32>, Error> {
+    let mut segments = path.split('/');
+    if segments.next() != Some("m") {
+        return Err(Error::msg(format!("Invalid derivation path: {}", path)));
+    }
+    segments
+        .map(|segment| {
+            let (index, hardened) = match segment.strip_suffix(['\'', 'h']) {
+                Some(index) => (index, true),
+                None => (segment, false),
+            };
+            let index: u32 = index
+                .parse()
+                .map_err(|_| Error::msg(format!("Invalid derivation path: {}", path)))?;
+            if index >= HARDENED_OFFSET {
+                return Err(Error::msg(format!(
+                    "Derivation index out of range: {}",
+                    path
+                )));
+            }
+            Ok(if hardened {
+                index + HARDENED_OFFSET
+            } else {
+                index
+            })
+        })
+        .collect()
+}
+
+/// Keys derived from a BIP-39 master seed. Key ids are BIP-32 derivation
+/// paths, so any `m/...` path given in a request can be signed with.
+pub struct HdKeyStore {
+    master: ExtendedKey,
+    secp: Secp256k1<All>,
+}
+
+impl HdKeyStore {
+    pub fn from_mnemonic(phrase: &str, passphrase: &str) -> Result<Self, Error> {
+        let mnemonic = Mnemonic::parse(phrase)
+            .map_err(|err| Error::msg(format!("Invalid mnemonic: {}", err)))?;
+        let seed = Zeroizing::new(mnemonic.to_seed(passphrase));
+        Self::from_seed(seed.as_slice())
+    }
+
+    pub fn from_seed(seed: &[u8]) -> Result<Self, Error> {
+        let i = hmac_sha512(b"Bitcoin seed", seed)?;
+        Ok(HdKeyStore {
+            master: extended_key_from_hmac(&i)?,
+            secp: Secp256k1::new(),
+        })
+    }
+
+    /// Load the mnemonic from `MNEMONIC_FILE` (or the TTY when unset or empty)
+    /// and the optional BIP-39 passphrase from `MNEMONIC_PASSPHRASE`.
+    pub fn from_env() -> Result<Self, Error> {
+        let phrase = match dotenvy::var("MNEMONIC_FILE")
+            .ok()
+            .filter(|path| !path.is_empty())
+        {
+            Some(path) => Zeroizing::new(std::fs::read_to_string(&path).map_err(|err| {
+                Error::msg(format!("Error reading mnemonic file {}: {}", path, err))
+            })?),
+            None => Zeroizing::new(rpassword::prompt_password("Mnemonic: ")?),
+        };
+        let passphrase = Zeroizing::new(dotenvy::var("MNEMONIC_PASSPHRASE").unwrap_or_default());
+        Self::from_mnemonic(phrase.trim(), &passphrase)
+    }
+
+    fn derive(&self, path: &str) -> Result<ExtendedKey, Error> {
+        let mut key = ExtendedKey {
+            key: self.master.key,
+            chain_code: self.master.chain_code.clone(),
+        };
+        for index in parse_derivation_path(path)? {
+            key = self.derive_child(&key, index)?;
+        }
+        Ok(key)
+    }
+
+    fn derive_child(&self, parent: &ExtendedKey, index: u32) -> Result<ExtendedKey, Error> {
+        let mut data = Zeroizing::new(Vec::with_capacity(37));
+        if index >= HARDENED_OFFSET {
+            data.push(0);
+            data.extend_from_slice(&parent.key.secret_bytes());
+        } else {
+            let public_key = PublicKey::from_secret_key(&self.secp, &parent.key);
+            data.extend_from_slice(&public_key.serialize());
+        }
+        data.extend_from_slice(&index.to_be_bytes());
+
+        let i = hmac_sha512(parent.chain_code.as_slice(), &data)?;
+        let mut tweak = [0u8; 32];
+        tweak.copy_from_slice(&i[..32]);
+        let tweak =
+            Scalar::from_be_bytes(tweak).map_err(|_| Error::msg("Derived key is out of range"))?;
+        let key = parent
+            .key
+            .add_tweak(&tweak)
+            .map_err(|err| Error::msg(format!("Error deriving child key: {}", err)))?;
+        let mut chain_code = Zeroizing::new([0u8; 32]);
+        chain_code.copy_from_slice(&i[32..]);
+        Ok(ExtendedKey { key, chain_code })
+    }
+}
+
+impl KeyStore for HdKeyStore {
+    fn key_ids(&self) -> Vec<String> {
+        vec![format!("{}/0", ETH_ACCOUNT_PATH)]
+    }
+
+    fn address(&self, key_id: &str) -> Result<Address, Error> {
+        let public_key = self.public_key(key_id)?;
+        Ok(public_key_address(&public_key))
+    }
+
+    fn public_key(&self, key_id: &str) -> Result<Vec<u8>, Error> {
+        let derived = self.derive(key_id)?;
+        let public_key = PublicKey::from_secret_key(&self.secp, &derived.key);
+        Ok(public_key.serialize_uncompressed().to_vec())
+    }
+
+    fn sign_digest(&self, key_id: &str, digest: &[u8]) -> Result<Signature, Error> {
+        let derived = self.derive(key_id)?;
+        sign_with_secret(&self.secp, &derived.key, digest)
+    }
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> Result<Zeroizing<[u8; 64]>, Error> {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key)
+        .map_err(|err| Error::msg(format!("Invalid HMAC key: {}", err)))?;
+    mac.update(data);
+    let mut output = Zeroizing::new([0u8; 64]);
+    output.copy_from_slice(&mac.finalize().into_bytes());
+    Ok(output)
+}
+
+fn extended_key_from_hmac(i: &[u8; 64]) -> Result<ExtendedKey, Error> {
+    let key = SecretKey::from_slice(&i[..32])
+        .map_err(|err| Error::msg(format!("Invalid master key: {}", err)))?;
+    let mut chain_code = Zeroizing::new([0u8; 32]);
+    chain_code.copy_from_slice(&i[32..]);
+    Ok(ExtendedKey { key, chain_code })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ABANDON_MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon \
+        abandon abandon abandon abandon abandon about";
+
+    fn secret_at(store: &HdKeyStore, path: &str) -> String {
+        hex::encode(store.derive(path).unwrap().key.secret_bytes())
+    }
+
+    #[test]
+    fn parses_derivation_paths() {
+        assert_eq!(
+            parse_derivation_path("m/44'/60'/0'/0/7").unwrap(),
+            vec![
+                44 + HARDENED_OFFSET,
+                60 + HARDENED_OFFSET,
+                HARDENED_OFFSET,
+                0,
+                7
+            ]
+        );
+        assert_eq!(
+            parse_derivation_path("m/0h/1").unwrap(),
+            vec![HARDENED_OFFSET, 1]
+        );
+        assert!(parse_derivation_path("m").unwrap().is_empty());
+        assert!(parse_derivation_path("44'/60'").is_err());
+        assert!(parse_derivation_path("m/x").is_err());
+        assert!(parse_derivation_path("m/2147483648").is_err());
+    }
+
+    // BIP-32 test vector 1
+    #[test]
+    fn derives_bip32_vector() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let store = HdKeyStore::from_seed(&seed).unwrap();
+        assert_eq!(
+            secret_at(&store, "m"),
+            "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35"
+        );
+        assert_eq!(
+            secret_at(&store, "m/0'"),
+            "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea"
+        );
+        assert_eq!(
+            secret_at(&store, "m/0'/1"),
+            "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368"
+        );
+        assert_eq!(
+            secret_at(&store, "m/0'/1/2'/2/1000000000"),
+            "471b76e389e528d6de6d816857e012c5455051cad6660850e58372a6c3e6e7c8"
+        );
+    }
+
+    #[test]
+    fn derives_first_ethereum_account_of_bip39_mnemonic() {
+        let store = HdKeyStore::from_mnemonic(ABANDON_MNEMONIC, "").unwrap();
+        let key_id = format!("{}/0", ETH_ACCOUNT_PATH);
+        assert_eq!(
+            secret_at(&store, &key_id),
+            "1ab42cc412b618bdea3a599e3c9bae199ebf030895b039e9db1e30dafb12b727"
+        );
+        assert_eq!(
+            format!("{:?}", store.address(&key_id).unwrap()),
+            "0x9858effd232b4033e47d90003d41ec34ecaeda94"
+        );
+    }
+}
@@ -1,29 +1,36 @@
-use anyhow::Error;
-use hkdf::Hkdf;
-use p256::{
-    ecdh::EphemeralSecret, ecdsa::signature::Verifier, ecdsa::VerifyingKey, EncodedPoint, PublicKey,
+use crate::utils::{
+    abi_registry::{
+        encode_call, encode_constructor, erc1155_abi, erc20_abi, erc721_abi, AbiRegistry,
+    },
+    client_registry::ClientRegistry,
+    eip712::{DomainAllowlist, TypedData},
+    identity::exchange_transcript,
+    key_schedule::SessionKeys,
+    key_store::KeyStore,
+    key_versions::split_version,
+    permit::{Permit, PermitSpenders, PERMIT2_ADDRESS},
+    safe::{SafeSignatureType, SafeTransaction, DEFAULT_SAFE_VERSION},
 };
+use anyhow::Error;
+use p256::{ecdh::EphemeralSecret, ecdsa::signature::Verifier, EncodedPoint, PublicKey};
 use rand_core::OsRng;
-use redis::Commands;
-use rlp::RlpStream;
+use rlp::{DecoderError, Rlp, RlpStream};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::types::Json;
 use std::str::FromStr;
 use web3::{
-    api::{Eth, Namespace},
     contract::tokens::Tokenize,
     ethabi::Token,
     signing,
     signing::Signature,
-    transports::Http,
-    types::{
-        AccessList, Address, Bytes, SignedTransaction, TransactionParameters, H160, H256, U256, U64,
-    },
+    types::{AccessList, AccessListItem, Address, Bytes, SignedTransaction, H160, H256, U256, U64},
 };
 const LEGACY_TX_ID: u64 = 0;
 const ACCESSLISTS_TX_ID: u64 = 1;
 const EIP1559_TX_ID: u64 = 2;
+pub const MAX_BATCH_SIZE: usize = 500;
+/// Deterministic deployment proxy present at the same address on most chains.
+pub const CREATE2_FACTORY: &str = "0x4e59b44847b379578588920ca78fbf26c0b4956c";
 
 #[derive(Debug)]
 pub struct TransactionParam {
@@ -41,73 +48,604 @@ pub struct TransactionParam {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TxRequest {
     pub chain_id: String,
+    /// Recipient; may be left empty when the endpoint determines it.
+    #[serde(default)]
     pub to: String,
     pub nonce: String,
     pub value: String,
     pub gas: String,
+    /// Required for legacy and EIP-2930 transactions.
+    #[serde(default)]
     pub gas_price: String,
+    /// `0` legacy (default), `1` EIP-2930 or `2` EIP-1559.
+    #[serde(rename = "type", default)]
+    pub tx_type: Option<String>,
+    #[serde(default)]
+    pub max_fee_per_gas: Option<String>,
+    #[serde(default)]
+    pub max_priority_fee_per_gas: Option<String>,
+    #[serde(default)]
+    pub access_list: Option<AccessList>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TxBroadcastRequest {
+    /// Not used for signing, which never touches the network.
+    #[serde(default)]
     pub network_rpc: String,
+    pub key_id: String,
     pub bridge_address: String,
     pub tx: TxRequest,
     pub token_address: Option<String>,
-    pub abi: Option<Json<Value>>,
+}
+
+/// Call of a function of a contract registered in the [`AbiRegistry`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ContractCallRequest {
+    pub key_id: String,
+    /// Registered contract name or address.
+    pub contract: String,
+    /// Function name, or signature such as `mint(address,uint256)`.
+    pub function: String,
+    #[serde(default)]
+    pub args: Vec<Value>,
+    pub tx: TxRequest,
+}
+
+/// Contract creation. Without `salt` the transaction deploys with CREATE; with
+/// it the init code is sent to a CREATE2 factory.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployRequest {
+    pub key_id: String,
+    /// Init bytecode, `0x` hex.
+    pub bytecode: String,
+    /// Contract ABI, required to encode `constructor_args`.
+    #[serde(default)]
+    pub abi: Option<Value>,
+    #[serde(default)]
+    pub constructor_args: Vec<Value>,
+    /// 32-byte CREATE2 salt, `0x` hex.
+    #[serde(default)]
+    pub salt: Option<String>,
+    /// CREATE2 factory taking `salt ++ init code` as calldata; defaults to
+    /// [`CREATE2_FACTORY`].
+    #[serde(default)]
+    pub factory: Option<String>,
+    pub tx: TxRequest,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignDeployFeild {
+    #[serde(flatten)]
+    pub signed: SignRawTxFeild,
+    pub contract_address: Address,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TypedDataRequest {
+    pub key_id: String,
+    pub typed_data: TypedData,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignTypedDataFeild {
+    pub digest: H256,
+    /// `r ‖ s ‖ v` with `v` 27 or 28.
+    pub signature: Bytes,
+    pub key_id: String,
+    pub key_version: u32,
+}
+
+/// EIP-2612 or Permit2 permit signed by the key's address as owner.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PermitRequest {
+    pub key_id: String,
+    pub chain_id: String,
+    /// Permit2 deployment; defaults to the canonical address.
+    #[serde(default)]
+    pub permit2_address: Option<String>,
+    #[serde(flatten)]
+    pub permit: Permit,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignPermitFeild {
+    #[serde(flatten)]
+    pub signed: SignTypedDataFeild,
+    pub owner: Address,
+    /// Signed document, as submitted alongside the signature.
+    pub typed_data: TypedData,
+}
+
+/// Safe transaction signed by the key as one of the Safe's owners.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SafeTxRequest {
+    pub key_id: String,
+    pub safe_address: String,
+    pub chain_id: String,
+    /// Safe contract version, `1.3.0` when omitted.
+    #[serde(default)]
+    pub safe_version: Option<String>,
+    #[serde(default)]
+    pub signature_type: SafeSignatureType,
+    pub safe_tx: SafeTransaction,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignSafeTxFeild {
+    pub safe_tx_hash: H256,
+    /// Owner signature `r ‖ s ‖ v` as packed into `execTransaction` signatures.
+    pub signature: Bytes,
+    pub owner: Address,
+    pub key_id: String,
+    pub key_version: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageEncoding {
+    /// UTF-8 text signed as is.
+    #[default]
+    Text,
+    /// `0x` hex decoded to bytes before signing.
+    Hex,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignMessageRequest {
+    pub key_id: String,
+    pub message: String,
+    #[serde(default)]
+    pub encoding: MessageEncoding,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignMessageFeild {
+    pub message_hash: H256,
+    /// `r ‖ s ‖ v` with `v` 27 or 28.
+    pub signature: Bytes,
+    pub address: Address,
+    pub key_id: String,
+    pub key_version: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoverRequest {
+    pub message: String,
+    #[serde(default)]
+    pub encoding: MessageEncoding,
+    /// 65-byte `r ‖ s ‖ v` signature, `0x` hex.
+    pub signature: String,
+}
+
+/// Unsigned transaction built by the caller: an EIP-155 legacy RLP list or an
+/// EIP-2718 typed envelope (`0x01`/`0x02` ‖ RLP), `0x` hex.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnsignedTxRequest {
+    pub key_id: String,
+    pub unsigned_tx: String,
+}
+
+/// Every field of a transaction, as signed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DecodedTransaction {
+    #[serde(rename = "type")]
+    pub tx_type: u64,
+    pub chain_id: u64,
+    pub nonce: U256,
+    pub gas: U256,
+    pub gas_price: Option<U256>,
+    pub max_fee_per_gas: Option<U256>,
+    pub max_priority_fee_per_gas: Option<U256>,
+    pub to: Option<Address>,
+    pub value: U256,
+    pub data: Bytes,
+    pub access_list: AccessList,
+}
+
+impl DecodedTransaction {
+    fn new(tx: &TransactionParam, chain_id: u64) -> Self {
+        let tx_type = tx
+            .transaction_type
+            .map(|t| t.as_u64())
+            .unwrap_or(LEGACY_TX_ID);
+        let is_eip1559 = tx_type == EIP1559_TX_ID;
+        DecodedTransaction {
+            tx_type,
+            chain_id,
+            nonce: tx.nonce,
+            gas: tx.gas,
+            gas_price: (!is_eip1559).then_some(tx.gas_price),
+            max_fee_per_gas: is_eip1559.then_some(tx.gas_price),
+            max_priority_fee_per_gas: is_eip1559.then_some(tx.max_priority_fee_per_gas),
+            to: tx.to,
+            value: tx.value,
+            data: tx.data.clone().into(),
+            access_list: tx.access_list.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignUnsignedTxFeild {
+    #[serde(flatten)]
+    pub signed: SignRawTxFeild,
+    pub decoded: DecodedTransaction,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DecodeTxRequest {
+    /// Signed raw transaction, `0x` hex.
+    pub raw_tx: String,
+}
+
+/// ERC-20 `transfer`/`transferFrom` carried in a transaction's calldata.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Erc20TransferFeild {
+    pub token: Option<Address>,
+    pub function: String,
+    pub from: Option<Address>,
+    pub recipient: Address,
+    pub amount: U256,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DecodedSignedTxFeild {
+    pub tx_hash: H256,
+    pub message_hash: H256,
+    pub sender: Address,
+    pub signature: SignatureFormats,
+    pub decoded: DecodedTransaction,
+    pub erc20_transfer: Option<Erc20TransferFeild>,
+}
+
+/// [`TxRequest`] of a batch item, whose nonce the batch assigns.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchTxRequest {
+    pub chain_id: String,
+    pub to: String,
+    /// Must match the assigned nonce when given.
+    #[serde(default)]
+    pub nonce: Option<String>,
+    pub value: String,
+    pub gas: String,
+    #[serde(default)]
+    pub gas_price: String,
+    #[serde(rename = "type", default)]
+    pub tx_type: Option<String>,
+    #[serde(default)]
+    pub max_fee_per_gas: Option<String>,
+    #[serde(default)]
+    pub max_priority_fee_per_gas: Option<String>,
+    #[serde(default)]
+    pub access_list: Option<AccessList>,
+}
+
+impl BatchTxRequest {
+    /// The item as a [`TxRequest`] at its assigned `nonce`.
+    fn with_nonce(&self, nonce: U256) -> Result<TxRequest, Error> {
+        if let Some(given) = &self.nonce {
+            if parse_u256(given, "nonce")? != nonce {
+                return Err(Error::msg(format!(
+                    "Nonce {} does not follow the batch order",
+                    given
+                )));
+            }
+        }
+        Ok(TxRequest {
+            chain_id: self.chain_id.clone(),
+            to: self.to.clone(),
+            nonce: nonce.to_string(),
+            value: self.value.clone(),
+            gas: self.gas.clone(),
+            gas_price: self.gas_price.clone(),
+            tx_type: self.tx_type.clone(),
+            max_fee_per_gas: self.max_fee_per_gas.clone(),
+            max_priority_fee_per_gas: self.max_priority_fee_per_gas.clone(),
+            access_list: self.access_list.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchTransfer {
+    /// ERC-20 token; native transfer when absent.
+    #[serde(default)]
+    pub token_address: Option<String>,
+    pub tx: BatchTxRequest,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchTransferRequest {
+    pub key_id: String,
+    /// Nonce of the first transfer; the following ones count up from it.
+    pub start_nonce: String,
+    pub transfers: Vec<BatchTransfer>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NftStandard {
+    Erc721,
+    Erc1155,
+}
+
+/// `safeTransferFrom` of ERC-721 token, or of one or more ERC-1155 ids (batched
+/// through `safeBatchTransferFrom` when more than one).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NftTransferRequest {
+    pub key_id: String,
+    pub standard: NftStandard,
+    pub token_address: String,
+    /// Current owner; defaults to the signer.
+    #[serde(default)]
+    pub from: Option<String>,
+    pub recipient: String,
+    pub token_ids: Vec<String>,
+    /// ERC-1155 amount per id.
+    #[serde(default)]
+    pub amounts: Vec<String>,
+    /// Data passed to the receiver hook, `0x` hex.
+    #[serde(default)]
+    pub data: Option<String>,
+    pub tx: TxRequest,
+}
+
+/// Plaintext of an encrypted request: the request with the sequence number and
+/// Unix timestamp the session checks to reject replays.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestEnvelope<T> {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub request: T,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TxRequestTest {
     pub sign_tx: String,
-    pub pk: Vec<u8>,
+    /// Session id returned by the key exchange.
+    pub session_id: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SignTx {
     pub message: Vec<u8>,
     pub signature: Vec<u8>,
-    pub v_key: Vec<u8>,
+    /// Enrolled client whose verifying key checks `signature`.
+    pub client_id: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SignRawTxFeild {
     pub message: [u8; 32],
     pub r_tx: Bytes,
+    /// `r ‖ s ‖ v` with the transaction's `v` as minimal big-endian bytes,
+    /// 65 bytes unless an EIP-155 `v` exceeds 255.
     pub signature: Vec<u8>,
+    pub tx_hash: H256,
+    pub from: Address,
+    pub signature_formats: SignatureFormats,
+    pub key_id: String,
+    pub key_version: u32,
+}
+
+/// One signature in the encodings verifiers commonly expect.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignatureFormats {
+    pub r: H256,
+    pub s: H256,
+    /// `v` as placed in the transaction, including the EIP-155 chain id.
+    pub v: u64,
+    pub y_parity: u8,
+    /// EIP-2098 `r ‖ yParity·2²⁵⁵ + s`.
+    pub compact: Bytes,
+    /// ASN.1 DER `SEQUENCE { r INTEGER, s INTEGER }`.
+    pub der: Bytes,
+}
+
+impl SignatureFormats {
+    pub fn new(v: u64, r: H256, s: H256) -> Result<Self, Error> {
+        let y_parity = y_parity(v);
+        let mut compact = [r.as_bytes(), s.as_bytes()].concat();
+        compact[32] |= y_parity << 7;
+        let der = secp256k1::ecdsa::Signature::from_compact(&[r.as_bytes(), s.as_bytes()].concat())
+            .map_err(|err| Error::msg(format!("Invalid signature: {}", err)))?
+            .serialize_der()
+            .to_vec();
+        Ok(SignatureFormats {
+            r,
+            s,
+            v,
+            y_parity,
+            compact: compact.into(),
+            der: der.into(),
+        })
+    }
 }
 
-pub async fn sign_erc20(transaction: &TxBroadcastRequest) -> Result<SignRawTxFeild, Error> {
+/// Sign an ERC-20 `transfer` of `tx.value` to `tx.to`. The calldata is encoded
+/// against the built-in ERC-20 ABI, so no RPC endpoint is needed.
+pub fn sign_erc20(
+    transaction: &TxBroadcastRequest,
+    key_store: &dyn KeyStore,
+) -> Result<SignRawTxFeild, Error> {
     //========== implement authorization checks
     let token_address = match &transaction.token_address {
         Some(token) => token,
         None => return Err(Error::msg("Token Address Not Found")),
     };
-    let abi = match &transaction.abi {
-        Some(abi) => abi,
-        None => return Err(Error::msg("Token Address Not Found")),
-    };
     println!("Receive tx: {:#?}", &transaction);
-    let transport = match web3::transports::Http::new(&transaction.network_rpc) {
-        Ok(transport) => transport,
-        Err(err) => return Err(Error::msg(format!("Error Initialize Transport: {}", err))),
+    let tx = erc20_transfer_param(token_address, &transaction.tx)?;
+    println!("Tx Param: {:#?}", &tx);
+    sign_tx_field(&tx, &transaction.tx, key_store, &transaction.key_id)
+}
+
+pub async fn sign_raw_tx(
+    transaction: &TxBroadcastRequest,
+    key_store: &dyn KeyStore,
+) -> Result<SignRawTxFeild, Error> {
+    let tx = native_transfer_param(&transaction.tx)?;
+    println!("Tx Param: {:#?}", &tx);
+    sign_tx_field(&tx, &transaction.tx, key_store, &transaction.key_id)
+}
+
+/// Sign every transfer of a batch with consecutive nonces from `start_nonce`
+/// and the same key version. Nothing is returned unless every item signs.
+pub fn sign_batch(
+    request: &BatchTransferRequest,
+    key_store: &dyn KeyStore,
+) -> Result<Vec<SignRawTxFeild>, Error> {
+    if request.transfers.is_empty() || request.transfers.len() > MAX_BATCH_SIZE {
+        return Err(Error::msg(format!(
+            "Batch must contain 1 to {} transfers",
+            MAX_BATCH_SIZE
+        )));
+    }
+    let start_nonce = parse_u256(&request.start_nonce, "start_nonce")?;
+    let mut txs = Vec::with_capacity(request.transfers.len());
+    for (index, transfer) in request.transfers.iter().enumerate() {
+        let nonce = start_nonce
+            .checked_add(U256::from(index))
+            .ok_or_else(|| Error::msg("Nonce overflow"))?;
+        let tx = batch_transfer_param(transfer, nonce)
+            .map_err(|err| Error::msg(format!("Transfer {}: {}", index, err)))?;
+        txs.push(tx);
+    }
+
+    let (signer_id, _) = key_store.pin_version(&request.key_id)?;
+    let signed = txs
+        .iter()
+        .enumerate()
+        .map(|(index, (tx, chain_id))| {
+            sign_with_chain_id(tx, *chain_id, key_store, &signer_id)
+                .map_err(|err| Error::msg(format!("Transfer {}: {}", index, err)))
+        })
+        .collect::<Result<Vec<SignRawTxFeild>, Error>>()?;
+    println!(
+        "Signed batch of {} transfers from nonce {}",
+        signed.len(),
+        start_nonce
+    );
+    Ok(signed)
+}
+
+/// Sign an ERC-721 or ERC-1155 `safeTransferFrom` encoded against the built-in ABIs.
+pub fn sign_nft_transfer(
+    request: &NftTransferRequest,
+    key_store: &dyn KeyStore,
+) -> Result<SignRawTxFeild, Error> {
+    let token_address = H160::from_str(&request.token_address)
+        .map_err(|err| Error::msg(format!("Error parsing token address: {}", err)))?;
+    let recipient = H160::from_str(&request.recipient)
+        .map_err(|err| Error::msg(format!("Error parsing recipient address: {}", err)))?;
+    let (signer_id, _) = key_store.pin_version(&request.key_id)?;
+    let from = match &request.from {
+        Some(from) => H160::from_str(from)
+            .map_err(|err| Error::msg(format!("Error parsing from address: {}", err)))?,
+        None => key_store.address(&signer_id)?,
     };
-    let contract = match contract(
-        transport.clone(),
-        H160::from_str(token_address).unwrap(),
-        abi,
-    )
-    .await
-    {
-        Ok(contract) => contract,
-        Err(err) => return Err(Error::msg(format!("Error: {}", err))),
+    let token_ids = request
+        .token_ids
+        .iter()
+        .map(|id| parse_u256(id, "token id"))
+        .collect::<Result<Vec<U256>, Error>>()?;
+    let data = match &request.data {
+        Some(data) => hex::decode(data.trim_start_matches("0x"))
+            .map_err(|err| Error::msg(format!("Error parsing data: {}", err)))?,
+        None => Vec::new(),
+    };
+
+    let (abi, function_name, tokens) = match (request.standard, token_ids.as_slice()) {
+        (NftStandard::Erc721, [token_id]) => {
+            if !request.amounts.is_empty() {
+                return Err(Error::msg("ERC-721 transfers take no amounts"));
+            }
+            let mut tokens = vec![
+                Token::Address(from),
+                Token::Address(recipient),
+                Token::Uint(*token_id),
+            ];
+            if request.data.is_some() {
+                tokens.push(Token::Bytes(data));
+            }
+            (erc721_abi(), "safeTransferFrom", tokens)
+        }
+        (NftStandard::Erc721, _) => {
+            return Err(Error::msg("ERC-721 transfers take exactly one token id"))
+        }
+        (NftStandard::Erc1155, []) => return Err(Error::msg("No token id given")),
+        (NftStandard::Erc1155, token_ids) => {
+            if request.amounts.len() != token_ids.len() {
+                return Err(Error::msg("Each ERC-1155 token id needs an amount"));
+            }
+            let amounts = request
+                .amounts
+                .iter()
+                .map(|amount| parse_u256(amount, "amount").map(Token::Uint))
+                .collect::<Result<Vec<Token>, Error>>()?;
+            let id_tokens: Vec<Token> = token_ids.iter().map(|id| Token::Uint(*id)).collect();
+            match (id_tokens.as_slice(), amounts.as_slice()) {
+                ([token_id], [amount]) => (
+                    erc1155_abi(),
+                    "safeTransferFrom",
+                    vec![
+                        Token::Address(from),
+                        Token::Address(recipient),
+                        token_id.clone(),
+                        amount.clone(),
+                        Token::Bytes(data),
+                    ],
+                ),
+                _ => (
+                    erc1155_abi(),
+                    "safeBatchTransferFrom",
+                    vec![
+                        Token::Address(from),
+                        Token::Address(recipient),
+                        Token::Array(id_tokens),
+                        Token::Array(amounts),
+                        Token::Bytes(data),
+                    ],
+                ),
+            }
+        }
     };
-    let actual_transfer_amount = U256::from_dec_str(&transaction.tx.value).unwrap();
+    // overloads differ in their argument count
+    let function = abi
+        .functions_by_name(function_name)
+        .map_err(|err| Error::msg(format!("Error: {}", err)))?
+        .iter()
+        .find(|function| function.inputs.len() == tokens.len())
+        .ok_or_else(|| Error::msg(format!("Function not found: {}", function_name)))?;
+    let fn_data = function
+        .encode_input(&tokens)
+        .map_err(|err| Error::msg(format!("Error encoding {}: {}", function_name, err)))?;
+    let value = parse_u256(&request.tx.value, "value")?;
+    let tx = transaction_param(&request.tx, Some(token_address), value, fn_data)?;
+    sign_tx_field(&tx, &request.tx, key_store, &signer_id)
+}
+
+/// Build one batch item with its assigned `nonce`, returning it with its chain id.
+fn batch_transfer_param(
+    transfer: &BatchTransfer,
+    nonce: U256,
+) -> Result<(TransactionParam, u64), Error> {
+    let tx = transfer.tx.with_nonce(nonce)?;
+    let tx_param = match &transfer.token_address {
+        Some(token_address) => erc20_transfer_param(token_address, &tx)?,
+        None => native_transfer_param(&tx)?,
+    };
+    Ok((tx_param, parse_chain_id(&tx)?))
+}
+
+fn erc20_transfer_param(token_address: &str, tx: &TxRequest) -> Result<TransactionParam, Error> {
+    let token_address = match H160::from_str(token_address) {
+        Ok(address) => address,
+        Err(err) => return Err(Error::msg(format!("Error parsing token address: {}", err))),
+    };
+    let actual_transfer_amount = parse_u256(&tx.value, "value")?;
     println!("Actual Transfer Amount: {}", actual_transfer_amount);
-    let nonce = U256::from_dec_str(&transaction.tx.nonce).unwrap();
-    let gas_price = U256::from_dec_str(&transaction.tx.gas_price).unwrap();
-    let gas = U256::from_dec_str(&transaction.tx.gas).unwrap();
-    let receiver_address = match H160::from_str(&transaction.tx.to) {
+    let receiver_address = match H160::from_str(&tx.to) {
         Ok(address) => address,
         Err(err) => {
             return Err(Error::msg(format!(
@@ -120,63 +658,17 @@ pub async fn sign_erc20(transaction: &TxBroadcastRequest) -> Result<SignRawTxFei
         Token::Address(receiver_address),
         Token::Uint(actual_transfer_amount),
     );
-    let fn_data = contract
-        .abi()
+    let fn_data = erc20_abi()
         .function("transfer")
         .and_then(|function| function.encode_input(&params.into_tokens()))
-        .map_err(|err| web3::Error::Decoder(format!("Error: {}", err)))?;
-    let tx_p = TransactionParameters {
-        nonce: Some(nonce),
-        to: Some(contract.address()),
-        gas,
-        gas_price: Some(gas_price),
-        data: Bytes(fn_data),
-        ..Default::default()
-    };
-    println!("Tx P: {:#?}", tx_p);
-    let max_priority_fee_per_gas = match tx_p.transaction_type {
-        Some(tx_type) if tx_type == U64::from(EIP1559_TX_ID) => {
-            tx_p.max_priority_fee_per_gas.unwrap_or(gas_price)
-        }
-        _ => gas_price,
-    };
-    let tx = TransactionParam {
-        to: Some(tx_p.to.unwrap()),
-        nonce,
-        gas: tx_p.gas,
-        gas_price: tx_p.gas_price.unwrap(),
-        value: tx_p.value,
-        data: tx_p.data.0,
-        transaction_type: tx_p.transaction_type,
-        access_list: tx_p.access_list.unwrap_or_default(),
-        max_priority_fee_per_gas,
-    };
-    println!("Tx Param: {:#?}", &tx);
-    // init key instance
-    let private_key = dotenvy::var("PRIVATE_KEY").expect("Private key must be set");
-    let key = match web3::signing::SecretKey::from_str(&private_key) {
-        Ok(k) => k,
-        Err(err) => return Err(Error::msg(format!("Error parsing key: {}", err))),
-    };
-    let sign_tx = sign_raw(&tx, &key, u64::from_str(&transaction.tx.chain_id).unwrap());
-    let combined_sign_bytes = combined_sign_bytes(sign_tx.v, sign_tx.r, sign_tx.s);
-
-    println!("Signed Tx: {:#?}", sign_tx);
-    let sign_tx_field = SignRawTxFeild {
-        message: sign_tx.message_hash.0,
-        r_tx: sign_tx.raw_transaction,
-        signature: combined_sign_bytes.to_vec(),
-    };
-    Ok(sign_tx_field)
+        .map_err(|err| Error::msg(format!("Error encoding transfer: {}", err)))?;
+    transaction_param(tx, Some(token_address), U256::zero(), fn_data)
 }
 
-pub async fn sign_raw_tx(transaction: &TxBroadcastRequest) -> Result<SignRawTxFeild, Error> {
-    let actual_transfer_amount = U256::from_dec_str(&transaction.tx.value).unwrap();
+fn native_transfer_param(tx: &TxRequest) -> Result<TransactionParam, Error> {
+    let actual_transfer_amount = parse_u256(&tx.value, "value")?;
     println!("Actual Transfer Amount: {}", actual_transfer_amount);
-    let nonce = U256::from_dec_str(&transaction.tx.nonce).unwrap();
-    let gas_price = U256::from_dec_str(&transaction.tx.gas_price).unwrap();
-    let gas = U256::from_dec_str(&transaction.tx.gas).unwrap();
-    let receiver_address = match H160::from_str(&transaction.tx.to) {
+    let receiver_address = match H160::from_str(&tx.to) {
         Ok(address) => address,
         Err(err) => {
             return Err(Error::msg(format!(
@@ -185,45 +677,462 @@ pub async fn sign_raw_tx(transaction: &TxBroadcastRequest) -> Result<SignRawTxFe
             )))
         }
     };
-    let tx_p = TransactionParameters {
-        nonce: Some(nonce),
-        to: Some(receiver_address),
-        value: actual_transfer_amount,
-        gas,
-        gas_price: Some(gas_price),
-        ..Default::default()
+    transaction_param(
+        tx,
+        Some(receiver_address),
+        actual_transfer_amount,
+        Vec::new(),
+    )
+}
+
+/// Sign a call of a registered contract; `tx.to` is the contract address.
+pub fn sign_contract_call(
+    request: &ContractCallRequest,
+    abi_registry: &AbiRegistry,
+    key_store: &dyn KeyStore,
+) -> Result<SignRawTxFeild, Error> {
+    let contract = abi_registry.get(&request.contract)?;
+    if !request.tx.to.is_empty() && H160::from_str(&request.tx.to).ok() != Some(contract.address) {
+        return Err(Error::msg(format!(
+            "Recipient {} is not the address of {}",
+            request.tx.to, contract.name
+        )));
+    }
+    let function = contract.function(&request.function)?;
+    let data = encode_call(function, &request.args)?;
+    let value = parse_u256(&request.tx.value, "value")?;
+    let tx = transaction_param(&request.tx, Some(contract.address), value, data)?;
+    sign_tx_field(&tx, &request.tx, key_store, &request.key_id)
+}
+
+/// Sign `tx` carrying `data` as is; an empty `tx.to` creates a contract.
+pub fn sign_transaction_request(
+    tx: &TxRequest,
+    data: Vec<u8>,
+    key_store: &dyn KeyStore,
+    key_id: &str,
+) -> Result<SignRawTxFeild, Error> {
+    let to = match tx.to.as_str() {
+        "" => None,
+        to => Some(
+            H160::from_str(to)
+                .map_err(|err| Error::msg(format!("Error parsing receiver address: {}", err)))?,
+        ),
+    };
+    let value = parse_u256(&tx.value, "value")?;
+    let tx_param = transaction_param(tx, to, value, data)?;
+    sign_tx_field(&tx_param, tx, key_store, key_id)
+}
+
+/// Decode, validate and sign a transaction the caller encoded itself.
+pub fn sign_unsigned_tx(
+    request: &UnsignedTxRequest,
+    key_store: &dyn KeyStore,
+) -> Result<SignUnsignedTxFeild, Error> {
+    let unsigned = hex::decode(request.unsigned_tx.trim_start_matches("0x"))
+        .map_err(|err| Error::msg(format!("Error parsing unsigned transaction: {}", err)))?;
+    let (tx, chain_id, _) = decode_transaction(&unsigned, false)?;
+    let decoded = DecodedTransaction::new(&tx, chain_id);
+
+    Ok(SignUnsignedTxFeild {
+        signed: sign_with_chain_id(&tx, chain_id, key_store, &request.key_id)?,
+        decoded,
+    })
+}
+
+/// Decode a signed raw transaction and recover its sender.
+pub fn decode_signed_tx(request: &DecodeTxRequest) -> Result<DecodedSignedTxFeild, Error> {
+    let raw = hex::decode(request.raw_tx.trim_start_matches("0x"))
+        .map_err(|err| Error::msg(format!("Error parsing raw transaction: {}", err)))?;
+    let (tx, chain_id, signature) = decode_transaction(&raw, true)?;
+    let signature = signature.ok_or_else(|| Error::msg("Transaction is not signed"))?;
+    let message_hash: H256 = signing::keccak256(&encode(&tx, chain_id, None)).into();
+    // typed transactions carry the bare y parity, `recover` expects 27/28
+    let recovery_v = if signature.v < 2 {
+        signature.v + 27
+    } else {
+        signature.v
     };
-    let max_priority_fee_per_gas = match tx_p.transaction_type {
-        Some(tx_type) if tx_type == U64::from(EIP1559_TX_ID) => {
-            tx_p.max_priority_fee_per_gas.unwrap_or(gas_price)
+    let sender = recover(web3::types::Recovery::new(
+        message_hash,
+        recovery_v,
+        signature.r,
+        signature.s,
+    ))?;
+    Ok(DecodedSignedTxFeild {
+        tx_hash: signing::keccak256(&raw).into(),
+        message_hash,
+        sender,
+        signature: SignatureFormats::new(signature.v, signature.r, signature.s)?,
+        erc20_transfer: decode_erc20_transfer(&tx),
+        decoded: DecodedTransaction::new(&tx, chain_id),
+    })
+}
+
+fn decode_erc20_transfer(tx: &TransactionParam) -> Option<Erc20TransferFeild> {
+    let abi = erc20_abi();
+    let function = ["transfer", "transferFrom"]
+        .iter()
+        .filter_map(|name| abi.function(name).ok())
+        .find(|function| tx.data.starts_with(&function.short_signature()))?;
+    let tokens = function.decode_input(&tx.data[4..]).ok()?;
+    let (from, recipient, amount) = match tokens.as_slice() {
+        [Token::Address(recipient), Token::Uint(amount)] => (None, *recipient, *amount),
+        [Token::Address(from), Token::Address(recipient), Token::Uint(amount)] => {
+            (Some(*from), *recipient, *amount)
         }
-        _ => gas_price,
+        _ => return None,
     };
-    let tx = TransactionParam {
-        to: Some(tx_p.to.unwrap()),
-        nonce,
-        gas: tx_p.gas,
-        gas_price: tx_p.gas_price.unwrap(),
-        value: tx_p.value,
-        data: tx_p.data.0,
-        transaction_type: tx_p.transaction_type,
-        access_list: tx_p.access_list.unwrap_or_default(),
-        max_priority_fee_per_gas,
+    Some(Erc20TransferFeild {
+        token: tx.to,
+        function: function.name.clone(),
+        from,
+        recipient,
+        amount,
+    })
+}
+
+/// Sign a contract deployment and predict the address of the new contract.
+pub fn sign_deploy(
+    request: &DeployRequest,
+    key_store: &dyn KeyStore,
+) -> Result<SignDeployFeild, Error> {
+    let bytecode = hex::decode(request.bytecode.trim_start_matches("0x"))
+        .map_err(|err| Error::msg(format!("Error parsing bytecode: {}", err)))?;
+    if bytecode.is_empty() {
+        return Err(Error::msg("Bytecode is empty"));
+    }
+    let init_code = match &request.abi {
+        Some(abi) => {
+            let abi: web3::ethabi::Contract = serde_json::from_value(abi.clone())
+                .map_err(|err| Error::msg(format!("Error parsing ABI: {}", err)))?;
+            encode_constructor(&abi, bytecode, &request.constructor_args)?
+        }
+        None if request.constructor_args.is_empty() => bytecode,
+        None => {
+            return Err(Error::msg(
+                "ABI is required to encode constructor arguments",
+            ))
+        }
     };
-    println!("Tx Param: {:#?}", &tx);
-    let private_key = dotenvy::var("PRIVATE_KEY").expect("Private key must be set");
-    let key = match web3::signing::SecretKey::from_str(&private_key) {
-        Ok(k) => k,
-        Err(err) => return Err(Error::msg(format!("Error parsing key: {}", err))),
+    let (signer_id, _) = key_store.pin_version(&request.key_id)?;
+    let sender = key_store.address(&signer_id)?;
+    let value = parse_u256(&request.tx.value, "value")?;
+
+    let (tx, contract_address) = match &request.salt {
+        None => {
+            let tx = transaction_param(&request.tx, None, value, init_code)?;
+            let contract_address = create_address(sender, tx.nonce);
+            (tx, contract_address)
+        }
+        Some(salt) => {
+            let salt = H256::from_str(salt)
+                .map_err(|err| Error::msg(format!("Error parsing salt: {}", err)))?;
+            let factory = H160::from_str(request.factory.as_deref().unwrap_or(CREATE2_FACTORY))
+                .map_err(|err| Error::msg(format!("Error parsing factory address: {}", err)))?;
+            let contract_address = create2_address(factory, salt, &init_code);
+            let data = [salt.as_bytes(), &init_code].concat();
+            let tx = transaction_param(&request.tx, Some(factory), value, data)?;
+            (tx, contract_address)
+        }
+    };
+    let signed = sign_tx_field(&tx, &request.tx, key_store, &signer_id)?;
+    Ok(SignDeployFeild {
+        signed,
+        contract_address,
+    })
+}
+
+/// Sign EIP-712 typed data whose domain is on the allowlist.
+pub fn sign_typed_data(
+    request: &TypedDataRequest,
+    allowlist: &DomainAllowlist,
+    key_store: &dyn KeyStore,
+) -> Result<SignTypedDataFeild, Error> {
+    allowlist.check(&request.typed_data)?;
+    let (signer_id, key_version) = key_store.pin_version(&request.key_id)?;
+    sign_typed_digest(
+        &request.typed_data,
+        key_store,
+        &signer_id,
+        split_version(&request.key_id)?.0,
+        key_version,
+    )
+}
+
+/// Sign an EIP-2612 or Permit2 permit for the token and spender in `request`,
+/// both of which must be allowlisted.
+pub fn sign_permit(
+    request: &PermitRequest,
+    allowlist: &DomainAllowlist,
+    spenders: &PermitSpenders,
+    key_store: &dyn KeyStore,
+) -> Result<SignPermitFeild, Error> {
+    let chain_id = parse_u256(&request.chain_id, "chain id")?;
+    if chain_id.is_zero() || chain_id > U256::from(u64::MAX) {
+        return Err(Error::msg(format!("Invalid chain id: {}", chain_id)));
+    }
+    let permit2 = H160::from_str(
+        request
+            .permit2_address
+            .as_deref()
+            .unwrap_or(PERMIT2_ADDRESS),
+    )
+    .map_err(|err| Error::msg(format!("Error parsing Permit2 address: {}", err)))?;
+    let (signer_id, key_version) = key_store.pin_version(&request.key_id)?;
+    let owner = key_store.address(&signer_id)?;
+    let typed_data = request
+        .permit
+        .typed_data(chain_id.as_u64(), owner, permit2)?;
+    request
+        .permit
+        .check(chain_id.as_u64(), &typed_data, allowlist, spenders)?;
+    let signed = sign_typed_digest(
+        &typed_data,
+        key_store,
+        &signer_id,
+        split_version(&request.key_id)?.0,
+        key_version,
+    )?;
+    Ok(SignPermitFeild {
+        signed,
+        owner,
+        typed_data,
+    })
+}
+
+/// Compute the `safeTxHash` of a Safe transaction and sign it as an owner.
+pub fn sign_safe_tx(
+    request: &SafeTxRequest,
+    key_store: &dyn KeyStore,
+) -> Result<SignSafeTxFeild, Error> {
+    let chain_id = parse_u256(&request.chain_id, "chain id")?;
+    if chain_id.is_zero() || chain_id > U256::from(u64::MAX) {
+        return Err(Error::msg(format!("Invalid chain id: {}", chain_id)));
+    }
+    let safe_address = H160::from_str(&request.safe_address)
+        .map_err(|err| Error::msg(format!("Error parsing Safe address: {}", err)))?;
+    let safe_version = request
+        .safe_version
+        .as_deref()
+        .unwrap_or(DEFAULT_SAFE_VERSION);
+    let safe_tx_hash = request
+        .safe_tx
+        .typed_data(chain_id.as_u64(), safe_address, safe_version)?
+        .digest()?;
+    let (signer_id, key_version) = key_store.pin_version(&request.key_id)?;
+    let signature = match request.signature_type {
+        SafeSignatureType::Eip712 => {
+            let signature = key_store.sign_digest(&signer_id, safe_tx_hash.as_bytes())?;
+            combined_sign_bytes(signature.v + 27, signature.r, signature.s)
+        }
+        SafeSignatureType::EthSign => {
+            // Safe marks `eth_sign` signatures by adding 4 to `v`
+            let mut signature = sign_message(safe_tx_hash.as_bytes(), key_store, &signer_id)?;
+            signature[64] += 4;
+            signature.to_vec()
+        }
     };
-    let sign_tx = sign_raw(&tx, &key, u64::from_str(&transaction.tx.chain_id).unwrap());
+    Ok(SignSafeTxFeild {
+        safe_tx_hash,
+        signature: signature.into(),
+        owner: key_store.address(&signer_id)?,
+        key_id: split_version(&request.key_id)?.0.to_string(),
+        key_version,
+    })
+}
+
+fn sign_typed_digest(
+    typed_data: &TypedData,
+    key_store: &dyn KeyStore,
+    signer_id: &str,
+    key_id: &str,
+    key_version: u32,
+) -> Result<SignTypedDataFeild, Error> {
+    let digest = typed_data.digest()?;
+    let signature = key_store.sign_digest(signer_id, digest.as_bytes())?;
+    let combined_sign_bytes = combined_sign_bytes(signature.v + 27, signature.r, signature.s);
+    Ok(SignTypedDataFeild {
+        digest,
+        signature: combined_sign_bytes.into(),
+        key_id: key_id.to_string(),
+        key_version,
+    })
+}
+
+pub fn message_bytes(message: &str, encoding: MessageEncoding) -> Result<Vec<u8>, Error> {
+    match encoding {
+        MessageEncoding::Text => Ok(message.as_bytes().to_vec()),
+        MessageEncoding::Hex => hex::decode(message.trim_start_matches("0x"))
+            .map_err(|err| Error::msg(format!("Error parsing message: {}", err))),
+    }
+}
+
+/// Sign a message with `personal_sign` semantics.
+pub fn sign_personal_message(
+    request: &SignMessageRequest,
+    key_store: &dyn KeyStore,
+) -> Result<SignMessageFeild, Error> {
+    let message = message_bytes(&request.message, request.encoding)?;
+    let (signer_id, key_version) = key_store.pin_version(&request.key_id)?;
+    let signature = sign_message(&message, key_store, &signer_id)?;
+    Ok(SignMessageFeild {
+        message_hash: signing::hash_message(&message),
+        signature: signature.to_vec().into(),
+        address: key_store.address(&signer_id)?,
+        key_id: split_version(&request.key_id)?.0.to_string(),
+        key_version,
+    })
+}
+
+/// Recover the signer of a `personal_sign` signature.
+pub fn recover_message_signer(request: &RecoverRequest) -> Result<Address, Error> {
+    let message = message_bytes(&request.message, request.encoding)?;
+    let signature = hex::decode(request.signature.trim_start_matches("0x"))
+        .map_err(|err| Error::msg(format!("Error parsing signature: {}", err)))?;
+    if signature.len() != 65 {
+        return Err(Error::msg("Signature must be 65 bytes"));
+    }
+    recover(web3::types::Recovery::from_raw_signature(
+        message, signature,
+    )?)
+}
+
+/// Address of a contract created with CREATE by `sender` at `nonce`.
+pub fn create_address(sender: Address, nonce: U256) -> Address {
+    let mut stream = RlpStream::new_list(2);
+    stream.append(&sender);
+    stream.append(&nonce);
+    Address::from_slice(&signing::keccak256(&stream.out())[12..])
+}
+
+/// Address of a contract created with CREATE2 by `factory`.
+pub fn create2_address(factory: Address, salt: H256, init_code: &[u8]) -> Address {
+    let hash = signing::keccak256(
+        &[
+            &[0xff],
+            factory.as_bytes(),
+            salt.as_bytes(),
+            &signing::keccak256(init_code),
+        ]
+        .concat(),
+    );
+    Address::from_slice(&hash[12..])
+}
+
+/// Sign `tx` with the version of `key_id` pinned at this point.
+fn sign_tx_field(
+    tx: &TransactionParam,
+    request: &TxRequest,
+    key_store: &dyn KeyStore,
+    key_id: &str,
+) -> Result<SignRawTxFeild, Error> {
+    sign_with_chain_id(tx, parse_chain_id(request)?, key_store, key_id)
+}
+
+fn sign_with_chain_id(
+    tx: &TransactionParam,
+    chain_id: u64,
+    key_store: &dyn KeyStore,
+    key_id: &str,
+) -> Result<SignRawTxFeild, Error> {
+    let (signer_id, key_version) = key_store.pin_version(key_id)?;
+    let sign_tx = sign_raw(tx, key_store, &signer_id, chain_id)?;
     let combined_sign_bytes = combined_sign_bytes(sign_tx.v, sign_tx.r, sign_tx.s);
-    let sign_tx_field = SignRawTxFeild {
+
+    println!("Signed Tx: {:#?}", sign_tx);
+    Ok(SignRawTxFeild {
         message: sign_tx.message_hash.0,
         r_tx: sign_tx.raw_transaction,
-        signature: combined_sign_bytes.to_vec(),
+        signature: combined_sign_bytes,
+        tx_hash: sign_tx.transaction_hash,
+        from: key_store.address(&signer_id)?,
+        signature_formats: SignatureFormats::new(sign_tx.v, sign_tx.r, sign_tx.s)?,
+        key_id: split_version(key_id)?.0.to_string(),
+        key_version,
+    })
+}
+
+/// Parse a decimal field. Empty input is refused: `from_dec_str` would read it
+/// as zero.
+fn parse_u256(value: &str, field: &str) -> Result<U256, Error> {
+    if value.is_empty() {
+        return Err(Error::msg(format!("{} is required", field)));
+    }
+    U256::from_dec_str(value)
+        .map_err(|err| Error::msg(format!("Error parsing {}: {:?}", field, err)))
+}
+
+fn parse_chain_id(tx: &TxRequest) -> Result<u64, Error> {
+    u64::from_str(&tx.chain_id)
+        .map_err(|err| Error::msg(format!("Error parsing chain_id: {}", err)))
+}
+
+/// Build the transaction to sign from the gas, fee and access-list fields of
+/// `tx`, according to its type.
+fn transaction_param(
+    tx: &TxRequest,
+    to: Option<Address>,
+    value: U256,
+    data: Vec<u8>,
+) -> Result<TransactionParam, Error> {
+    let transaction_type = match tx.tx_type.as_deref() {
+        None | Some("") => LEGACY_TX_ID,
+        Some(tx_type) => u64::from_str(tx_type)
+            .map_err(|err| Error::msg(format!("Error parsing type: {}", err)))?,
+    };
+    let access_list = tx.access_list.clone().unwrap_or_default();
+    let (gas_price, max_priority_fee_per_gas) = match transaction_type {
+        LEGACY_TX_ID | ACCESSLISTS_TX_ID => {
+            if transaction_type == LEGACY_TX_ID && !access_list.is_empty() {
+                return Err(Error::msg(
+                    "Legacy transactions cannot carry an access list",
+                ));
+            }
+            if tx.gas_price.is_empty() {
+                return Err(Error::msg("gas_price is required for type 0 and 1"));
+            }
+            let gas_price = parse_u256(&tx.gas_price, "gas_price")?;
+            (gas_price, gas_price)
+        }
+        EIP1559_TX_ID => {
+            let max_fee_per_gas = tx
+                .max_fee_per_gas
+                .as_deref()
+                .ok_or_else(|| Error::msg("max_fee_per_gas is required for type 2"))?;
+            let max_priority_fee_per_gas = tx
+                .max_priority_fee_per_gas
+                .as_deref()
+                .ok_or_else(|| Error::msg("max_priority_fee_per_gas is required for type 2"))?;
+            let max_fee_per_gas = parse_u256(max_fee_per_gas, "max_fee_per_gas")?;
+            let max_priority_fee_per_gas =
+                parse_u256(max_priority_fee_per_gas, "max_priority_fee_per_gas")?;
+            if max_priority_fee_per_gas > max_fee_per_gas {
+                return Err(Error::msg(
+                    "max_priority_fee_per_gas must not exceed max_fee_per_gas",
+                ));
+            }
+            // `gas_price` holds the max fee per gas of EIP-1559 transactions
+            (max_fee_per_gas, max_priority_fee_per_gas)
+        }
+        other => {
+            return Err(Error::msg(format!(
+                "Unsupported transaction type: {}",
+                other
+            )))
+        }
     };
-    Ok(sign_tx_field)
+    Ok(TransactionParam {
+        to,
+        nonce: parse_u256(&tx.nonce, "nonce")?,
+        gas: parse_u256(&tx.gas, "gas")?,
+        gas_price,
+        value,
+        data,
+        transaction_type: Some(U64::from(transaction_type)),
+        access_list,
+        max_priority_fee_per_gas,
+    })
 }
 
 fn rlp_append_legacy(tx: &TransactionParam, stream: &mut RlpStream) {
@@ -356,8 +1265,164 @@ fn encode(tx: &TransactionParam, chain_id: u64, signature: Option<&Signature>) -
     }
 }
 
-/// Sign and return a raw signed transaction.
-fn sign_raw(tx: &TransactionParam, sign: impl signing::Key, chain_id: u64) -> SignedTransaction {
+/// Decode a transaction in the form [`encode`] produces, unsigned or signed.
+/// Only encodings that re-encode to the same bytes are accepted, so the hash
+/// always covers exactly the bytes that were sent.
+fn decode_transaction(
+    raw: &[u8],
+    signed: bool,
+) -> Result<(TransactionParam, u64, Option<Signature>), Error> {
+    let (transaction_type, payload) = match raw.first() {
+        Some(&tx_id) if tx_id as u64 == ACCESSLISTS_TX_ID || tx_id as u64 == EIP1559_TX_ID => {
+            (tx_id as u64, &raw[1..])
+        }
+        Some(&prefix) if prefix >= 0xc0 => (LEGACY_TX_ID, raw),
+        _ => return Err(Error::msg("Unsupported transaction envelope")),
+    };
+    let rlp = Rlp::new(payload);
+    let decode_err = |err: DecoderError| Error::msg(format!("Error decoding transaction: {}", err));
+    // fields before the signature; typed transactions start with the chain id
+    let field_count = match transaction_type {
+        LEGACY_TX_ID => 6,
+        ACCESSLISTS_TX_ID => 8,
+        _ => 9,
+    };
+    let expected_items = if transaction_type == LEGACY_TX_ID || signed {
+        field_count + 3
+    } else {
+        field_count
+    };
+    if rlp.item_count().map_err(decode_err)? != expected_items {
+        return Err(Error::msg(format!(
+            "Type {} {} transaction must have {} fields",
+            transaction_type,
+            if signed { "signed" } else { "unsigned" },
+            expected_items
+        )));
+    }
+
+    let to_at = |index: usize| -> Result<Option<Address>, Error> {
+        let item = rlp.at(index).map_err(decode_err)?;
+        if item.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(item.as_val().map_err(decode_err)?))
+        }
+    };
+    let access_list_at = |index: usize| -> Result<AccessList, Error> {
+        rlp.at(index)
+            .map_err(decode_err)?
+            .iter()
+            .map(|item| {
+                Ok(AccessListItem {
+                    address: item.val_at(0).map_err(decode_err)?,
+                    storage_keys: item.list_at(1).map_err(decode_err)?,
+                })
+            })
+            .collect()
+    };
+    let tx = match transaction_type {
+        LEGACY_TX_ID => TransactionParam {
+            nonce: rlp.val_at(0).map_err(decode_err)?,
+            gas_price: rlp.val_at(1).map_err(decode_err)?,
+            gas: rlp.val_at(2).map_err(decode_err)?,
+            to: to_at(3)?,
+            value: rlp.val_at(4).map_err(decode_err)?,
+            data: rlp.val_at(5).map_err(decode_err)?,
+            transaction_type: Some(U64::from(LEGACY_TX_ID)),
+            access_list: AccessList::new(),
+            max_priority_fee_per_gas: U256::zero(),
+        },
+        ACCESSLISTS_TX_ID => {
+            let gas_price: U256 = rlp.val_at(2).map_err(decode_err)?;
+            TransactionParam {
+                nonce: rlp.val_at(1).map_err(decode_err)?,
+                gas_price,
+                gas: rlp.val_at(3).map_err(decode_err)?,
+                to: to_at(4)?,
+                value: rlp.val_at(5).map_err(decode_err)?,
+                data: rlp.val_at(6).map_err(decode_err)?,
+                transaction_type: Some(U64::from(ACCESSLISTS_TX_ID)),
+                access_list: access_list_at(7)?,
+                max_priority_fee_per_gas: gas_price,
+            }
+        }
+        _ => {
+            let max_priority_fee_per_gas: U256 = rlp.val_at(2).map_err(decode_err)?;
+            let max_fee_per_gas: U256 = rlp.val_at(3).map_err(decode_err)?;
+            if max_priority_fee_per_gas > max_fee_per_gas {
+                return Err(Error::msg(
+                    "max_priority_fee_per_gas must not exceed max_fee_per_gas",
+                ));
+            }
+            TransactionParam {
+                nonce: rlp.val_at(1).map_err(decode_err)?,
+                max_priority_fee_per_gas,
+                gas_price: max_fee_per_gas,
+                gas: rlp.val_at(4).map_err(decode_err)?,
+                to: to_at(5)?,
+                value: rlp.val_at(6).map_err(decode_err)?,
+                data: rlp.val_at(7).map_err(decode_err)?,
+                transaction_type: Some(U64::from(EIP1559_TX_ID)),
+                access_list: access_list_at(8)?,
+            }
+        }
+    };
+
+    let signature_at = |index: usize| -> Result<Signature, Error> {
+        let r: U256 = rlp.val_at(index + 1).map_err(decode_err)?;
+        let s: U256 = rlp.val_at(index + 2).map_err(decode_err)?;
+        let (mut r_bytes, mut s_bytes) = ([0u8; 32], [0u8; 32]);
+        r.to_big_endian(&mut r_bytes);
+        s.to_big_endian(&mut s_bytes);
+        Ok(Signature {
+            v: rlp.val_at(index).map_err(decode_err)?,
+            r: r_bytes.into(),
+            s: s_bytes.into(),
+        })
+    };
+    let (chain_id, signature) = match (transaction_type, signed) {
+        (LEGACY_TX_ID, false) => {
+            let empty = signature_at(6)?;
+            if !empty.r.is_zero() || !empty.s.is_zero() {
+                return Err(Error::msg("Transaction is already signed"));
+            }
+            (empty.v, None)
+        }
+        (LEGACY_TX_ID, true) => {
+            let signature = signature_at(6)?;
+            if signature.v < 35 {
+                return Err(Error::msg(
+                    "Legacy transactions without EIP-155 replay protection are not supported",
+                ));
+            }
+            ((signature.v - 35) / 2, Some(signature))
+        }
+        (_, false) => (rlp.val_at(0).map_err(decode_err)?, None),
+        (_, true) => {
+            let signature = signature_at(field_count)?;
+            if signature.v > 1 {
+                return Err(Error::msg("Invalid signature y parity"));
+            }
+            (rlp.val_at(0).map_err(decode_err)?, Some(signature))
+        }
+    };
+    if chain_id == 0 {
+        return Err(Error::msg("Chain id must not be 0"));
+    }
+    if encode(&tx, chain_id, signature.as_ref()) != raw {
+        return Err(Error::msg("Transaction is not canonically encoded"));
+    }
+    Ok((tx, chain_id, signature))
+}
+
+/// Sign and return a raw signed transaction with the key `key_id` of `key_store`.
+fn sign_raw(
+    tx: &TransactionParam,
+    key_store: &dyn KeyStore,
+    key_id: &str,
+    chain_id: u64,
+) -> Result<SignedTransaction, Error> {
     let adjust_v_value = matches!(
         tx.transaction_type.map(|t| t.as_u64()),
         Some(LEGACY_TX_ID) | None
@@ -368,14 +1433,14 @@ fn sign_raw(tx: &TransactionParam, sign: impl signing::Key, chain_id: u64) -> Si
 
     let hash = signing::keccak256(encoded.as_ref());
 
-    let signature = if adjust_v_value {
-        println!("Address Sign: {:#?}", sign.address());
-        sign.sign(&hash, Some(chain_id))
-            .expect("hash is non-zero 32-bytes; qed")
-    } else {
-        sign.sign_message(&hash)
-            .expect("hash is non-zero 32-bytes; qed")
-    };
+    let mut signature = key_store.sign_digest(key_id, &hash)?;
+    if adjust_v_value {
+        // EIP-155 replay protection
+        signature.v = chain_id
+            .checked_mul(2)
+            .and_then(|v| v.checked_add(35 + signature.v))
+            .ok_or_else(|| Error::msg(format!("Chain id {} is too large", chain_id)))?;
+    }
 
     let signed = encode(tx, chain_id, Some(&signature));
     let transaction_hash: web3::types::H256 = signing::keccak256(signed.as_ref()).into();
@@ -389,19 +1454,25 @@ fn sign_raw(tx: &TransactionParam, sign: impl signing::Key, chain_id: u64) -> Si
         transaction_hash,
     };
     println!("{:#?}", &s);
-    s
+    Ok(s)
 }
 
-pub fn sign_message(message: &[u8], sign: impl signing::Key) -> [u8; 65] {
-    let signature = sign.sign_message(message).unwrap();
+/// `personal_sign`: sign the EIP-191 hash of `message`, `v` is 27 or 28.
+pub fn sign_message(
+    message: &[u8],
+    key_store: &dyn KeyStore,
+    key_id: &str,
+) -> Result<[u8; 65], Error> {
+    let message_hash = signing::hash_message(message);
+    let signature = key_store.sign_digest(key_id, message_hash.as_bytes())?;
     let combined_bytes: [u8; 65] = {
         let mut combined = [0u8; 65];
         combined[..32].copy_from_slice(&signature.r.0);
         combined[32..64].copy_from_slice(&signature.s.0);
-        combined[64] = signature.v as u8;
+        combined[64] = signature.v as u8 + 27;
         combined
     };
-    combined_bytes
+    Ok(combined_bytes)
 }
 
 pub fn recover(recovery: web3::types::Recovery) -> anyhow::Result<Address> {
@@ -417,78 +1488,781 @@ pub fn recover(recovery: web3::types::Recovery) -> anyhow::Result<Address> {
     Ok(address)
 }
 
-async fn contract(
-    transport: Http,
-    address: Address,
-    abi_json: &Json<Value>,
-) -> Result<web3::contract::Contract<Http>, Error> {
-    let eth = Eth::new(transport);
-    let abi: String = match serde_json::to_string(&abi_json) {
-        Ok(abi) => abi,
-        Err(err) => {
-            return Err(err.into());
-        }
-    };
-    let json_bytes = abi.as_bytes().to_vec();
-    match web3::contract::Contract::from_json(eth, address, &json_bytes) {
-        Ok(contract) => Ok(contract),
-        Err(err) => Err(Error::msg(format!("Error Initialize Contract: {}", err))),
-    }
-}
-
-pub fn verify_signature(sign_tx: &SignTx) -> bool {
+/// Verify `sign_tx.signature` over the message with the verifying key enrolled
+/// for `sign_tx.client_id`, which must be active and allowed `operation`.
+pub fn verify_signature(
+    sign_tx: &SignTx,
+    clients: &ClientRegistry,
+    operation: &str,
+) -> Result<(), Error> {
     // ==== signature verifying process =====
-    // ========= received payload: message (tx_field), signature, verification_bytes
-    let received_v_key = VerifyingKey::from_sec1_bytes(&sign_tx.v_key).unwrap();
-    let signature_parse = p256::ecdsa::Signature::from_slice(&sign_tx.signature).unwrap();
+    // ========= received payload: message (tx_field), signature, client id
+    let verifying_key = clients.verifying_key(&sign_tx.client_id, operation)?;
+    let signature_parse = p256::ecdsa::Signature::from_slice(&sign_tx.signature)
+        .map_err(|err| Error::msg(format!("Invalid client signature: {}", err)))?;
 
-    let stage = received_v_key
-        .verify(&sign_tx.message, &signature_parse.clone())
+    let stage = verifying_key
+        .verify(&sign_tx.message, &signature_parse)
         .is_ok();
     println!("Stage of verification: {}", stage);
-    stage
+    if !stage {
+        return Err(Error::msg("Client signature does not match"));
+    }
+    Ok(())
 }
 
-pub fn hsm_generate_pk(origin_pk: &[u8]) -> (Vec<u8>, EphemeralSecret) {
+/// Generate the HSM ephemeral key for an exchange with `origin_pk` at
+/// `timestamp` and derive the session keys from it.
+pub fn hsm_generate_pk(origin_pk: &[u8], timestamp: u64) -> Result<(Vec<u8>, SessionKeys), Error> {
     let hsm_secret = EphemeralSecret::random(&mut OsRng);
     let hsm_pk_bytes = EncodedPoint::from(hsm_secret.public_key()).to_bytes();
-    let _sk = generate_sk(origin_pk, &hsm_secret);
     println!("Generated PK HSM: {:?}", hsm_pk_bytes.to_vec());
+    let transcript = exchange_transcript(&hsm_pk_bytes, origin_pk, timestamp);
+    let keys = generate_sk(origin_pk, &hsm_secret, &transcript)?;
 
-    (hsm_pk_bytes.to_vec(), hsm_secret)
+    Ok((hsm_pk_bytes.to_vec(), keys))
 }
 
-pub fn generate_sk(pk_bytes: &[u8], sk_bytes: &EphemeralSecret) -> Result<Vec<u8>, anyhow::Error> {
-    let public = PublicKey::from_sec1_bytes(pk_bytes).expect("bob's public key is invalid!");
+/// Derive the session keys of the ECDH exchange with `pk_bytes` over
+/// `transcript`.
+pub fn generate_sk(
+    pk_bytes: &[u8],
+    sk_bytes: &EphemeralSecret,
+    transcript: &[u8],
+) -> Result<SessionKeys, anyhow::Error> {
+    let public = PublicKey::from_sec1_bytes(pk_bytes)
+        .map_err(|err| Error::msg(format!("Invalid client public key: {}", err)))?;
 
     let shared_key = sk_bytes.diffie_hellman(&public);
+    SessionKeys::derive(shared_key.raw_secret_bytes(), transcript)
+}
 
-    let shared = shared_key.raw_secret_bytes();
+/// `r ‖ s ‖ v`, with `v` in as many big-endian bytes as it needs.
+fn combined_sign_bytes(v: u64, r: H256, s: H256) -> Vec<u8> {
+    let v_bytes = v.to_be_bytes();
+    let leading_zeros = v_bytes.iter().take_while(|byte| **byte == 0).count().min(7);
+    [&r.0[..], &s.0[..], &v_bytes[leading_zeros..]].concat()
+}
 
-    let client = redis::Client::open("redis://127.0.0.1:6379")?;
-    let mut con = client.get_connection()?;
+/// Recovery bit of a raw (0/1), pre-EIP-155 (27/28) or EIP-155 `v`.
+fn y_parity(v: u64) -> u8 {
+    match v {
+        0 | 1 => v as u8,
+        27 | 28 => (v - 27) as u8,
+        v => (v.saturating_sub(35) % 2) as u8,
+    }
+}
 
-    let output_length = 32;
-    let hkdf = Hkdf::<sha2::Sha256>::new(None, shared.as_slice());
-    let info = pk_bytes;
-    // Expand the shared key using the provided info and desired output length
-    let mut okm = vec![0u8; output_length];
-    let _ = hkdf.expand(info, &mut okm);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::key_store::InMemoryKeyStore;
 
-    con.set(pk_bytes, shared.to_vec())?;
+    fn tx_request() -> TxRequest {
+        TxRequest {
+            chain_id: "1".to_string(),
+            to: "0x3535353535353535353535353535353535353535".to_string(),
+            nonce: "9".to_string(),
+            value: "1000000000000000000".to_string(),
+            gas: "21000".to_string(),
+            gas_price: "20000000000".to_string(),
+            tx_type: None,
+            max_fee_per_gas: None,
+            max_priority_fee_per_gas: None,
+            access_list: None,
+        }
+    }
 
-    // let k: Vec<u8> = con.get(pk_bytes).expect("Key value not found");
-    // println!("Key is {:#?}", k);
+    #[test]
+    fn rejects_missing_gas_price_for_legacy_and_2930() {
+        for tx_type in [None, Some("1".to_string())] {
+            let tx = TxRequest {
+                gas_price: String::new(),
+                tx_type,
+                ..tx_request()
+            };
+            let err = transaction_param(&tx, None, U256::zero(), vec![]).unwrap_err();
+            assert!(err.to_string().contains("gas_price is required"));
+        }
+        let tx = TxRequest {
+            gas_price: String::new(),
+            tx_type: Some("2".to_string()),
+            max_fee_per_gas: Some("30000000000".to_string()),
+            max_priority_fee_per_gas: Some("1000000000".to_string()),
+            ..tx_request()
+        };
+        assert!(transaction_param(&tx, None, U256::zero(), vec![]).is_ok());
+    }
 
-    println!("OKM: {:?}", okm);
+    #[test]
+    fn rejects_empty_numeric_fields() {
+        assert!(parse_u256("", "nonce").is_err());
+        assert_eq!(parse_u256("0", "nonce").unwrap(), U256::zero());
+        let tx = TxRequest {
+            gas: String::new(),
+            ..tx_request()
+        };
+        assert!(transaction_param(&tx, None, U256::zero(), vec![]).is_err());
+    }
 
-    Ok(shared.to_vec())
-}
+    #[test]
+    fn create_address_matches_known_deployments() {
+        let sender = Address::from_str("0x6ac7ea33f8831ea9dcc53393aaa88b25a785dbf0").unwrap();
+        let expected = [
+            "0xcd234a471b72ba2f1ccf0a70fcaba648a5eecd8d",
+            "0x343c43a37d37dff08ae8c4a11544c718abb4fcf8",
+            "0xf778b86fa74e846c4f0a1fbd1335fe81c00a0c91",
+            "0xfffd933a0bc612844eaf0c6fe3e5b8e9b6c1d19c",
+        ];
+        for (nonce, expected) in expected.iter().enumerate() {
+            assert_eq!(
+                create_address(sender, U256::from(nonce)),
+                Address::from_str(expected).unwrap()
+            );
+        }
+    }
+
+    // Examples of EIP-1014
+    #[test]
+    fn create2_address_matches_eip1014_examples() {
+        let examples = [
+            (
+                "0x0000000000000000000000000000000000000000",
+                "0x0000000000000000000000000000000000000000000000000000000000000000",
+                "00",
+                "0x4D1A2e2bB4F88F0250f26Ffff098B0b30B26BF38",
+            ),
+            (
+                "0xdeadbeef00000000000000000000000000000000",
+                "0x0000000000000000000000000000000000000000000000000000000000000000",
+                "00",
+                "0xB928f69Bb1D91Cd65274e3c79d8986362984fDA3",
+            ),
+            (
+                "0xdeadbeef00000000000000000000000000000000",
+                "0x000000000000000000000000feed000000000000000000000000000000000000",
+                "00",
+                "0xD04116cDd17beBE565EB2422F2497E06cC1C9833",
+            ),
+            (
+                "0x0000000000000000000000000000000000000000",
+                "0x0000000000000000000000000000000000000000000000000000000000000000",
+                "deadbeef",
+                "0x70f2b2914A2a4b783FaEFb75f459A580616Fcb5e",
+            ),
+            (
+                "0x00000000000000000000000000000000deadbeef",
+                "0x00000000000000000000000000000000000000000000000000000000cafebabe",
+                "deadbeef",
+                "0x60f3f640a8508fC6a86d45DF051962668E1e8AC7",
+            ),
+            (
+                "0x00000000000000000000000000000000deadbeef",
+                "0x00000000000000000000000000000000000000000000000000000000cafebabe",
+                "deadbeefdeadbeefdeadbeefdeadbeefdeadbeefdeadbeefdeadbeefdeadbeefdeadbeefdeadbeefdeadbeef",
+                "0x1d8bfDC5D46DC4f61D6b6115972536eBE6A8854C",
+            ),
+            (
+                "0x0000000000000000000000000000000000000000",
+                "0x0000000000000000000000000000000000000000000000000000000000000000",
+                "",
+                "0xE33C0C7F7df4809055C3ebA6c09CFe4BaF1BD9e0",
+            ),
+        ];
+        for (factory, salt, init_code, expected) in examples {
+            assert_eq!(
+                create2_address(
+                    Address::from_str(factory).unwrap(),
+                    H256::from_str(salt).unwrap(),
+                    &hex::decode(init_code).unwrap(),
+                ),
+                Address::from_str(expected).unwrap()
+            );
+        }
+    }
+
+    const EIP155_KEY: &str = "4646464646464646464646464646464646464646464646464646464646464646";
+    const EIP155_SENDER: &str = "0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f";
+    /// Signing payload and signed transaction of the EIP-155 example.
+    const EIP155_UNSIGNED: &str = "0xec098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a764000080018080";
+    const EIP155_SIGNED: &str = "0xf86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83";
+
+    fn key_store() -> InMemoryKeyStore {
+        let mut store = InMemoryKeyStore::new();
+        store.insert_hex("default", EIP155_KEY).unwrap();
+        store
+    }
+
+    /// One transaction of each supported type.
+    fn transactions() -> Vec<TransactionParam> {
+        let access_list = vec![AccessListItem {
+            address: Address::from_str("0xde0b295669a9fd93d5f28d9ec85e40f4cb697bae").unwrap(),
+            storage_keys: vec![H256::from_low_u64_be(3), H256::from_low_u64_be(7)],
+        }];
+        let tx = |tx_type: u64, access_list: AccessList| TransactionParam {
+            to: Some(Address::from_str("0x3535353535353535353535353535353535353535").unwrap()),
+            nonce: U256::from(9),
+            gas: U256::from(60000),
+            gas_price: U256::from(20_000_000_000u64),
+            value: U256::from(1_000_000_000_000_000_000u64),
+            data: vec![0xa9, 0x05, 0x9c, 0xbb],
+            transaction_type: Some(U64::from(tx_type)),
+            access_list,
+            max_priority_fee_per_gas: if tx_type == EIP1559_TX_ID {
+                U256::from(1_000_000_000u64)
+            } else {
+                U256::from(20_000_000_000u64)
+            },
+        };
+        vec![
+            tx(LEGACY_TX_ID, vec![]),
+            tx(ACCESSLISTS_TX_ID, access_list.clone()),
+            tx(EIP1559_TX_ID, access_list),
+        ]
+    }
+
+    #[test]
+    fn unsigned_transactions_round_trip() {
+        for tx in transactions() {
+            let unsigned = encode(&tx, 5, None);
+            let (decoded, chain_id, signature) = decode_transaction(&unsigned, false).unwrap();
+            assert_eq!(chain_id, 5);
+            assert!(signature.is_none());
+            assert_eq!(encode(&decoded, chain_id, None), unsigned);
+
+            let request = UnsignedTxRequest {
+                key_id: "default".to_string(),
+                unsigned_tx: format!("0x{}", hex::encode(&unsigned)),
+            };
+            let signed = sign_unsigned_tx(&request, &key_store()).unwrap();
+            assert_eq!(
+                signed.decoded.tx_type,
+                tx.transaction_type.unwrap().as_u64()
+            );
+            assert_eq!(signed.decoded.nonce, tx.nonce);
+            assert_eq!(signed.decoded.access_list, tx.access_list);
+            assert_eq!(
+                signed.signed.from,
+                Address::from_str(EIP155_SENDER).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn signs_eip155_example_payload() {
+        let request = UnsignedTxRequest {
+            key_id: "default".to_string(),
+            unsigned_tx: EIP155_UNSIGNED.to_string(),
+        };
+        let signed = sign_unsigned_tx(&request, &key_store()).unwrap();
+        assert_eq!(
+            format!("0x{}", hex::encode(&signed.signed.r_tx.0)),
+            EIP155_SIGNED
+        );
+    }
+
+    #[test]
+    fn rejects_non_canonical_unsigned_transactions() {
+        let mut unsigned = encode(&transactions()[2], 5, None);
+        unsigned.push(0x80);
+        assert!(decode_transaction(&unsigned, false).is_err());
+        assert!(decode_transaction(&[0x03, 0xc0], false).is_err());
+    }
+
+    #[test]
+    fn decodes_eip155_example_sender() {
+        let decoded = decode_signed_tx(&DecodeTxRequest {
+            raw_tx: EIP155_SIGNED.to_string(),
+        })
+        .unwrap();
+        assert_eq!(decoded.sender, Address::from_str(EIP155_SENDER).unwrap());
+        assert_eq!(
+            decoded.tx_hash,
+            H256::from_str("0x33469b22e9f636356c4160a87eb19df52b7412e8eac32a4a55ffe88ea8350788")
+                .unwrap()
+        );
+        assert_eq!(
+            decoded.message_hash,
+            H256::from_str("0xdaf5a779ae972f972197303d7b574746c7ef83eadac0f2791ad23db92e4c8e53")
+                .unwrap()
+        );
+        assert_eq!(decoded.decoded.chain_id, 1);
+        assert_eq!(decoded.decoded.nonce, U256::from(9));
+        assert_eq!(decoded.signature.v, 37);
+    }
+
+    #[test]
+    fn signed_transactions_round_trip() {
+        let store = key_store();
+        for tx in transactions() {
+            let signed = sign_with_chain_id(&tx, 5, &store, "default").unwrap();
+            let decoded = decode_signed_tx(&DecodeTxRequest {
+                raw_tx: format!("0x{}", hex::encode(&signed.r_tx.0)),
+            })
+            .unwrap();
+            assert_eq!(decoded.sender, signed.from);
+            assert_eq!(decoded.tx_hash, signed.tx_hash);
+            assert_eq!(decoded.message_hash.0, signed.message);
+            assert_eq!(decoded.decoded.chain_id, 5);
+            assert_eq!(decoded.decoded.value, tx.value);
+            assert_eq!(decoded.decoded.data.0, tx.data);
+            assert_eq!(decoded.decoded.access_list, tx.access_list);
+
+            let raw = signed.r_tx.0;
+            let (param, chain_id, signature) = decode_transaction(&raw, true).unwrap();
+            assert_eq!(encode(&param, chain_id, signature.as_ref()), raw);
+        }
+    }
+
+    // EIP-2098 test vectors
+    #[test]
+    fn encodes_eip2098_compact_and_der() {
+        let vectors = [
+            (
+                27,
+                "0x68a020a209d3d56c46f38cc50a33f704f4a9a10a59377f8dd762ac66910e9b90",
+                "0x7e865ad05c4035ab5792787d4a0297a43617ae897930a6fe4d822b8faea52064",
+                "7e865ad05c4035ab5792787d4a0297a43617ae897930a6fe4d822b8faea52064",
+                "30440220",
+                "0220",
+            ),
+            (
+                28,
+                "0x9328da16089fcba9bececa81663203989f2df5fe1faa6291a45381c81bd17f76",
+                "0x139c6d6b623b42da56557e5e734a43dc83345ddfadec52cbe24d0cc64f550793",
+                "939c6d6b623b42da56557e5e734a43dc83345ddfadec52cbe24d0cc64f550793",
+                // r has its top bit set and gets a leading zero byte
+                "3045022100",
+                "0220",
+            ),
+        ];
+        for (v, r, s, y_parity_and_s, der_r_prefix, der_s_prefix) in vectors {
+            let r = H256::from_str(r).unwrap();
+            let s = H256::from_str(s).unwrap();
+            let formats = SignatureFormats::new(v, r, s).unwrap();
+            assert_eq!(formats.y_parity, (v - 27) as u8);
+            assert_eq!(
+                hex::encode(&formats.compact.0),
+                format!("{}{}", hex::encode(r), y_parity_and_s)
+            );
+            assert_eq!(
+                hex::encode(&formats.der.0),
+                format!(
+                    "{}{}{}{}",
+                    der_r_prefix,
+                    hex::encode(r),
+                    der_s_prefix,
+                    hex::encode(s)
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn derives_y_parity_from_every_v_form() {
+        assert_eq!(y_parity(0), 0);
+        assert_eq!(y_parity(1), 1);
+        assert_eq!(y_parity(27), 0);
+        assert_eq!(y_parity(28), 1);
+        assert_eq!(y_parity(37), 0);
+        assert_eq!(y_parity(38), 1);
+        assert_eq!(y_parity(137 * 2 + 35), 0);
+        assert_eq!(y_parity(137 * 2 + 36), 1);
+    }
+
+    #[test]
+    fn appends_multi_byte_eip155_v() {
+        let store = key_store();
+        let legacy = &transactions()[0];
+        for chain_id in [137u64, 11_155_111] {
+            let signed = sign_with_chain_id(legacy, chain_id, &store, "default").unwrap();
+            let v = signed.signature_formats.v;
+            assert!(v == chain_id * 2 + 35 || v == chain_id * 2 + 36);
+            assert_eq!(
+                signed.signature_formats.y_parity as u64,
+                v - chain_id * 2 - 35
+            );
+            let v_bytes = v.to_be_bytes();
+            let v_bytes = &v_bytes[v_bytes.iter().take_while(|byte| **byte == 0).count()..];
+            assert!(v_bytes.len() > 1);
+            assert_eq!(signed.signature.len(), 64 + v_bytes.len());
+            assert_eq!(&signed.signature[64..], v_bytes);
+
+            let decoded = decode_signed_tx(&DecodeTxRequest {
+                raw_tx: format!("0x{}", hex::encode(&signed.r_tx.0)),
+            })
+            .unwrap();
+            assert_eq!(decoded.sender, signed.from);
+            assert_eq!(decoded.decoded.chain_id, chain_id);
+            assert_eq!(decoded.signature.v, v);
+        }
+    }
+
+    #[test]
+    fn requires_nonce_outside_batches() {
+        let mut tx = serde_json::to_value(tx_request()).unwrap();
+        tx.as_object_mut().unwrap().remove("nonce");
+        assert!(serde_json::from_value::<TxRequest>(tx).is_err());
+    }
+
+    #[test]
+    fn assigns_consecutive_batch_nonces() {
+        let mut item = serde_json::to_value(tx_request()).unwrap();
+        item.as_object_mut().unwrap().remove("nonce");
+        let transfer = |tx: serde_json::Value| BatchTransfer {
+            token_address: None,
+            tx: serde_json::from_value(tx).unwrap(),
+        };
+        let mut pinned = item.clone();
+        pinned["nonce"] = "8".into();
+        let request = BatchTransferRequest {
+            key_id: "default".to_string(),
+            start_nonce: "7".to_string(),
+            transfers: vec![
+                transfer(item.clone()),
+                transfer(pinned),
+                transfer(item.clone()),
+            ],
+        };
+        let signed = sign_batch(&request, &key_store()).unwrap();
+        let nonces: Vec<U256> = signed
+            .iter()
+            .map(|signed| {
+                decode_signed_tx(&DecodeTxRequest {
+                    raw_tx: format!("0x{}", hex::encode(&signed.r_tx.0)),
+                })
+                .unwrap()
+                .decoded
+                .nonce
+            })
+            .collect();
+        assert_eq!(nonces, vec![U256::from(7), U256::from(8), U256::from(9)]);
+
+        let mut out_of_order = item.clone();
+        out_of_order["nonce"] = "3".into();
+        let request = BatchTransferRequest {
+            key_id: "default".to_string(),
+            start_nonce: "7".to_string(),
+            transfers: vec![transfer(item), transfer(out_of_order)],
+        };
+        assert!(sign_batch(&request, &key_store()).is_err());
+    }
+
+    #[test]
+    fn signs_safe_tx_as_eip712_and_eth_sign_owner() {
+        let request = |signature_type: &str| -> SafeTxRequest {
+            serde_json::from_value(serde_json::json!({
+                "key_id": "default",
+                "safe_address": "0x1111111111111111111111111111111111111111",
+                "chain_id": "1",
+                "safe_version": "1.3.0",
+                "signature_type": signature_type,
+                "safe_tx": {
+                    "to": "0x3535353535353535353535353535353535353535",
+                    "value": "1000000000000000000",
+                    "data": "0xa9059cbb",
+                    "nonce": "7",
+                },
+            }))
+            .unwrap()
+        };
+        let safe_tx_hash =
+            H256::from_str("0xf0d708ae149b3d7037cbd8f36f1cfe6c0b8983393a7105fb4ee89687cb518851")
+                .unwrap();
+        let sender = Address::from_str(EIP155_SENDER).unwrap();
+
+        let signed = sign_safe_tx(&request("eip712"), &key_store()).unwrap();
+        assert_eq!(signed.safe_tx_hash, safe_tx_hash);
+        assert_eq!(signed.owner, sender);
+        let v = signed.signature.0[64];
+        assert!(v == 27 || v == 28);
+        let recovered = web3::signing::recover(
+            safe_tx_hash.as_bytes(),
+            &signed.signature.0[..64],
+            (v - 27) as i32,
+        )
+        .unwrap();
+        assert_eq!(recovered, sender);
+
+        // `eth_sign` signs the prefixed hash and marks it with v + 4
+        let signed = sign_safe_tx(&request("eth_sign"), &key_store()).unwrap();
+        assert_eq!(signed.safe_tx_hash, safe_tx_hash);
+        let v = signed.signature.0[64];
+        assert!(v == 31 || v == 32);
+        let recovered = web3::signing::recover(
+            web3::signing::hash_message(safe_tx_hash.as_bytes()).as_bytes(),
+            &signed.signature.0[..64],
+            (v - 31) as i32,
+        )
+        .unwrap();
+        assert_eq!(recovered, sender);
+
+        let mut legacy = request("eip712");
+        legacy.safe_version = Some("0.1.0".to_string());
+        assert!(sign_safe_tx(&legacy, &key_store()).is_err());
+    }
+
+    /// Decode what ends up on chain from a signed transaction.
+    fn signed_tx(signed: &SignRawTxFeild) -> DecodedTransaction {
+        decode_signed_tx(&DecodeTxRequest {
+            raw_tx: format!("0x{}", hex::encode(&signed.r_tx.0)),
+        })
+        .unwrap()
+        .decoded
+    }
+
+    // Example of the Solidity ABI specification
+    #[test]
+    fn signs_contract_call_with_dynamic_arguments() {
+        let path = std::env::temp_dir().join(format!("abi-registry-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"example": {"address": "0x3535353535353535353535353535353535353535", "abi": [
+                {"type":"function","name":"f","stateMutability":"nonpayable","inputs":[{"name":"a","type":"uint256"},{"name":"b","type":"uint32[]"},{"name":"c","type":"bytes10"},{"name":"d","type":"bytes"}],"outputs":[]}
+            ]}}"#,
+        )
+        .unwrap();
+        let registry = AbiRegistry::load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let registry = registry.unwrap();
+
+        let mut request = ContractCallRequest {
+            key_id: "default".to_string(),
+            contract: "example".to_string(),
+            function: "f".to_string(),
+            args: vec![
+                Value::from("0x123"),
+                serde_json::json!(["0x456", "0x789"]),
+                Value::from("0x31323334353637383930"),
+                Value::from("0x48656c6c6f2c20776f726c6421"),
+            ],
+            tx: TxRequest {
+                value: "0".to_string(),
+                gas: "100000".to_string(),
+                ..tx_request()
+            },
+        };
+        let decoded = signed_tx(&sign_contract_call(&request, &registry, &key_store()).unwrap());
+        assert_eq!(
+            hex::encode(&decoded.data.0),
+            "8be65246\
+             0000000000000000000000000000000000000000000000000000000000000123\
+             0000000000000000000000000000000000000000000000000000000000000080\
+             3132333435363738393000000000000000000000000000000000000000000000\
+             00000000000000000000000000000000000000000000000000000000000000e0\
+             0000000000000000000000000000000000000000000000000000000000000002\
+             0000000000000000000000000000000000000000000000000000000000000456\
+             0000000000000000000000000000000000000000000000000000000000000789\
+             000000000000000000000000000000000000000000000000000000000000000d\
+             48656c6c6f2c20776f726c642100000000000000000000000000000000000000"
+        );
+        assert_eq!(
+            decoded.to,
+            Some(Address::from_str("0x3535353535353535353535353535353535353535").unwrap())
+        );
 
-fn combined_sign_bytes(v: u64, r: H256, s: H256) -> [u8; 65] {
-    let mut combined = [0u8; 65];
-    combined[..32].copy_from_slice(&r.0);
-    combined[32..64].copy_from_slice(&s.0);
-    combined[64] = v as u8;
-    combined
+        request.tx.to = EIP155_SENDER.to_string();
+        assert!(sign_contract_call(&request, &registry, &key_store()).is_err());
+    }
+
+    // Example of web3.js `accounts.sign`
+    const PERSONAL_KEY: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+    const PERSONAL_SIGNER: &str = "0x2c7536E3605D9C16a7a3D7b1898e529396a65c23";
+    const PERSONAL_SIGNATURE: &str = "0xb91467e570a6466aa9e9876cbcd013baba02900b8979d43fe208a4a4f339f5fd6007e74cd82e037b800186422fc2da167c747ef045e5d18a5f5d4300f8e1a0291c";
+
+    fn personal_key_store() -> InMemoryKeyStore {
+        let mut store = InMemoryKeyStore::new();
+        store.insert_hex("personal", PERSONAL_KEY).unwrap();
+        store
+    }
+
+    #[test]
+    fn signs_eip191_example() {
+        let signed = sign_personal_message(
+            &SignMessageRequest {
+                key_id: "personal".to_string(),
+                message: "Some data".to_string(),
+                encoding: MessageEncoding::Text,
+            },
+            &personal_key_store(),
+        )
+        .unwrap();
+        assert_eq!(
+            signed.message_hash,
+            H256::from_str("0x1da44b586eb0729ff70a73c326926f6ed5a25f5b056e7f47fbc6e58d86871655")
+                .unwrap()
+        );
+        assert_eq!(
+            format!("0x{}", hex::encode(&signed.signature.0)),
+            PERSONAL_SIGNATURE
+        );
+        assert_eq!(signed.address, Address::from_str(PERSONAL_SIGNER).unwrap());
+    }
+
+    #[test]
+    fn recovers_personal_message_signer() {
+        let store = personal_key_store();
+        let signer = Address::from_str(PERSONAL_SIGNER).unwrap();
+        for message in ["Some data", "", "\u{1f980} unicode"] {
+            let signed = sign_personal_message(
+                &SignMessageRequest {
+                    key_id: "personal".to_string(),
+                    message: message.to_string(),
+                    encoding: MessageEncoding::Text,
+                },
+                &store,
+            )
+            .unwrap();
+            let recovered = recover_message_signer(&RecoverRequest {
+                message: message.to_string(),
+                encoding: MessageEncoding::Text,
+                signature: format!("0x{}", hex::encode(&signed.signature.0)),
+            })
+            .unwrap();
+            assert_eq!(recovered, signer);
+        }
+
+        let truncated = RecoverRequest {
+            message: "Some data".to_string(),
+            encoding: MessageEncoding::Text,
+            signature: PERSONAL_SIGNATURE[..130].to_string(),
+        };
+        assert!(recover_message_signer(&truncated).is_err());
+        let tampered = RecoverRequest {
+            message: "Some datA".to_string(),
+            encoding: MessageEncoding::Text,
+            signature: PERSONAL_SIGNATURE.to_string(),
+        };
+        assert_ne!(recover_message_signer(&tampered).unwrap(), signer);
+    }
+
+    #[test]
+    fn decodes_hex_messages_before_signing() {
+        let store = personal_key_store();
+        let sign = |message: &str, encoding| {
+            sign_personal_message(
+                &SignMessageRequest {
+                    key_id: "personal".to_string(),
+                    message: message.to_string(),
+                    encoding,
+                },
+                &store,
+            )
+        };
+        // "Some data" as hex signs the same bytes as the text
+        let hex = sign("0x536f6d652064617461", MessageEncoding::Hex).unwrap();
+        assert_eq!(
+            format!("0x{}", hex::encode(&hex.signature.0)),
+            PERSONAL_SIGNATURE
+        );
+        let text = sign("0x536f6d652064617461", MessageEncoding::Text).unwrap();
+        assert_ne!(text.message_hash, hex.message_hash);
+        assert!(sign("0xzz", MessageEncoding::Hex).is_err());
+
+        let recovered = recover_message_signer(&RecoverRequest {
+            message: "536f6d652064617461".to_string(),
+            encoding: MessageEncoding::Hex,
+            signature: PERSONAL_SIGNATURE.to_string(),
+        })
+        .unwrap();
+        assert_eq!(recovered, Address::from_str(PERSONAL_SIGNER).unwrap());
+    }
+
+    /// Calldata of the signed NFT transfer of `token_ids` to 0x3535..35.
+    fn nft_calldata(
+        standard: NftStandard,
+        token_ids: &[&str],
+        amounts: &[&str],
+        data: Option<&str>,
+    ) -> Result<String, Error> {
+        let request = NftTransferRequest {
+            key_id: "default".to_string(),
+            standard,
+            token_address: "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf".to_string(),
+            from: None,
+            recipient: "0x3535353535353535353535353535353535353535".to_string(),
+            token_ids: token_ids.iter().map(|id| id.to_string()).collect(),
+            amounts: amounts.iter().map(|amount| amount.to_string()).collect(),
+            data: data.map(str::to_string),
+            tx: TxRequest {
+                value: "0".to_string(),
+                gas: "100000".to_string(),
+                ..tx_request()
+            },
+        };
+        let decoded = signed_tx(&sign_nft_transfer(&request, &key_store())?);
+        assert_eq!(
+            decoded.to,
+            Some(Address::from_str("0x7e5f4552091a69125d5dfcb7b8c2659029395bdf").unwrap())
+        );
+        Ok(hex::encode(&decoded.data.0))
+    }
+
+    #[test]
+    fn encodes_erc721_safe_transfers() {
+        assert_eq!(
+            nft_calldata(NftStandard::Erc721, &["42"], &[], None).unwrap(),
+            "42842e0e\
+             0000000000000000000000009d8a62f656a8d1615c1294fd71e9cfb3e4855a4f\
+             0000000000000000000000003535353535353535353535353535353535353535\
+             000000000000000000000000000000000000000000000000000000000000002a"
+        );
+        assert_eq!(
+            nft_calldata(NftStandard::Erc721, &["42"], &[], Some("0xdead")).unwrap(),
+            "b88d4fde\
+             0000000000000000000000009d8a62f656a8d1615c1294fd71e9cfb3e4855a4f\
+             0000000000000000000000003535353535353535353535353535353535353535\
+             000000000000000000000000000000000000000000000000000000000000002a\
+             0000000000000000000000000000000000000000000000000000000000000080\
+             0000000000000000000000000000000000000000000000000000000000000002\
+             dead000000000000000000000000000000000000000000000000000000000000"
+        );
+        assert!(nft_calldata(NftStandard::Erc721, &["1", "2"], &[], None).is_err());
+        assert!(nft_calldata(NftStandard::Erc721, &["1"], &["1"], None).is_err());
+    }
+
+    #[test]
+    fn encodes_erc1155_safe_transfers() {
+        assert_eq!(
+            nft_calldata(NftStandard::Erc1155, &["1"], &["10"], None).unwrap(),
+            "f242432a\
+             0000000000000000000000009d8a62f656a8d1615c1294fd71e9cfb3e4855a4f\
+             0000000000000000000000003535353535353535353535353535353535353535\
+             0000000000000000000000000000000000000000000000000000000000000001\
+             000000000000000000000000000000000000000000000000000000000000000a\
+             00000000000000000000000000000000000000000000000000000000000000a0\
+             0000000000000000000000000000000000000000000000000000000000000000"
+        );
+        assert_eq!(
+            nft_calldata(
+                NftStandard::Erc1155,
+                &["1", "2"],
+                &["10", "20"],
+                Some("0x01")
+            )
+            .unwrap(),
+            "2eb2c2d6\
+             0000000000000000000000009d8a62f656a8d1615c1294fd71e9cfb3e4855a4f\
+             0000000000000000000000003535353535353535353535353535353535353535\
+             00000000000000000000000000000000000000000000000000000000000000a0\
+             0000000000000000000000000000000000000000000000000000000000000100\
+             0000000000000000000000000000000000000000000000000000000000000160\
+             0000000000000000000000000000000000000000000000000000000000000002\
+             0000000000000000000000000000000000000000000000000000000000000001\
+             0000000000000000000000000000000000000000000000000000000000000002\
+             0000000000000000000000000000000000000000000000000000000000000002\
+             000000000000000000000000000000000000000000000000000000000000000a\
+             0000000000000000000000000000000000000000000000000000000000000014\
+             0000000000000000000000000000000000000000000000000000000000000001\
+             0100000000000000000000000000000000000000000000000000000000000000"
+        );
+    }
+
+    #[test]
+    fn refuses_mismatched_erc1155_amounts() {
+        assert!(nft_calldata(NftStandard::Erc1155, &["1", "2"], &["10"], None).is_err());
+        assert!(nft_calldata(NftStandard::Erc1155, &["1"], &["10", "20"], None).is_err());
+        assert!(nft_calldata(NftStandard::Erc1155, &["1"], &[], None).is_err());
+        assert!(nft_calldata(NftStandard::Erc1155, &[], &[], None).is_err());
+    }
 }
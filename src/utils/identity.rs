@@ -0,0 +1,227 @@
+use anyhow::Error;
+use p256::ecdsa::{signature::Signer, Signature, SigningKey, VerifyingKey};
+use rand_core::OsRng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{io::Write, path::Path};
+use zeroize::Zeroizing;
+
+/// Prefix of the signed key exchange transcript.
+pub const EXCHANGE_CONTEXT: &[u8] = b"hsm-jaamlong/key-exchange/v1";
+
+/// Long-term P-256 key the HSM signs its ephemeral ECDH keys with, so clients
+/// that pinned its public key can detect a substituted exchange.
+pub struct IdentityKey {
+    signer: IdentitySigner,
+    verifying_key: VerifyingKey,
+}
+
+enum IdentitySigner {
+    /// Key read from `IDENTITY_KEY_PATH`.
+    Local(SigningKey),
+    /// P-256 key `label` inside the PKCS#11 token.
+    #[cfg(feature = "pkcs11")]
+    Pkcs11 {
+        store: crate::utils::pkcs11::Pkcs11KeyStore,
+        label: String,
+    },
+}
+
+/// Ephemeral key of a key exchange, bound to the client key and time by the
+/// identity signature.
+#[derive(Debug, Serialize)]
+pub struct SignedExchange {
+    /// SEC1 uncompressed ephemeral public key.
+    pub public_key: Vec<u8>,
+    pub timestamp: u64,
+    /// `r ‖ s` over [`exchange_transcript`].
+    pub signature: Vec<u8>,
+    pub identity_key: Vec<u8>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IdentityInfo {
+    /// SEC1 uncompressed identity public key.
+    pub public_key: Vec<u8>,
+    /// SHA-256 of `public_key`, for comparing out of band.
+    pub fingerprint: String,
+}
+
+impl IdentityKey {
+    /// Load the hex private key at `path`, creating it on first start. The key
+    /// is kept in plaintext, outside the Shamir seal.
+    pub fn load(path: &str) -> Result<Self, Error> {
+        if Path::new(path).exists() {
+            let content = Zeroizing::new(std::fs::read_to_string(path)?);
+            let bytes = Zeroizing::new(
+                hex::decode(content.trim())
+                    .map_err(|err| Error::msg(format!("Error parsing identity key: {}", err)))?,
+            );
+            let signing_key = SigningKey::from_slice(&bytes)
+                .map_err(|err| Error::msg(format!("Invalid identity key: {}", err)))?;
+            return Ok(Self::local(signing_key));
+        }
+        let signing_key = SigningKey::random(&mut OsRng);
+        let content = Zeroizing::new(hex::encode(signing_key.to_bytes()));
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(path)?.write_all(content.as_bytes())?;
+        println!("Created identity key {}", path);
+        Ok(Self::local(signing_key))
+    }
+
+    fn local(signing_key: SigningKey) -> Self {
+        IdentityKey {
+            verifying_key: VerifyingKey::from(&signing_key),
+            signer: IdentitySigner::Local(signing_key),
+        }
+    }
+
+    /// Fresh key that is never written anywhere.
+    #[cfg(test)]
+    pub fn random() -> Self {
+        Self::local(SigningKey::random(&mut OsRng))
+    }
+
+    /// Use the P-256 key `label` of the PKCS#11 token configured by the
+    /// `PKCS11_*` variables; the key never leaves the token.
+    #[cfg(feature = "pkcs11")]
+    pub fn from_pkcs11(label: &str) -> Result<Self, Error> {
+        use crate::utils::{
+            key_store::KeyStore,
+            pkcs11::{Curve, Pkcs11KeyStore},
+        };
+        let store = Pkcs11KeyStore::from_env_with_labels(&[label.to_string()])?;
+        if store.curve(label)? != Curve::P256 {
+            return Err(Error::msg(format!(
+                "Identity key {} is not a P-256 key",
+                label
+            )));
+        }
+        let verifying_key = VerifyingKey::from_sec1_bytes(&store.public_key(label)?)
+            .map_err(|err| Error::msg(format!("Invalid identity key: {}", err)))?;
+        Ok(IdentityKey {
+            signer: IdentitySigner::Pkcs11 {
+                store,
+                label: label.to_string(),
+            },
+            verifying_key,
+        })
+    }
+
+    #[cfg(not(feature = "pkcs11"))]
+    pub fn from_pkcs11(_label: &str) -> Result<Self, Error> {
+        Err(Error::msg(
+            "IDENTITY_KEY_LABEL needs a build with the pkcs11 feature",
+        ))
+    }
+
+    /// Use the token key `IDENTITY_KEY_LABEL` when set, otherwise load
+    /// `IDENTITY_KEY_PATH`, `identity_key.hex` when unset.
+    pub fn from_env() -> Result<Self, Error> {
+        if let Some(label) = dotenvy::var("IDENTITY_KEY_LABEL")
+            .ok()
+            .filter(|label| !label.is_empty())
+        {
+            return Self::from_pkcs11(&label);
+        }
+        let path = dotenvy::var("IDENTITY_KEY_PATH").unwrap_or("identity_key.hex".to_string());
+        Self::load(&path)
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.verifying_key
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec()
+    }
+
+    pub fn info(&self) -> IdentityInfo {
+        let public_key = self.public_key();
+        IdentityInfo {
+            fingerprint: hex::encode(Sha256::digest(&public_key)),
+            public_key,
+        }
+    }
+
+    /// Sign the HSM ephemeral key of an exchange with `client_key` at `timestamp`.
+    pub fn sign_exchange(
+        &self,
+        public_key: &[u8],
+        client_key: &[u8],
+        timestamp: u64,
+    ) -> Result<SignedExchange, Error> {
+        let transcript = exchange_transcript(public_key, client_key, timestamp);
+        let signature: Signature = match &self.signer {
+            IdentitySigner::Local(signing_key) => signing_key.sign(&transcript),
+            #[cfg(feature = "pkcs11")]
+            IdentitySigner::Pkcs11 { store, label } => {
+                store.sign_p256(label, &Sha256::digest(&transcript))?
+            }
+        };
+        Ok(SignedExchange {
+            public_key: public_key.to_vec(),
+            timestamp,
+            signature: signature.to_vec(),
+            identity_key: self.public_key(),
+        })
+    }
+}
+
+/// `context ‖ len ‖ hsm key ‖ len ‖ client key ‖ timestamp`, lengths and
+/// timestamp as big-endian `u32`/`u64`.
+pub fn exchange_transcript(public_key: &[u8], client_key: &[u8], timestamp: u64) -> Vec<u8> {
+    let mut transcript = EXCHANGE_CONTEXT.to_vec();
+    for key in [public_key, client_key] {
+        transcript.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        transcript.extend_from_slice(key);
+    }
+    transcript.extend_from_slice(&timestamp.to_be_bytes());
+    transcript
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::signature::Verifier;
+
+    #[test]
+    fn exchange_signature_verifies_with_identity_key() {
+        let path = std::env::temp_dir().join(format!("identity-{}.hex", std::process::id()));
+        let path = path.to_str().unwrap();
+        let identity = IdentityKey::load(path).unwrap();
+        let reloaded = IdentityKey::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(reloaded.public_key(), identity.public_key());
+
+        let public_key = VerifyingKey::from(&SigningKey::random(&mut OsRng))
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec();
+        let client_key = VerifyingKey::from(&SigningKey::random(&mut OsRng))
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec();
+        let signed = identity
+            .sign_exchange(&public_key, &client_key, 1_700_000_000)
+            .unwrap();
+        let info = identity.info();
+        assert_eq!(signed.identity_key, info.public_key);
+        assert_eq!(
+            info.fingerprint,
+            hex::encode(Sha256::digest(&info.public_key))
+        );
+
+        let verifying_key = VerifyingKey::from_sec1_bytes(&info.public_key).unwrap();
+        let signature = Signature::from_slice(&signed.signature).unwrap();
+        let transcript = exchange_transcript(&public_key, &client_key, signed.timestamp);
+        assert!(verifying_key.verify(&transcript, &signature).is_ok());
+        // The signature binds the client key and the timestamp
+        let other = exchange_transcript(&public_key, &public_key, signed.timestamp);
+        assert!(verifying_key.verify(&other, &signature).is_err());
+        let later = exchange_transcript(&public_key, &client_key, signed.timestamp + 1);
+        assert!(verifying_key.verify(&later, &signature).is_err());
+    }
+}
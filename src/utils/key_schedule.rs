@@ -0,0 +1,162 @@
+use anyhow::Error;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const CLIENT_TO_SERVER_LABEL: &[u8] = b"hsm-jaamlong c2s key";
+const SERVER_TO_CLIENT_LABEL: &[u8] = b"hsm-jaamlong s2c key";
+const CLIENT_CONFIRM_LABEL: &[u8] = b"hsm-jaamlong c2s confirm";
+const SERVER_CONFIRM_LABEL: &[u8] = b"hsm-jaamlong s2c confirm";
+
+/// Keys of one ECDH session. Requests are sealed under `client_to_server`,
+/// responses under `server_to_client`; both sides prove they derived the same
+/// keys by exchanging confirmation tags before any request is accepted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionKeys {
+    pub client_to_server: [u8; 32],
+    pub server_to_client: [u8; 32],
+    client_confirm_key: [u8; 32],
+    server_confirm_key: [u8; 32],
+    /// SHA-256 of the handshake transcript the keys are bound to.
+    pub transcript_hash: [u8; 32],
+    /// Set once the client's confirmation tag checked out.
+    #[serde(default)]
+    pub confirmed: bool,
+}
+
+impl SessionKeys {
+    /// HKDF-SHA256 of the ECDH `shared_secret`, salted with the transcript hash
+    /// and expanded once per key label.
+    pub fn derive(shared_secret: &[u8], transcript: &[u8]) -> Result<Self, Error> {
+        let transcript_hash: [u8; 32] = Sha256::digest(transcript).into();
+        let hkdf = Hkdf::<Sha256>::new(Some(&transcript_hash), shared_secret);
+        let expand = |label: &[u8]| -> Result<[u8; 32], Error> {
+            let mut okm = [0u8; 32];
+            hkdf.expand(label, &mut okm)
+                .map_err(|err| Error::msg(format!("Error expanding session key: {}", err)))?;
+            Ok(okm)
+        };
+        Ok(SessionKeys {
+            client_to_server: expand(CLIENT_TO_SERVER_LABEL)?,
+            server_to_client: expand(SERVER_TO_CLIENT_LABEL)?,
+            client_confirm_key: expand(CLIENT_CONFIRM_LABEL)?,
+            server_confirm_key: expand(SERVER_CONFIRM_LABEL)?,
+            transcript_hash,
+            confirmed: false,
+        })
+    }
+
+    /// `HMAC-SHA256(server confirm key, transcript hash)`, sent with the HSM key.
+    pub fn server_confirmation(&self) -> Vec<u8> {
+        confirmation_mac(&self.server_confirm_key, &self.transcript_hash)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+
+    /// Check the client's `HMAC-SHA256(client confirm key, transcript hash)`.
+    pub fn verify_client_confirmation(&self, tag: &[u8]) -> Result<(), Error> {
+        confirmation_mac(&self.client_confirm_key, &self.transcript_hash)
+            .verify_slice(tag)
+            .map_err(|_| Error::msg("Key confirmation failed"))
+    }
+}
+
+fn confirmation_mac(key: &[u8; 32], transcript_hash: &[u8; 32]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(transcript_hash);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::{ecdh::EphemeralSecret, PublicKey};
+    use rand_core::OsRng;
+
+    const TRANSCRIPT: &[u8] = b"hsm key | client key | timestamp";
+
+    /// Keys each side of one exchange derives.
+    fn exchange(transcript: &[u8]) -> (SessionKeys, SessionKeys) {
+        let hsm_secret = EphemeralSecret::random(&mut OsRng);
+        let client_secret = EphemeralSecret::random(&mut OsRng);
+        let hsm_shared = hsm_secret.diffie_hellman(&PublicKey::from(&client_secret));
+        let client_shared = client_secret.diffie_hellman(&PublicKey::from(&hsm_secret));
+        (
+            SessionKeys::derive(hsm_shared.raw_secret_bytes(), transcript).unwrap(),
+            SessionKeys::derive(client_shared.raw_secret_bytes(), transcript).unwrap(),
+        )
+    }
+
+    fn client_confirmation(keys: &SessionKeys) -> Vec<u8> {
+        confirmation_mac(&keys.client_confirm_key, &keys.transcript_hash)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+
+    fn all_keys(keys: &SessionKeys) -> [[u8; 32]; 4] {
+        [
+            keys.client_to_server,
+            keys.server_to_client,
+            keys.client_confirm_key,
+            keys.server_confirm_key,
+        ]
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_keys() {
+        let (hsm, client) = exchange(TRANSCRIPT);
+        assert_eq!(all_keys(&hsm), all_keys(&client));
+        assert_eq!(hsm.transcript_hash, client.transcript_hash);
+        assert_eq!(hsm.server_confirmation(), client.server_confirmation());
+        assert!(hsm
+            .verify_client_confirmation(&client_confirmation(&client))
+            .is_ok());
+    }
+
+    #[test]
+    fn keys_differ_by_direction() {
+        let (hsm, _) = exchange(TRANSCRIPT);
+        let keys = all_keys(&hsm);
+        for (i, key) in keys.iter().enumerate() {
+            assert!(keys[i + 1..].iter().all(|other| other != key));
+        }
+        assert_ne!(client_confirmation(&hsm), hsm.server_confirmation());
+    }
+
+    #[test]
+    fn rejects_bad_confirmation_tag() {
+        let (hsm, client) = exchange(TRANSCRIPT);
+        let mut tag = client_confirmation(&client);
+        tag[0] ^= 1;
+        assert!(hsm.verify_client_confirmation(&tag).is_err());
+        // The server's own tag is not a client confirmation
+        assert!(hsm
+            .verify_client_confirmation(&client.server_confirmation())
+            .is_err());
+        assert!(hsm.verify_client_confirmation(&[]).is_err());
+        // Nor is a tag from another exchange
+        let (_, other) = exchange(TRANSCRIPT);
+        assert!(hsm
+            .verify_client_confirmation(&client_confirmation(&other))
+            .is_err());
+    }
+
+    #[test]
+    fn transcript_changes_every_key() {
+        let shared_secret = [7u8; 32];
+        let keys = SessionKeys::derive(&shared_secret, TRANSCRIPT).unwrap();
+        let mut transcript = TRANSCRIPT.to_vec();
+        transcript[0] ^= 1;
+        let changed = SessionKeys::derive(&shared_secret, &transcript).unwrap();
+        assert_ne!(keys.transcript_hash, changed.transcript_hash);
+        for (key, changed) in all_keys(&keys).iter().zip(all_keys(&changed)) {
+            assert_ne!(*key, changed);
+        }
+        assert!(changed
+            .verify_client_confirmation(&client_confirmation(&keys))
+            .is_err());
+    }
+}
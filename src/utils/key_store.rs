@@ -0,0 +1,389 @@
+use crate::utils::hd_wallet::HdKeyStore;
+use crate::utils::secret_storage::{load_keystore_dir, read_passphrase};
+use anyhow::Error;
+use secp256k1::{All, Message, PublicKey, Secp256k1, SecretKey};
+use serde::Deserialize;
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+use web3::{
+    signing::{self, Signature},
+    types::{Address, H256},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+pub const DEFAULT_KEY_ID: &str = "default";
+
+/// Source of the secp256k1 keys the HSM signs with.
+///
+/// Stores are built once at startup and shared through the router state, so
+/// implementations must never expose the secret key itself.
+pub trait KeyStore: Send + Sync {
+    /// Ids of every key held by the store.
+    fn key_ids(&self) -> Vec<String>;
+
+    /// Ethereum address of the key registered under `key_id`.
+    fn address(&self, key_id: &str) -> Result<Address, Error>;
+
+    /// Uncompressed SEC1 public key of the key registered under `key_id`.
+    fn public_key(&self, key_id: &str) -> Result<Vec<u8>, Error>;
+
+    /// Sign a 32-byte digest. The returned `v` is the raw recovery id (0 or 1).
+    fn sign_digest(&self, key_id: &str, digest: &[u8]) -> Result<Signature, Error>;
+
+    /// Pin `key_id` to the version a signature would be produced with, returning
+    /// the pinned id and the version. Unversioned stores only have version 1.
+    fn pin_version(&self, key_id: &str) -> Result<(String, u32), Error> {
+        self.address(key_id)?;
+        Ok((key_id.to_string(), 1))
+    }
+}
+
+/// Keys held in process memory, keyed by id.
+pub struct InMemoryKeyStore {
+    keys: HashMap<String, SecretKey>,
+    secp: Secp256k1<All>,
+}
+
+impl InMemoryKeyStore {
+    pub fn new() -> Self {
+        InMemoryKeyStore {
+            keys: HashMap::new(),
+            secp: Secp256k1::new(),
+        }
+    }
+
+    /// Load the single `PRIVATE_KEY` from the environment under `KEY_ID`
+    /// (or `default`).
+    pub fn from_env() -> Result<Self, Error> {
+        let private_key =
+            dotenvy::var("PRIVATE_KEY").map_err(|_| Error::msg("Private key must be set"))?;
+        let key_id = dotenvy::var("KEY_ID").unwrap_or_else(|_| DEFAULT_KEY_ID.to_string());
+        let mut store = Self::new();
+        store.insert_hex(&key_id, &private_key)?;
+        Ok(store)
+    }
+
+    /// Parse a JSON object mapping key ids to hex private keys.
+    pub fn from_json(content: &str) -> Result<Self, Error> {
+        let key_file: KeyFile = serde_json::from_str(content)?;
+        let mut store = Self::new();
+        for (key_id, private_key) in key_file.0.iter() {
+            store.insert_hex(key_id, private_key)?;
+        }
+        Ok(store)
+    }
+
+    pub fn insert(&mut self, key_id: &str, key: SecretKey) {
+        self.keys.insert(key_id.to_string(), key);
+    }
+
+    pub fn insert_hex(&mut self, key_id: &str, private_key: &str) -> Result<(), Error> {
+        let key = SecretKey::from_str(private_key.trim_start_matches("0x"))
+            .map_err(|err| Error::msg(format!("Error parsing key {}: {}", key_id, err)))?;
+        self.insert(key_id, key);
+        Ok(())
+    }
+
+    fn key(&self, key_id: &str) -> Result<&SecretKey, Error> {
+        self.keys
+            .get(key_id)
+            .ok_or_else(|| Error::msg(format!("Key not found: {}", key_id)))
+    }
+}
+
+impl Drop for InMemoryKeyStore {
+    fn drop(&mut self) {
+        for key in self.keys.values_mut() {
+            key.non_secure_erase();
+        }
+    }
+}
+
+impl Default for InMemoryKeyStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyStore for InMemoryKeyStore {
+    fn key_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.keys.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    fn address(&self, key_id: &str) -> Result<Address, Error> {
+        let public_key = self.public_key(key_id)?;
+        Ok(public_key_address(&public_key))
+    }
+
+    fn public_key(&self, key_id: &str) -> Result<Vec<u8>, Error> {
+        let key = self.key(key_id)?;
+        let public_key = PublicKey::from_secret_key(&self.secp, key);
+        Ok(public_key.serialize_uncompressed().to_vec())
+    }
+
+    fn sign_digest(&self, key_id: &str, digest: &[u8]) -> Result<Signature, Error> {
+        let key = self.key(key_id)?;
+        sign_with_secret(&self.secp, key, digest)
+    }
+}
+
+/// Keys loaded from a JSON file mapping key ids to hex private keys.
+pub struct FileKeyStore {
+    inner: InMemoryKeyStore,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyFile(HashMap<String, String>);
+
+impl Drop for KeyFile {
+    fn drop(&mut self) {
+        for private_key in self.0.values_mut() {
+            private_key.zeroize();
+        }
+    }
+}
+
+impl FileKeyStore {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let content = Zeroizing::new(
+            std::fs::read_to_string(path)
+                .map_err(|err| Error::msg(format!("Error reading key file {}: {}", path, err)))?,
+        );
+        let inner = InMemoryKeyStore::from_json(&content)
+            .map_err(|err| Error::msg(format!("Error loading key file {}: {}", path, err)))?;
+        Ok(FileKeyStore { inner })
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn key_ids(&self) -> Vec<String> {
+        self.inner.key_ids()
+    }
+
+    fn address(&self, key_id: &str) -> Result<Address, Error> {
+        self.inner.address(key_id)
+    }
+
+    fn public_key(&self, key_id: &str) -> Result<Vec<u8>, Error> {
+        self.inner.public_key(key_id)
+    }
+
+    fn sign_digest(&self, key_id: &str, digest: &[u8]) -> Result<Signature, Error> {
+        self.inner.sign_digest(key_id, digest)
+    }
+}
+
+/// Deterministic keys for local testing: every key id maps to the secret
+/// `keccak256(key_id)`. Never use outside of development.
+pub struct TestKeyStore {
+    key_ids: Vec<String>,
+    secp: Secp256k1<All>,
+}
+
+impl TestKeyStore {
+    pub fn new(key_ids: &[&str]) -> Self {
+        TestKeyStore {
+            key_ids: key_ids.iter().map(|id| id.to_string()).collect(),
+            secp: Secp256k1::new(),
+        }
+    }
+
+    fn key(&self, key_id: &str) -> Result<SecretKey, Error> {
+        if !self.key_ids.iter().any(|id| id == key_id) {
+            return Err(Error::msg(format!("Key not found: {}", key_id)));
+        }
+        SecretKey::from_slice(&signing::keccak256(key_id.as_bytes()))
+            .map_err(|err| Error::msg(format!("Error deriving test key: {}", err)))
+    }
+}
+
+impl KeyStore for TestKeyStore {
+    fn key_ids(&self) -> Vec<String> {
+        self.key_ids.clone()
+    }
+
+    fn address(&self, key_id: &str) -> Result<Address, Error> {
+        let public_key = self.public_key(key_id)?;
+        Ok(public_key_address(&public_key))
+    }
+
+    fn public_key(&self, key_id: &str) -> Result<Vec<u8>, Error> {
+        let key = self.key(key_id)?;
+        let public_key = PublicKey::from_secret_key(&self.secp, &key);
+        Ok(public_key.serialize_uncompressed().to_vec())
+    }
+
+    fn sign_digest(&self, key_id: &str, digest: &[u8]) -> Result<Signature, Error> {
+        let key = self.key(key_id)?;
+        sign_with_secret(&self.secp, &key, digest)
+    }
+}
+
+/// Build the key store selected by `KEY_STORE` (`memory`, `file`, `keystore`,
+/// `mnemonic`, `test`, or `pkcs11` with the `pkcs11` feature).
+pub fn key_store_from_env() -> Result<Arc<dyn KeyStore>, Error> {
+    let kind = dotenvy::var("KEY_STORE").unwrap_or_else(|_| "memory".to_string());
+    let store: Arc<dyn KeyStore> = match kind.as_str() {
+        "memory" => Arc::new(InMemoryKeyStore::from_env()?),
+        "file" => {
+            let path = dotenvy::var("KEY_STORE_PATH")
+                .map_err(|_| Error::msg("Key store path not found"))?;
+            Arc::new(FileKeyStore::load(&path)?)
+        }
+        "keystore" => {
+            let dir = dotenvy::var("KEYSTORE_DIR")
+                .map_err(|_| Error::msg("Keystore directory not found"))?;
+            let passphrase = read_passphrase()?;
+            Arc::new(load_keystore_dir(&dir, &passphrase)?)
+        }
+        "mnemonic" => Arc::new(HdKeyStore::from_env()?),
+        #[cfg(feature = "pkcs11")]
+        "pkcs11" => Arc::new(crate::utils::pkcs11::Pkcs11KeyStore::from_env()?),
+        "test" => Arc::new(TestKeyStore::new(&[DEFAULT_KEY_ID])),
+        other => return Err(Error::msg(format!("Unknown key store: {}", other))),
+    };
+    println!("Loaded key store with keys: {:?}", store.key_ids());
+    Ok(store)
+}
+
+pub fn sign_with_secret(
+    secp: &Secp256k1<All>,
+    key: &SecretKey,
+    digest: &[u8],
+) -> Result<Signature, Error> {
+    let message = Message::from_digest_slice(digest)
+        .map_err(|err| Error::msg(format!("Invalid digest: {}", err)))?;
+    let (recovery_id, signature) = secp
+        .sign_ecdsa_recoverable(&message, key)
+        .serialize_compact();
+    Ok(Signature {
+        v: recovery_id.to_i32() as u64,
+        r: H256::from_slice(&signature[..32]),
+        s: H256::from_slice(&signature[32..]),
+    })
+}
+
+/// Address of an uncompressed SEC1 public key.
+pub fn public_key_address(public_key: &[u8]) -> Address {
+    let hash = signing::keccak256(&public_key[1..]);
+    Address::from_slice(&hash[12..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // EIP-155 example key and its address
+    const PRIVATE_KEY: &str = "0x4646464646464646464646464646464646464646464646464646464646464646";
+    const ADDRESS: &str = "0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f";
+
+    fn recover(key_store: &dyn KeyStore, key_id: &str, digest: &[u8; 32]) -> Address {
+        let signature = key_store.sign_digest(key_id, digest).unwrap();
+        let mut rs = signature.r.as_bytes().to_vec();
+        rs.extend_from_slice(signature.s.as_bytes());
+        signing::recover(digest, &rs, signature.v as i32).unwrap()
+    }
+
+    #[test]
+    fn derives_address_from_private_key() {
+        let mut store = InMemoryKeyStore::new();
+        store.insert_hex(DEFAULT_KEY_ID, PRIVATE_KEY).unwrap();
+        store
+            .insert_hex(
+                "one",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+            )
+            .unwrap();
+        assert_eq!(
+            store.address(DEFAULT_KEY_ID).unwrap(),
+            Address::from_str(ADDRESS).unwrap()
+        );
+        assert_eq!(
+            store.address("one").unwrap(),
+            Address::from_str("0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf").unwrap()
+        );
+        let public_key = store.public_key(DEFAULT_KEY_ID).unwrap();
+        assert_eq!(public_key.len(), 65);
+        assert_eq!(public_key[0], 0x04);
+        assert_eq!(store.key_ids(), vec!["default", "one"]);
+    }
+
+    #[test]
+    fn signatures_recover_to_key_address() {
+        let mut store = InMemoryKeyStore::new();
+        store.insert_hex(DEFAULT_KEY_ID, PRIVATE_KEY).unwrap();
+        let digest = signing::keccak256(b"hsm-jaamlong");
+        assert_eq!(
+            recover(&store, DEFAULT_KEY_ID, &digest),
+            Address::from_str(ADDRESS).unwrap()
+        );
+        assert!(store.sign_digest(DEFAULT_KEY_ID, &digest[..31]).is_err());
+
+        let test_store = TestKeyStore::new(&[DEFAULT_KEY_ID]);
+        assert_eq!(
+            recover(&test_store, DEFAULT_KEY_ID, &digest),
+            test_store.address(DEFAULT_KEY_ID).unwrap()
+        );
+        // Test keys are keccak256 of their id
+        let mut derived = InMemoryKeyStore::new();
+        derived
+            .insert_hex(
+                DEFAULT_KEY_ID,
+                &hex::encode(signing::keccak256(DEFAULT_KEY_ID.as_bytes())),
+            )
+            .unwrap();
+        assert_eq!(
+            test_store.address(DEFAULT_KEY_ID).unwrap(),
+            derived.address(DEFAULT_KEY_ID).unwrap()
+        );
+    }
+
+    #[test]
+    fn loads_key_file() {
+        let path = std::env::temp_dir().join(format!("key-store-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let content = serde_json::json!({
+            "default": PRIVATE_KEY,
+            "one": "0000000000000000000000000000000000000000000000000000000000000001",
+        });
+        std::fs::write(path, serde_json::to_vec(&content).unwrap()).unwrap();
+        let store = FileKeyStore::load(path);
+        std::fs::write(path, r#"{"default": "0x1234"}"#).unwrap();
+        let invalid = FileKeyStore::load(path);
+        std::fs::remove_file(path).unwrap();
+
+        let store = store.unwrap();
+        assert_eq!(store.key_ids(), vec!["default", "one"]);
+        assert_eq!(
+            store.address(DEFAULT_KEY_ID).unwrap(),
+            Address::from_str(ADDRESS).unwrap()
+        );
+        let digest = signing::keccak256(b"hsm-jaamlong");
+        assert_eq!(
+            recover(&store, "one", &digest),
+            store.address("one").unwrap()
+        );
+        assert!(invalid.is_err());
+        assert!(FileKeyStore::load(path).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_key_ids() {
+        let mut store = InMemoryKeyStore::new();
+        store.insert_hex(DEFAULT_KEY_ID, PRIVATE_KEY).unwrap();
+        let test_store = TestKeyStore::new(&[DEFAULT_KEY_ID]);
+        let digest = [1u8; 32];
+        for key_store in [&store as &dyn KeyStore, &test_store] {
+            assert!(key_store.address("unknown").is_err());
+            assert!(key_store.public_key("unknown").is_err());
+            assert!(key_store.sign_digest("unknown", &digest).is_err());
+            assert!(key_store.pin_version("unknown").is_err());
+            assert_eq!(
+                key_store.pin_version(DEFAULT_KEY_ID).unwrap(),
+                (DEFAULT_KEY_ID.to_string(), 1)
+            );
+        }
+        assert!(store.insert_hex("bad", "0x00").is_err());
+    }
+}
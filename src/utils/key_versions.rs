@@ -0,0 +1,465 @@
+use crate::utils::key_store::KeyStore;
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use web3::{signing::Signature, types::Address};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyState {
+    /// Signs every request that names the key without a version.
+    Active,
+    /// Kept for verification; only signs when pinned during its grace period.
+    VerifyOnly,
+    /// Refuses new signatures, listed for audit.
+    Retired,
+    /// Never used again, listed for audit. The operator removes the material.
+    Destroyed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyVersion {
+    pub version: u32,
+    pub state: KeyState,
+    /// Id of the key material in the underlying store.
+    pub key_ref: Option<String>,
+    pub created_at: u64,
+    pub grace_until: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyVersionInfo {
+    pub key_id: String,
+    pub version: u32,
+    pub state: KeyState,
+    pub address: Option<Address>,
+    pub created_at: u64,
+    pub grace_until: Option<u64>,
+}
+
+/// Versioned view over a [`KeyStore`]. A key id names a logical signer whose
+/// versions each point at key material in the underlying store; `name` signs
+/// with the active version and `name@N` pins version `N`. Ids that were never
+/// registered fall through to the underlying store as version 1.
+pub struct VersionedKeyStore {
+    inner: Arc<dyn KeyStore>,
+    versions: RwLock<HashMap<String, Vec<KeyVersion>>>,
+    path: Option<String>,
+}
+
+impl VersionedKeyStore {
+    /// Load the version registry from `path` and register every key of `inner`
+    /// it does not know yet as an active version 1.
+    pub fn load(inner: Arc<dyn KeyStore>, path: Option<String>) -> Result<Self, Error> {
+        let versions = match &path {
+            Some(path) if std::path::Path::new(path).exists() => {
+                let content = std::fs::read_to_string(path)?;
+                serde_json::from_str(&content).map_err(|err| {
+                    Error::msg(format!("Error parsing key versions {}: {}", path, err))
+                })?
+            }
+            _ => HashMap::new(),
+        };
+        let store = VersionedKeyStore {
+            inner,
+            versions: RwLock::new(versions),
+            path,
+        };
+        store.sync_keys()?;
+        Ok(store)
+    }
+
+    pub fn from_env(inner: Arc<dyn KeyStore>) -> Result<Self, Error> {
+        Self::load(inner, dotenvy::var("KEY_VERSIONS_PATH").ok())
+    }
+
+    /// Register keys of the underlying store that are neither a key nor the
+    /// material of a version yet, e.g. after the store was unsealed.
+    pub fn sync_keys(&self) -> Result<(), Error> {
+        let mut versions = self.versions.write().unwrap();
+        let mut updated = versions.clone();
+        let mut changed = false;
+        for key_id in self.inner.key_ids() {
+            let known = updated.contains_key(&key_id)
+                || updated
+                    .values()
+                    .flatten()
+                    .any(|version| version.key_ref.as_deref() == Some(key_id.as_str()));
+            if known {
+                continue;
+            }
+            let version = KeyVersion {
+                version: 1,
+                state: KeyState::Active,
+                key_ref: Some(key_id.clone()),
+                created_at: now(),
+                grace_until: None,
+            };
+            updated.insert(key_id, vec![version]);
+            changed = true;
+        }
+        if changed {
+            self.persist(&updated)?;
+            *versions = updated;
+        }
+        Ok(())
+    }
+
+    /// Every version of every registered key, including retired and destroyed ones.
+    pub fn list(&self) -> Vec<KeyVersionInfo> {
+        let versions = self.versions.read().unwrap();
+        let mut infos: Vec<KeyVersionInfo> = versions
+            .iter()
+            .flat_map(|(key_id, versions)| {
+                versions.iter().map(move |version| KeyVersionInfo {
+                    key_id: key_id.clone(),
+                    version: version.version,
+                    state: version.state,
+                    address: version
+                        .key_ref
+                        .as_ref()
+                        .and_then(|key_ref| self.inner.address(key_ref).ok()),
+                    created_at: version.created_at,
+                    grace_until: version.grace_until,
+                })
+            })
+            .collect();
+        infos.sort_by(|a, b| (&a.key_id, a.version).cmp(&(&b.key_id, b.version)));
+        infos
+    }
+
+    /// Add `key_ref` as the new active version of `key_id`. The previous active
+    /// version becomes verify-only and may still be pinned for `grace_period` seconds.
+    pub fn promote(&self, key_id: &str, key_ref: &str, grace_period: u64) -> Result<u32, Error> {
+        self.inner.address(key_ref)?;
+        let mut versions = self.versions.write().unwrap();
+        let mut updated = versions.clone();
+        let key_versions = updated.entry(key_id.to_string()).or_default();
+        if key_versions
+            .iter()
+            .any(|version| version.key_ref.as_deref() == Some(key_ref))
+        {
+            return Err(Error::msg(format!(
+                "Key material {} is already a version of {}",
+                key_ref, key_id
+            )));
+        }
+        let now = now();
+        for version in key_versions.iter_mut() {
+            if version.state == KeyState::Active {
+                version.state = KeyState::VerifyOnly;
+                version.grace_until = Some(now + grace_period);
+            }
+        }
+        let new_version = key_versions.iter().map(|v| v.version).max().unwrap_or(0) + 1;
+        key_versions.push(KeyVersion {
+            version: new_version,
+            state: KeyState::Active,
+            key_ref: Some(key_ref.to_string()),
+            created_at: now,
+            grace_until: None,
+        });
+        self.persist(&updated)?;
+        *versions = updated;
+        println!("Promoted {} to version {}", key_id, new_version);
+        Ok(new_version)
+    }
+
+    /// Move a version to `verify_only`, `retired` or `destroyed`. Versions only
+    /// become active through [`VersionedKeyStore::promote`].
+    pub fn set_state(&self, key_id: &str, version: u32, state: KeyState) -> Result<(), Error> {
+        if state == KeyState::Active {
+            return Err(Error::msg("Use promote to activate a key version"));
+        }
+        let mut versions = self.versions.write().unwrap();
+        let mut updated = versions.clone();
+        let key_version = updated
+            .get_mut(key_id)
+            .and_then(|versions| versions.iter_mut().find(|v| v.version == version))
+            .ok_or_else(|| Error::msg(format!("Key not found: {}@{}", key_id, version)))?;
+        if key_version.state == KeyState::Destroyed {
+            return Err(Error::msg(format!(
+                "Key {}@{} is destroyed",
+                key_id, version
+            )));
+        }
+        key_version.state = state;
+        key_version.grace_until = None;
+        self.persist(&updated)?;
+        *versions = updated;
+        println!("Key {}@{} is now {:?}", key_id, version, state);
+        Ok(())
+    }
+
+    /// Resolve `key_id` to the underlying key material and its version.
+    /// With `signing`, versions that may not produce new signatures are refused.
+    fn resolve(&self, key_id: &str, signing: bool) -> Result<(String, u32), Error> {
+        let (name, pinned) = split_version(key_id)?;
+        let versions = self.versions.read().unwrap();
+        let key_versions = match versions.get(name) {
+            Some(key_versions) => key_versions,
+            // material owned by a registered version is only reachable through it
+            None if versions
+                .values()
+                .flatten()
+                .any(|version| version.key_ref.as_deref() == Some(name)) =>
+            {
+                return Err(Error::msg(format!("Key not found: {}", key_id)))
+            }
+            None if pinned.unwrap_or(1) == 1 => return Ok((name.to_string(), 1)),
+            None => return Err(Error::msg(format!("Key not found: {}", key_id))),
+        };
+        let key_version = match pinned {
+            Some(pinned) => key_versions.iter().find(|v| v.version == pinned),
+            None => key_versions.iter().find(|v| v.state == KeyState::Active),
+        }
+        .ok_or_else(|| Error::msg(format!("No usable version for key: {}", key_id)))?;
+
+        let usable = match key_version.state {
+            KeyState::Active => true,
+            KeyState::VerifyOnly => {
+                !signing || key_version.grace_until.is_some_and(|until| now() < until)
+            }
+            KeyState::Retired => !signing,
+            KeyState::Destroyed => false,
+        };
+        let key_ref = key_version
+            .key_ref
+            .as_ref()
+            .filter(|_| usable)
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "Key {}@{} is {:?}",
+                    name, key_version.version, key_version.state
+                ))
+            })?;
+        Ok((key_ref.clone(), key_version.version))
+    }
+
+    /// Write `versions` to `path`; callers swap them in only once this succeeds.
+    fn persist(&self, versions: &HashMap<String, Vec<KeyVersion>>) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            let tmp_path = format!("{}.tmp", path);
+            std::fs::write(&tmp_path, serde_json::to_vec_pretty(versions)?)?;
+            std::fs::rename(&tmp_path, path)?;
+        }
+        Ok(())
+    }
+}
+
+impl KeyStore for VersionedKeyStore {
+    fn key_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.versions.read().unwrap().keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    fn address(&self, key_id: &str) -> Result<Address, Error> {
+        let (key_ref, _) = self.resolve(key_id, false)?;
+        self.inner.address(&key_ref)
+    }
+
+    fn public_key(&self, key_id: &str) -> Result<Vec<u8>, Error> {
+        let (key_ref, _) = self.resolve(key_id, false)?;
+        self.inner.public_key(&key_ref)
+    }
+
+    fn sign_digest(&self, key_id: &str, digest: &[u8]) -> Result<Signature, Error> {
+        let (key_ref, _) = self.resolve(key_id, true)?;
+        self.inner.sign_digest(&key_ref, digest)
+    }
+
+    fn pin_version(&self, key_id: &str) -> Result<(String, u32), Error> {
+        let (name, _) = split_version(key_id)?;
+        let (_, version) = self.resolve(key_id, true)?;
+        Ok((format!("{}@{}", name, version), version))
+    }
+}
+
+/// Split `name@N` into the key name and pinned version.
+pub fn split_version(key_id: &str) -> Result<(&str, Option<u32>), Error> {
+    match key_id.rsplit_once('@') {
+        Some((name, version)) => {
+            let version = version
+                .parse()
+                .map_err(|_| Error::msg(format!("Invalid key version: {}", key_id)))?;
+            Ok((name, Some(version)))
+        }
+        None => Ok((key_id, None)),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::key_store::TestKeyStore;
+
+    const DIGEST: [u8; 32] = [1u8; 32];
+
+    fn inner() -> Arc<dyn KeyStore> {
+        Arc::new(TestKeyStore::new(&["bridge", "bridge-v2", "bridge-v3"]))
+    }
+
+    /// Store over `inner` managing only `bridge`, with the newer material unregistered.
+    fn store(path: Option<String>) -> VersionedKeyStore {
+        let store = VersionedKeyStore {
+            inner: inner(),
+            versions: RwLock::new(HashMap::new()),
+            path,
+        };
+        store.promote("bridge", "bridge", 0).unwrap();
+        store
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("{}-{}.json", name, std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn pins_versions() {
+        let store = store(None);
+        store.promote("bridge", "bridge-v2", 3600).unwrap();
+        let v1 = inner().address("bridge").unwrap();
+        let v2 = inner().address("bridge-v2").unwrap();
+        assert_eq!(store.address("bridge").unwrap(), v2);
+        assert_eq!(store.address("bridge@2").unwrap(), v2);
+        assert_eq!(store.address("bridge@1").unwrap(), v1);
+        assert_eq!(
+            store.pin_version("bridge").unwrap(),
+            ("bridge@2".to_string(), 2)
+        );
+        assert_eq!(
+            store.pin_version("bridge@1").unwrap(),
+            ("bridge@1".to_string(), 1)
+        );
+        assert!(store.address("bridge@3").is_err());
+        assert!(store.address("bridge@x").is_err());
+        // Material of a version is only reachable through its key
+        assert!(store.address("bridge-v2").is_err());
+        // Unregistered ids fall through as version 1
+        assert_eq!(
+            store.address("bridge-v3").unwrap(),
+            inner().address("bridge-v3").unwrap()
+        );
+        assert!(store.address("bridge-v3@2").is_err());
+        assert_eq!(split_version("a@b@7").unwrap(), ("a@b", Some(7)));
+    }
+
+    #[test]
+    fn promote_keeps_old_version_for_grace_period() {
+        let store = store(None);
+        assert_eq!(store.promote("bridge", "bridge-v2", 3600).unwrap(), 2);
+        let states: Vec<(u32, KeyState)> = store
+            .list()
+            .iter()
+            .map(|info| (info.version, info.state))
+            .collect();
+        assert_eq!(
+            states,
+            vec![(1, KeyState::VerifyOnly), (2, KeyState::Active)]
+        );
+        assert!(store.sign_digest("bridge@1", &DIGEST).is_ok());
+
+        // Without a grace period the previous version stops signing at once
+        assert_eq!(store.promote("bridge", "bridge-v3", 0).unwrap(), 3);
+        assert!(store.sign_digest("bridge@2", &DIGEST).is_err());
+        assert!(store.address("bridge@2").is_ok());
+        assert!(store.sign_digest("bridge", &DIGEST).is_ok());
+        assert!(store.promote("bridge", "bridge-v3", 0).is_err());
+        assert!(store.promote("bridge", "unknown", 0).is_err());
+
+        store.set_state("bridge", 1, KeyState::Retired).unwrap();
+        assert!(store.sign_digest("bridge@1", &DIGEST).is_err());
+        assert!(store.address("bridge@1").is_ok());
+        assert!(store.set_state("bridge", 1, KeyState::Active).is_err());
+    }
+
+    #[test]
+    fn refuses_signing_with_inactive_versions() {
+        let store = store(None);
+        store.promote("bridge", "bridge-v2", 3600).unwrap();
+        for state in [KeyState::VerifyOnly, KeyState::Retired, KeyState::Destroyed] {
+            store.set_state("bridge", 1, state).unwrap();
+            assert!(
+                store.sign_digest("bridge@1", &DIGEST).is_err(),
+                "{:?}",
+                state
+            );
+            assert!(store.pin_version("bridge@1").is_err(), "{:?}", state);
+        }
+        // Destroyed versions stay destroyed and cannot be resolved at all
+        assert!(store.address("bridge@1").is_err());
+        assert!(store.set_state("bridge", 1, KeyState::Retired).is_err());
+        assert!(store.set_state("bridge", 9, KeyState::Retired).is_err());
+        // Once the active version is retired, the key has nothing to sign with
+        store.set_state("bridge", 2, KeyState::Retired).unwrap();
+        assert!(store.sign_digest("bridge", &DIGEST).is_err());
+    }
+
+    #[test]
+    fn reloads_persisted_versions() {
+        let path = temp_path("key-versions");
+        let store = store(Some(path.clone()));
+        store.promote("bridge", "bridge-v2", 3600).unwrap();
+        store.set_state("bridge", 1, KeyState::Retired).unwrap();
+        let reloaded = VersionedKeyStore::load(inner(), Some(path.clone())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let states: Vec<(String, u32, KeyState)> = reloaded
+            .list()
+            .into_iter()
+            .map(|info| (info.key_id, info.version, info.state))
+            .collect();
+        // `bridge-v3` was unknown to the registry, so loading registered it
+        assert_eq!(
+            states,
+            vec![
+                ("bridge".to_string(), 1, KeyState::Retired),
+                ("bridge".to_string(), 2, KeyState::Active),
+                ("bridge-v3".to_string(), 1, KeyState::Active),
+            ]
+        );
+        assert_eq!(
+            reloaded.address("bridge").unwrap(),
+            inner().address("bridge-v2").unwrap()
+        );
+    }
+
+    #[test]
+    fn keeps_state_when_persisting_fails() {
+        let dir = std::env::temp_dir().join(format!("key-versions-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = store(Some(
+            dir.join("versions.json").to_str().unwrap().to_string(),
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(store.promote("bridge", "bridge-v2", 3600).is_err());
+        assert!(store.set_state("bridge", 1, KeyState::Retired).is_err());
+        let states: Vec<(u32, KeyState)> = store
+            .list()
+            .iter()
+            .map(|info| (info.version, info.state))
+            .collect();
+        assert_eq!(states, vec![(1, KeyState::Active)]);
+        assert_eq!(
+            store.address("bridge").unwrap(),
+            inner().address("bridge").unwrap()
+        );
+    }
+}
@@ -1,3 +1,20 @@
+pub mod abi_registry;
+pub mod app_state;
+pub mod client_registry;
+pub mod eip712;
 pub mod encryption;
+pub mod hd_wallet;
 pub mod hsm_utils;
+pub mod identity;
 pub mod jwt_auth;
+pub mod key_schedule;
+pub mod key_store;
+pub mod key_versions;
+pub mod permit;
+#[cfg(feature = "pkcs11")]
+pub mod pkcs11;
+pub mod rpc_signer;
+pub mod safe;
+pub mod seal;
+pub mod secret_storage;
+pub mod session_store;
@@ -0,0 +1,434 @@
+use crate::utils::eip712::{chain_addresses_from_env, DomainAllowlist, TypedData, TypedField};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashSet},
+    str::FromStr,
+};
+use web3::types::Address;
+
+/// Canonical Permit2 deployment, the same on every chain.
+pub const PERMIT2_ADDRESS: &str = "0x000000000022D473030F116dDEE9F6B43aC78BA3";
+
+fn default_version() -> String {
+    "1".to_string()
+}
+
+/// Permit2 `PermitDetails` of one token in a `PermitBatch`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PermitDetails {
+    pub token: String,
+    /// `uint160` allowance.
+    pub amount: String,
+    /// `uint48` timestamp the allowance expires at.
+    pub expiration: String,
+    /// `uint48` allowance nonce of the owner, token and spender.
+    pub nonce: String,
+}
+
+/// Permit structs the HSM signs, tagged by `kind`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Permit {
+    /// EIP-2612 `Permit` verified by the token itself.
+    Eip2612 {
+        token: String,
+        /// Token `name()` as used in its domain.
+        name: String,
+        #[serde(default = "default_version")]
+        version: String,
+        /// Domain salt for tokens that bind the chain through it instead of
+        /// `chainId`.
+        #[serde(default)]
+        salt: Option<String>,
+        spender: String,
+        value: String,
+        nonce: String,
+        deadline: String,
+    },
+    /// Permit2 `PermitTransferFrom` (signature transfer).
+    Permit2TransferFrom {
+        token: String,
+        amount: String,
+        spender: String,
+        nonce: String,
+        deadline: String,
+    },
+    /// Permit2 `PermitBatch` (allowance transfer).
+    Permit2Batch {
+        details: Vec<PermitDetails>,
+        spender: String,
+        sig_deadline: String,
+    },
+}
+
+impl Permit {
+    /// Tokens the permit approves.
+    pub fn tokens(&self) -> Vec<&str> {
+        match self {
+            Permit::Eip2612 { token, .. } | Permit::Permit2TransferFrom { token, .. } => {
+                vec![token.as_str()]
+            }
+            Permit::Permit2Batch { details, .. } => {
+                details.iter().map(|detail| detail.token.as_str()).collect()
+            }
+        }
+    }
+
+    pub fn spender(&self) -> &str {
+        match self {
+            Permit::Eip2612 { spender, .. }
+            | Permit::Permit2TransferFrom { spender, .. }
+            | Permit::Permit2Batch { spender, .. } => spender,
+        }
+    }
+
+    /// Refuse permits for tokens outside the EIP-712 allowlist or spenders
+    /// outside `spenders`. `typed_data` must be the permit's own document;
+    /// its domain is checked too unless the token binds the chain by salt.
+    pub fn check(
+        &self,
+        chain_id: u64,
+        typed_data: &TypedData,
+        allowlist: &DomainAllowlist,
+        spenders: &PermitSpenders,
+    ) -> Result<(), Error> {
+        for token in self.tokens() {
+            let token = Address::from_str(token)
+                .map_err(|err| Error::msg(format!("Error parsing token address: {}", err)))?;
+            allowlist.check_contract(chain_id, token)?;
+        }
+        let spender = Address::from_str(self.spender())
+            .map_err(|err| Error::msg(format!("Error parsing spender address: {}", err)))?;
+        spenders.check(chain_id, spender)?;
+        match self {
+            Permit::Eip2612 { salt: Some(_), .. } => Ok(()),
+            _ => allowlist.check(typed_data),
+        }
+    }
+
+    /// Typed-data document of the permit signed by `owner`. Permit2 permits are
+    /// bound to the Permit2 contract at `permit2`.
+    pub fn typed_data(
+        &self,
+        chain_id: u64,
+        owner: Address,
+        permit2: Address,
+    ) -> Result<TypedData, Error> {
+        let mut types = BTreeMap::new();
+        let (primary_type, domain, message) = match self {
+            Permit::Eip2612 {
+                token,
+                name,
+                version,
+                salt,
+                spender,
+                value,
+                nonce,
+                deadline,
+            } => {
+                types.insert(
+                    "Permit".to_string(),
+                    fields(&[
+                        ("owner", "address"),
+                        ("spender", "address"),
+                        ("value", "uint256"),
+                        ("nonce", "uint256"),
+                        ("deadline", "uint256"),
+                    ]),
+                );
+                let domain = match salt {
+                    Some(salt) => json!({
+                        "name": name,
+                        "version": version,
+                        "verifyingContract": token,
+                        "salt": salt,
+                    }),
+                    None => json!({
+                        "name": name,
+                        "version": version,
+                        "chainId": chain_id,
+                        "verifyingContract": token,
+                    }),
+                };
+                let message = json!({
+                    "owner": format!("{:?}", owner),
+                    "spender": spender,
+                    "value": value,
+                    "nonce": nonce,
+                    "deadline": deadline,
+                });
+                ("Permit", domain, message)
+            }
+            Permit::Permit2TransferFrom {
+                token,
+                amount,
+                spender,
+                nonce,
+                deadline,
+            } => {
+                types.insert(
+                    "PermitTransferFrom".to_string(),
+                    fields(&[
+                        ("permitted", "TokenPermissions"),
+                        ("spender", "address"),
+                        ("nonce", "uint256"),
+                        ("deadline", "uint256"),
+                    ]),
+                );
+                types.insert(
+                    "TokenPermissions".to_string(),
+                    fields(&[("token", "address"), ("amount", "uint256")]),
+                );
+                let message = json!({
+                    "permitted": { "token": token, "amount": amount },
+                    "spender": spender,
+                    "nonce": nonce,
+                    "deadline": deadline,
+                });
+                (
+                    "PermitTransferFrom",
+                    permit2_domain(chain_id, permit2),
+                    message,
+                )
+            }
+            Permit::Permit2Batch {
+                details,
+                spender,
+                sig_deadline,
+            } => {
+                if details.is_empty() {
+                    return Err(Error::msg("PermitBatch needs at least one token"));
+                }
+                types.insert(
+                    "PermitBatch".to_string(),
+                    fields(&[
+                        ("details", "PermitDetails[]"),
+                        ("spender", "address"),
+                        ("sigDeadline", "uint256"),
+                    ]),
+                );
+                types.insert(
+                    "PermitDetails".to_string(),
+                    fields(&[
+                        ("token", "address"),
+                        ("amount", "uint160"),
+                        ("expiration", "uint48"),
+                        ("nonce", "uint48"),
+                    ]),
+                );
+                let message = json!({
+                    "details": serde_json::to_value(details)?,
+                    "spender": spender,
+                    "sigDeadline": sig_deadline,
+                });
+                ("PermitBatch", permit2_domain(chain_id, permit2), message)
+            }
+        };
+        Ok(TypedData {
+            types,
+            primary_type: primary_type.to_string(),
+            domain,
+            message,
+        })
+    }
+}
+
+/// Spenders permits may approve, as `chainId:spender` pairs.
+#[derive(Debug, Default)]
+pub struct PermitSpenders {
+    spenders: HashSet<(u64, Address)>,
+}
+
+impl PermitSpenders {
+    /// Parse `PERMIT_ALLOWED_SPENDERS`. With no entries every permit is refused.
+    pub fn from_env() -> Result<Self, Error> {
+        Ok(PermitSpenders {
+            spenders: chain_addresses_from_env("PERMIT_ALLOWED_SPENDERS")?,
+        })
+    }
+
+    pub fn check(&self, chain_id: u64, spender: Address) -> Result<(), Error> {
+        if self.spenders.contains(&(chain_id, spender)) {
+            Ok(())
+        } else {
+            Err(Error::msg(format!(
+                "Spender {}:{:?} is not allowed",
+                chain_id, spender
+            )))
+        }
+    }
+}
+
+fn permit2_domain(chain_id: u64, permit2: Address) -> Value {
+    json!({
+        "name": "Permit2",
+        "chainId": chain_id,
+        "verifyingContract": format!("{:?}", permit2),
+    })
+}
+
+fn fields(fields: &[(&str, &str)]) -> Vec<TypedField> {
+    fields
+        .iter()
+        .map(|(name, kind)| TypedField {
+            name: name.to_string(),
+            kind: kind.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web3::types::H256;
+
+    const USDC: &str = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+    const OWNER: &str = "0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f";
+    const SPENDER: &str = "0x3fC91A3afd70395Cd496C647d5a6CC9D4B2b7FAD";
+
+    fn address(address: &str) -> Address {
+        Address::from_str(address).unwrap()
+    }
+
+    fn typed_data(permit: &Permit) -> TypedData {
+        permit
+            .typed_data(1, address(OWNER), address(PERMIT2_ADDRESS))
+            .unwrap()
+    }
+
+    fn usdc_permit() -> Permit {
+        Permit::Eip2612 {
+            token: USDC.to_string(),
+            name: "USD Coin".to_string(),
+            version: "2".to_string(),
+            salt: None,
+            spender: SPENDER.to_string(),
+            value: "1000000".to_string(),
+            nonce: "0".to_string(),
+            deadline: "1700000000".to_string(),
+        }
+    }
+
+    fn permit2_transfer() -> Permit {
+        Permit::Permit2TransferFrom {
+            token: USDC.to_string(),
+            amount: "1000000".to_string(),
+            spender: SPENDER.to_string(),
+            nonce: "5".to_string(),
+            deadline: "1700000000".to_string(),
+        }
+    }
+
+    fn permit2_batch() -> Permit {
+        Permit::Permit2Batch {
+            details: vec![PermitDetails {
+                token: USDC.to_string(),
+                amount: "1000000".to_string(),
+                expiration: "1700003600".to_string(),
+                nonce: "2".to_string(),
+            }],
+            spender: SPENDER.to_string(),
+            sig_deadline: "1700000000".to_string(),
+        }
+    }
+
+    fn hash(hex: &str) -> H256 {
+        H256::from_str(hex).unwrap()
+    }
+
+    #[test]
+    fn hashes_usdc_permit() {
+        let typed_data = typed_data(&usdc_permit());
+        assert_eq!(
+            H256(typed_data.type_hash("Permit").unwrap()),
+            hash("0x6e71edae12b1b97f4d1f60370fef10105fa2faae0126114a169c64845d6126c9")
+        );
+        // USDC.DOMAIN_SEPARATOR() on mainnet
+        assert_eq!(
+            H256(typed_data.domain_separator().unwrap()),
+            hash("0x06c37168a7db5138defc7866392bb87a741f9b3d104deb5094588ce041cae335")
+        );
+        assert_eq!(
+            typed_data.digest().unwrap(),
+            hash("0x0247aff46364d25152dd7197831ccbdd98428410bcc8af72d42c36259ad6a3a1")
+        );
+    }
+
+    // Typehash constants of the Permit2 contracts
+    #[test]
+    fn hashes_permit2_transfer_from() {
+        let typed_data = typed_data(&permit2_transfer());
+        assert_eq!(
+            H256(typed_data.type_hash("TokenPermissions").unwrap()),
+            hash("0x618358ac3db8dc274f0cd8829da7e234bd48cd73c4a740aede1adec9846d06a1")
+        );
+        assert_eq!(
+            H256(typed_data.type_hash("PermitTransferFrom").unwrap()),
+            hash("0x939c21a48a8dbe3a9a2404a1d46691e4d39f6583d6ec6b35714604c986d80106")
+        );
+        // Permit2.DOMAIN_SEPARATOR() on mainnet
+        assert_eq!(
+            H256(typed_data.domain_separator().unwrap()),
+            hash("0x866a5aba21966af95d6c7ab78eb2b2fc913915c28be3b9aa07cc04ff903e3f28")
+        );
+        assert_eq!(
+            typed_data.digest().unwrap(),
+            hash("0x56bdb1fa8d0a170f116998aab1fea5b574d381f00ce0a5f75c50255eaf42e5c2")
+        );
+    }
+
+    #[test]
+    fn hashes_permit2_batch() {
+        let typed_data = typed_data(&permit2_batch());
+        assert_eq!(
+            H256(typed_data.type_hash("PermitDetails").unwrap()),
+            hash("0x65626cad6cb96493bf6f5ebea28756c966f023ab9e8a83a7101849d5573b3678")
+        );
+        assert_eq!(
+            H256(typed_data.type_hash("PermitBatch").unwrap()),
+            hash("0xaf1b0d30d2cab0380e68f0689007e3254993c596f2fdd0aaa7f4d04f79440863")
+        );
+        assert_eq!(
+            typed_data.digest().unwrap(),
+            hash("0x6a42c614be25b49536958bdb00f0c54f86cf30fc571103d03c1c6d56e0497758")
+        );
+    }
+
+    #[test]
+    fn checks_tokens_and_spenders() {
+        let allowlist = |contracts: &[&str]| {
+            DomainAllowlist::new(
+                contracts
+                    .iter()
+                    .map(|contract| (1, address(contract)))
+                    .collect(),
+            )
+        };
+        let spenders = PermitSpenders {
+            spenders: HashSet::from([(1, address(SPENDER))]),
+        };
+        let both = allowlist(&[USDC, PERMIT2_ADDRESS]);
+        for permit in [usdc_permit(), permit2_transfer(), permit2_batch()] {
+            let typed_data = typed_data(&permit);
+            assert!(permit.check(1, &typed_data, &both, &spenders).is_ok());
+            assert!(permit
+                .check(1, &typed_data, &both, &PermitSpenders::default())
+                .is_err());
+            assert!(permit
+                .check(1, &typed_data, &allowlist(&[PERMIT2_ADDRESS]), &spenders)
+                .is_err());
+        }
+        // Permit2 itself must be allowed for Permit2 permits
+        let usdc_only = allowlist(&[USDC]);
+        let permit = usdc_permit();
+        assert!(permit
+            .check(1, &typed_data(&permit), &usdc_only, &spenders)
+            .is_ok());
+        let permit = permit2_transfer();
+        assert!(permit
+            .check(1, &typed_data(&permit), &usdc_only, &spenders)
+            .is_err());
+    }
+}
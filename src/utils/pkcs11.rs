@@ -0,0 +1,774 @@
+//! PKCS#11 signer: keys stay non-extractable inside a hardware or software HSM
+//! and every signature is produced by `C_Sign` with `CKM_ECDSA`.
+//!
+//! Local setup against SoftHSMv2:
+//!
+//! ```text
+//! softhsm2-util --init-token --free --label bridge --pin 1234 --so-pin 5678
+//! pkcs11-tool --module /usr/lib/softhsm/libsofthsm2.so --login --pin 1234 \
+//!     --keypairgen --key-type EC:secp256k1 --label bridge
+//! pkcs11-tool --module /usr/lib/softhsm/libsofthsm2.so --login --pin 1234 \
+//!     --keypairgen --key-type EC:prime256v1 --label bridge-p256
+//! ```
+//!
+//! then run with `KEY_STORE=pkcs11`, `PKCS11_MODULE=/usr/lib/softhsm/libsofthsm2.so`
+//! and `PKCS11_KEY_LABELS=bridge`; `IDENTITY_KEY_LABEL=bridge-p256` signs key
+//! exchanges with the P-256 key. Private keys that are not `CKA_SENSITIVE` or
+//! are `CKA_EXTRACTABLE` are refused. The token tests run against the same setup:
+//!
+//! ```text
+//! PKCS11_MODULE=/usr/lib/softhsm/libsofthsm2.so PKCS11_PIN=1234 \
+//!     cargo test --features pkcs11 -- --ignored pkcs11
+//! ```
+use crate::utils::key_store::{public_key_address, KeyStore};
+use anyhow::Error;
+use libloading::Library;
+use p256::ecdsa::signature::hazmat::PrehashVerifier;
+use secp256k1::{
+    ecdsa::{RecoverableSignature, RecoveryId},
+    All, Message, PublicKey, Secp256k1,
+};
+use std::{collections::HashMap, ffi::c_void, os::raw::c_ulong, ptr, sync::Mutex};
+use web3::{
+    signing::Signature,
+    types::{Address, H256},
+};
+use zeroize::Zeroizing;
+
+type CkUlong = c_ulong;
+type CkRv = CkUlong;
+type Unused = Option<unsafe extern "C" fn()>;
+
+const CKR_OK: CkRv = 0x0;
+const CKR_USER_ALREADY_LOGGED_IN: CkRv = 0x100;
+const CKR_CRYPTOKI_ALREADY_INITIALIZED: CkRv = 0x191;
+const CKF_RW_SESSION: CkUlong = 0x2;
+const CKF_SERIAL_SESSION: CkUlong = 0x4;
+const CKF_OS_LOCKING_OK: CkUlong = 0x2;
+const CKU_USER: CkUlong = 1;
+const CKA_CLASS: CkUlong = 0x0;
+const CKA_LABEL: CkUlong = 0x3;
+const CKA_SENSITIVE: CkUlong = 0x103;
+const CKA_EXTRACTABLE: CkUlong = 0x162;
+const CKA_EC_PARAMS: CkUlong = 0x180;
+const CKA_EC_POINT: CkUlong = 0x181;
+const CKO_PUBLIC_KEY: CkUlong = 0x2;
+const CKO_PRIVATE_KEY: CkUlong = 0x3;
+const CKM_ECDSA: CkUlong = 0x1041;
+
+/// DER encoded curve OIDs as found in `CKA_EC_PARAMS`.
+const SECP256K1_OID: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x0a];
+const P256_OID: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+#[repr(C)]
+struct CkVersion {
+    major: u8,
+    minor: u8,
+}
+
+#[repr(C)]
+struct CkAttribute {
+    attr_type: CkUlong,
+    value: *mut c_void,
+    value_len: CkUlong,
+}
+
+#[repr(C)]
+struct CkMechanism {
+    mechanism: CkUlong,
+    parameter: *mut c_void,
+    parameter_len: CkUlong,
+}
+
+#[repr(C)]
+struct CkInitializeArgs {
+    create_mutex: Unused,
+    destroy_mutex: Unused,
+    lock_mutex: Unused,
+    unlock_mutex: Unused,
+    flags: CkUlong,
+    reserved: *mut c_void,
+}
+
+/// Leading part of `CK_FUNCTION_LIST`, up to `C_Sign`. The order is fixed by
+/// the PKCS#11 specification; entries the signer never calls are `Unused`.
+#[repr(C)]
+struct CkFunctionList {
+    version: CkVersion,
+    c_initialize: Option<unsafe extern "C" fn(*mut c_void) -> CkRv>,
+    c_finalize: Unused,
+    c_get_info: Unused,
+    c_get_function_list: Unused,
+    c_get_slot_list: Option<unsafe extern "C" fn(u8, *mut CkUlong, *mut CkUlong) -> CkRv>,
+    c_get_slot_info: Unused,
+    c_get_token_info: Unused,
+    c_get_mechanism_list: Unused,
+    c_get_mechanism_info: Unused,
+    c_init_token: Unused,
+    c_init_pin: Unused,
+    c_set_pin: Unused,
+    c_open_session: Option<
+        unsafe extern "C" fn(CkUlong, CkUlong, *mut c_void, *mut c_void, *mut CkUlong) -> CkRv,
+    >,
+    c_close_session: Option<unsafe extern "C" fn(CkUlong) -> CkRv>,
+    c_close_all_sessions: Unused,
+    c_get_session_info: Unused,
+    c_get_operation_state: Unused,
+    c_set_operation_state: Unused,
+    c_login: Option<unsafe extern "C" fn(CkUlong, CkUlong, *const u8, CkUlong) -> CkRv>,
+    c_logout: Unused,
+    c_create_object: Unused,
+    c_copy_object: Unused,
+    c_destroy_object: Unused,
+    c_get_object_size: Unused,
+    c_get_attribute_value:
+        Option<unsafe extern "C" fn(CkUlong, CkUlong, *mut CkAttribute, CkUlong) -> CkRv>,
+    c_set_attribute_value: Unused,
+    c_find_objects_init: Option<unsafe extern "C" fn(CkUlong, *mut CkAttribute, CkUlong) -> CkRv>,
+    c_find_objects:
+        Option<unsafe extern "C" fn(CkUlong, *mut CkUlong, CkUlong, *mut CkUlong) -> CkRv>,
+    c_find_objects_final: Option<unsafe extern "C" fn(CkUlong) -> CkRv>,
+    c_encrypt_init: Unused,
+    c_encrypt: Unused,
+    c_encrypt_update: Unused,
+    c_encrypt_final: Unused,
+    c_decrypt_init: Unused,
+    c_decrypt: Unused,
+    c_decrypt_update: Unused,
+    c_decrypt_final: Unused,
+    c_digest_init: Unused,
+    c_digest: Unused,
+    c_digest_update: Unused,
+    c_digest_key: Unused,
+    c_digest_final: Unused,
+    c_sign_init: Option<unsafe extern "C" fn(CkUlong, *mut CkMechanism, CkUlong) -> CkRv>,
+    c_sign:
+        Option<unsafe extern "C" fn(CkUlong, *const u8, CkUlong, *mut u8, *mut CkUlong) -> CkRv>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Curve {
+    Secp256k1,
+    P256,
+}
+
+struct Pkcs11Key {
+    private_key: CkUlong,
+    public_key: Vec<u8>,
+    curve: Curve,
+}
+
+/// Keys living in a PKCS#11 token, addressed by their `CKA_LABEL`.
+pub struct Pkcs11KeyStore {
+    functions: *const CkFunctionList,
+    session: Mutex<CkUlong>,
+    keys: HashMap<String, Pkcs11Key>,
+    secp: Secp256k1<All>,
+    // keeps the module loaded for as long as `functions` is used
+    _library: Library,
+}
+
+// The function list is immutable and every call into the token goes through
+// the `session` mutex.
+unsafe impl Send for Pkcs11KeyStore {}
+unsafe impl Sync for Pkcs11KeyStore {}
+
+fn check(rv: CkRv, operation: &str) -> Result<(), Error> {
+    if rv == CKR_OK {
+        Ok(())
+    } else {
+        Err(Error::msg(format!("{} failed: CKR 0x{:x}", operation, rv)))
+    }
+}
+
+fn function<T>(function: Option<T>, name: &str) -> Result<T, Error> {
+    function.ok_or_else(|| Error::msg(format!("PKCS#11 module does not provide {}", name)))
+}
+
+impl Pkcs11KeyStore {
+    /// Load `module`, log into `slot` (or the first slot with a token) and
+    /// look up the key pair of every label.
+    pub fn load(
+        module: &str,
+        slot: Option<CkUlong>,
+        pin: &str,
+        labels: &[String],
+    ) -> Result<Self, Error> {
+        let library = unsafe { Library::new(module) }.map_err(|err| {
+            Error::msg(format!("Error loading PKCS#11 module {}: {}", module, err))
+        })?;
+        let functions = unsafe {
+            let get_function_list = library.get::<unsafe extern "C" fn(
+                *mut *const CkFunctionList,
+            ) -> CkRv>(b"C_GetFunctionList\0")?;
+            let mut functions: *const CkFunctionList = ptr::null();
+            check(get_function_list(&mut functions), "C_GetFunctionList")?;
+            functions
+        };
+        if functions.is_null() {
+            return Err(Error::msg("PKCS#11 module returned no function list"));
+        }
+        let list = unsafe { &*functions };
+
+        let mut init_args = CkInitializeArgs {
+            create_mutex: None,
+            destroy_mutex: None,
+            lock_mutex: None,
+            unlock_mutex: None,
+            flags: CKF_OS_LOCKING_OK,
+            reserved: ptr::null_mut(),
+        };
+        let rv = unsafe {
+            function(list.c_initialize, "C_Initialize")?(
+                &mut init_args as *mut CkInitializeArgs as *mut c_void,
+            )
+        };
+        if rv != CKR_CRYPTOKI_ALREADY_INITIALIZED {
+            check(rv, "C_Initialize")?;
+        }
+
+        let slot = match slot {
+            Some(slot) => slot,
+            None => {
+                let get_slot_list = function(list.c_get_slot_list, "C_GetSlotList")?;
+                let mut count: CkUlong = 0;
+                check(
+                    unsafe { get_slot_list(1, ptr::null_mut(), &mut count) },
+                    "C_GetSlotList",
+                )?;
+                let mut slots = vec![0; count as usize];
+                check(
+                    unsafe { get_slot_list(1, slots.as_mut_ptr(), &mut count) },
+                    "C_GetSlotList",
+                )?;
+                *slots
+                    .first()
+                    .ok_or_else(|| Error::msg("No PKCS#11 slot with a token found"))?
+            }
+        };
+
+        let mut session: CkUlong = 0;
+        check(
+            unsafe {
+                function(list.c_open_session, "C_OpenSession")?(
+                    slot,
+                    CKF_SERIAL_SESSION | CKF_RW_SESSION,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    &mut session,
+                )
+            },
+            "C_OpenSession",
+        )?;
+        let rv = unsafe {
+            function(list.c_login, "C_Login")?(
+                session,
+                CKU_USER,
+                pin.as_ptr(),
+                pin.len() as CkUlong,
+            )
+        };
+        if rv != CKR_USER_ALREADY_LOGGED_IN {
+            check(rv, "C_Login")?;
+        }
+
+        let mut store = Pkcs11KeyStore {
+            functions,
+            session: Mutex::new(session),
+            keys: HashMap::new(),
+            secp: Secp256k1::new(),
+            _library: library,
+        };
+        for label in labels {
+            let key = store.find_key(label)?;
+            println!("Found PKCS#11 key {} ({:?})", label, key.curve);
+            store.keys.insert(label.clone(), key);
+        }
+        Ok(store)
+    }
+
+    /// Configure from `PKCS11_MODULE`, `PKCS11_SLOT`, `PKCS11_KEY_LABELS` and
+    /// the user PIN in `PKCS11_PIN_FILE` (or prompted on the TTY). Empty
+    /// values count as unset.
+    pub fn from_env() -> Result<Self, Error> {
+        let labels: Vec<String> = dotenvy::var("PKCS11_KEY_LABELS")
+            .map_err(|_| Error::msg("PKCS#11 key labels not found"))?
+            .split(',')
+            .map(|label| label.trim().to_string())
+            .filter(|label| !label.is_empty())
+            .collect();
+        Self::from_env_with_labels(&labels)
+    }
+
+    /// Like [`Pkcs11KeyStore::from_env`], for the keys `labels`.
+    pub fn from_env_with_labels(labels: &[String]) -> Result<Self, Error> {
+        let module =
+            dotenvy::var("PKCS11_MODULE").map_err(|_| Error::msg("PKCS#11 module not found"))?;
+        let slot = match dotenvy::var("PKCS11_SLOT")
+            .ok()
+            .filter(|slot| !slot.is_empty())
+        {
+            Some(slot) => Some(
+                slot.parse()
+                    .map_err(|err| Error::msg(format!("Invalid PKCS#11 slot {}: {}", slot, err)))?,
+            ),
+            None => None,
+        };
+        let pin = match dotenvy::var("PKCS11_PIN_FILE")
+            .ok()
+            .filter(|path| !path.is_empty())
+        {
+            Some(path) => Zeroizing::new(
+                std::fs::read_to_string(&path)
+                    .map_err(|err| Error::msg(format!("Error reading PIN file {}: {}", path, err)))?
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string(),
+            ),
+            None => Zeroizing::new(rpassword::prompt_password("PKCS#11 user PIN: ")?),
+        };
+        Self::load(&module, slot, &pin, labels)
+    }
+
+    fn list(&self) -> &CkFunctionList {
+        unsafe { &*self.functions }
+    }
+
+    fn find_key(&self, label: &str) -> Result<Pkcs11Key, Error> {
+        let session = *self.session.lock().unwrap();
+        let private_key = self.find_object(session, CKO_PRIVATE_KEY, label)?;
+        check_non_extractable(
+            label,
+            &self.attribute(session, private_key, CKA_SENSITIVE)?,
+            &self.attribute(session, private_key, CKA_EXTRACTABLE)?,
+        )?;
+        let public_key = self.find_object(session, CKO_PUBLIC_KEY, label)?;
+
+        let ec_params = self.attribute(session, public_key, CKA_EC_PARAMS)?;
+        let curve = match ec_params.as_slice() {
+            SECP256K1_OID => Curve::Secp256k1,
+            P256_OID => Curve::P256,
+            _ => {
+                return Err(Error::msg(format!(
+                    "Key {} is on an unsupported curve",
+                    label
+                )))
+            }
+        };
+        let ec_point = self.attribute(session, public_key, CKA_EC_POINT)?;
+        // CKA_EC_POINT is usually a DER OCTET STRING around the SEC1 point
+        let public_key = match ec_point.as_slice() {
+            [0x04, 0x41, point @ ..] if point.len() == 65 => point.to_vec(),
+            point if point.len() == 65 && point[0] == 0x04 => point.to_vec(),
+            _ => return Err(Error::msg(format!("Key {} has an invalid EC point", label))),
+        };
+        Ok(Pkcs11Key {
+            private_key,
+            public_key,
+            curve,
+        })
+    }
+
+    fn find_object(&self, session: CkUlong, class: CkUlong, label: &str) -> Result<CkUlong, Error> {
+        let list = self.list();
+        let mut class = class;
+        let mut template = [
+            CkAttribute {
+                attr_type: CKA_CLASS,
+                value: &mut class as *mut CkUlong as *mut c_void,
+                value_len: std::mem::size_of::<CkUlong>() as CkUlong,
+            },
+            CkAttribute {
+                attr_type: CKA_LABEL,
+                value: label.as_ptr() as *mut c_void,
+                value_len: label.len() as CkUlong,
+            },
+        ];
+        let mut object: CkUlong = 0;
+        let mut count: CkUlong = 0;
+        unsafe {
+            check(
+                function(list.c_find_objects_init, "C_FindObjectsInit")?(
+                    session,
+                    template.as_mut_ptr(),
+                    template.len() as CkUlong,
+                ),
+                "C_FindObjectsInit",
+            )?;
+            let rv = function(list.c_find_objects, "C_FindObjects")?(
+                session,
+                &mut object,
+                1,
+                &mut count,
+            );
+            check(
+                function(list.c_find_objects_final, "C_FindObjectsFinal")?(session),
+                "C_FindObjectsFinal",
+            )?;
+            check(rv, "C_FindObjects")?;
+        }
+        if count == 0 {
+            return Err(Error::msg(format!("PKCS#11 object not found: {}", label)));
+        }
+        Ok(object)
+    }
+
+    fn attribute(
+        &self,
+        session: CkUlong,
+        object: CkUlong,
+        attr_type: CkUlong,
+    ) -> Result<Vec<u8>, Error> {
+        let get_attribute_value =
+            function(self.list().c_get_attribute_value, "C_GetAttributeValue")?;
+        let mut template = CkAttribute {
+            attr_type,
+            value: ptr::null_mut(),
+            value_len: 0,
+        };
+        check(
+            unsafe { get_attribute_value(session, object, &mut template, 1) },
+            "C_GetAttributeValue",
+        )?;
+        let mut value = vec![0u8; template.value_len as usize];
+        template.value = value.as_mut_ptr() as *mut c_void;
+        check(
+            unsafe { get_attribute_value(session, object, &mut template, 1) },
+            "C_GetAttributeValue",
+        )?;
+        value.truncate(template.value_len as usize);
+        Ok(value)
+    }
+
+    fn key(&self, key_id: &str) -> Result<&Pkcs11Key, Error> {
+        self.keys
+            .get(key_id)
+            .ok_or_else(|| Error::msg(format!("Key not found: {}", key_id)))
+    }
+
+    /// Curve of the key registered under `key_id`.
+    pub fn curve(&self, key_id: &str) -> Result<Curve, Error> {
+        Ok(self.key(key_id)?.curve)
+    }
+
+    /// `C_Sign` with `CKM_ECDSA`: the 64-byte `r || s` of `digest`, for
+    /// secp256k1 and P-256 keys alike.
+    pub fn sign_ecdsa(&self, key_id: &str, digest: &[u8]) -> Result<[u8; 64], Error> {
+        let key = self.key(key_id)?;
+        let list = self.list();
+        let session = self.session.lock().unwrap();
+        let mut mechanism = CkMechanism {
+            mechanism: CKM_ECDSA,
+            parameter: ptr::null_mut(),
+            parameter_len: 0,
+        };
+        // room for a DER signature from tokens that do not return raw `r || s`
+        let mut signature = [0u8; 72];
+        let mut signature_len = signature.len() as CkUlong;
+        unsafe {
+            check(
+                function(list.c_sign_init, "C_SignInit")?(
+                    *session,
+                    &mut mechanism,
+                    key.private_key,
+                ),
+                "C_SignInit",
+            )?;
+            check(
+                function(list.c_sign, "C_Sign")?(
+                    *session,
+                    digest.as_ptr(),
+                    digest.len() as CkUlong,
+                    signature.as_mut_ptr(),
+                    &mut signature_len,
+                ),
+                "C_Sign",
+            )?;
+        }
+        compact_signature(key.curve, &signature[..signature_len as usize])
+    }
+
+    /// ECDSA P-256 signature of the SHA-256 `digest` by the P-256 key
+    /// `key_id`, low-S normalised and checked against its public key.
+    pub fn sign_p256(&self, key_id: &str, digest: &[u8]) -> Result<p256::ecdsa::Signature, Error> {
+        let key = self.key(key_id)?;
+        if key.curve != Curve::P256 {
+            return Err(Error::msg(format!("Key {} is not a P-256 key", key_id)));
+        }
+        let raw_signature = self.sign_ecdsa(key_id, digest)?;
+        p256_signature(&key.public_key, digest, &raw_signature)
+    }
+}
+
+impl KeyStore for Pkcs11KeyStore {
+    fn key_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.keys.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    fn address(&self, key_id: &str) -> Result<Address, Error> {
+        let key = self.key(key_id)?;
+        if key.curve != Curve::Secp256k1 {
+            return Err(Error::msg(format!("Key {} is not a secp256k1 key", key_id)));
+        }
+        Ok(public_key_address(&key.public_key))
+    }
+
+    fn public_key(&self, key_id: &str) -> Result<Vec<u8>, Error> {
+        Ok(self.key(key_id)?.public_key.clone())
+    }
+
+    /// The token only returns `r || s`; see [`recoverable_signature`] for `v`.
+    fn sign_digest(&self, key_id: &str, digest: &[u8]) -> Result<Signature, Error> {
+        let key = self.key(key_id)?;
+        if key.curve != Curve::Secp256k1 {
+            return Err(Error::msg(format!("Key {} is not a secp256k1 key", key_id)));
+        }
+        let raw_signature = self.sign_ecdsa(key_id, digest)?;
+        recoverable_signature(&self.secp, &key.public_key, digest, &raw_signature)
+    }
+}
+
+/// Refuse private keys whose `CKA_SENSITIVE` is not true or whose
+/// `CKA_EXTRACTABLE` is not false (`CK_BBOOL` values).
+fn check_non_extractable(label: &str, sensitive: &[u8], extractable: &[u8]) -> Result<(), Error> {
+    if sensitive != [1] || extractable != [0] {
+        return Err(Error::msg(format!(
+            "Key {} must be sensitive and non-extractable",
+            label
+        )));
+    }
+    Ok(())
+}
+
+/// `r || s` of a `CKM_ECDSA` signature, given either raw or DER encoded.
+fn compact_signature(curve: Curve, signature: &[u8]) -> Result<[u8; 64], Error> {
+    let invalid =
+        |err: &dyn std::fmt::Display| Error::msg(format!("Invalid token signature: {}", err));
+    if let Ok(compact) = <[u8; 64]>::try_from(signature) {
+        return Ok(compact);
+    }
+    match curve {
+        Curve::Secp256k1 => Ok(secp256k1::ecdsa::Signature::from_der(signature)
+            .map_err(|err| invalid(&err))?
+            .serialize_compact()),
+        Curve::P256 => Ok(p256::ecdsa::Signature::from_der(signature)
+            .map_err(|err| invalid(&err))?
+            .to_bytes()
+            .into()),
+    }
+}
+
+/// Ethereum signature of `digest` from a token's `r || s`: `s` is normalised
+/// to the lower half of the curve order (EIP-2) and the recovery id is found
+/// by recovering `public_key` with each candidate.
+fn recoverable_signature(
+    secp: &Secp256k1<All>,
+    public_key: &[u8],
+    digest: &[u8],
+    raw_signature: &[u8; 64],
+) -> Result<Signature, Error> {
+    let mut signature = secp256k1::ecdsa::Signature::from_compact(raw_signature)
+        .map_err(|err| Error::msg(format!("Invalid token signature: {}", err)))?;
+    signature.normalize_s();
+    let compact = signature.serialize_compact();
+
+    let message = Message::from_digest_slice(digest)
+        .map_err(|err| Error::msg(format!("Invalid digest: {}", err)))?;
+    let public_key = PublicKey::from_slice(public_key)?;
+    for recovery_id in 0..2 {
+        let recoverable =
+            RecoverableSignature::from_compact(&compact, RecoveryId::from_i32(recovery_id)?)?;
+        if secp.recover_ecdsa(&message, &recoverable).ok() == Some(public_key) {
+            return Ok(Signature {
+                v: recovery_id as u64,
+                r: H256::from_slice(&compact[..32]),
+                s: H256::from_slice(&compact[32..]),
+            });
+        }
+    }
+    Err(Error::msg("Could not determine the signature recovery id"))
+}
+
+/// P-256 signature of `digest` from a token's `r || s`, low-S normalised and
+/// checked against `public_key`.
+fn p256_signature(
+    public_key: &[u8],
+    digest: &[u8],
+    raw_signature: &[u8; 64],
+) -> Result<p256::ecdsa::Signature, Error> {
+    let signature = p256::ecdsa::Signature::from_slice(raw_signature)
+        .map_err(|err| Error::msg(format!("Invalid token signature: {}", err)))?;
+    let signature = signature.normalize_s().unwrap_or(signature);
+    let verifying_key = p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)
+        .map_err(|err| Error::msg(format!("Invalid P-256 public key: {}", err)))?;
+    verifying_key
+        .verify_prehash(digest, &signature)
+        .map_err(|_| Error::msg("Token signature does not verify"))?;
+    Ok(signature)
+}
+
+impl Drop for Pkcs11KeyStore {
+    fn drop(&mut self) {
+        if let Some(close_session) = self.list().c_close_session {
+            unsafe {
+                close_session(*self.session.lock().unwrap());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::signature::hazmat::PrehashSigner;
+    use rand_core::OsRng;
+    use sha2::{Digest, Sha256};
+    use web3::{
+        signing::{keccak256, recover},
+        types::U256,
+    };
+
+    const SECP256K1_ORDER: &str =
+        "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141";
+    const P256_ORDER: &str = "ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551";
+
+    /// `r || (n - s)`, the other valid encoding of the same signature.
+    fn high_s(compact: &[u8; 64], order: &str) -> [u8; 64] {
+        let s = U256::from_big_endian(&compact[32..]);
+        let mut flipped = *compact;
+        (U256::from_str_radix(order, 16).unwrap() - s).to_big_endian(&mut flipped[32..]);
+        flipped
+    }
+
+    #[test]
+    fn refuses_extractable_keys() {
+        assert!(check_non_extractable("bridge", &[1], &[0]).is_ok());
+        for (sensitive, extractable) in [
+            (&[0u8][..], &[0u8][..]),
+            (&[1], &[1]),
+            (&[0], &[1]),
+            (&[], &[0]),
+            (&[1], &[]),
+        ] {
+            assert!(check_non_extractable("bridge", sensitive, extractable).is_err());
+        }
+    }
+
+    #[test]
+    fn recovers_v_from_token_signatures() {
+        let secp = Secp256k1::new();
+        let secret_key = secp256k1::SecretKey::from_slice(&keccak256(b"token key")).unwrap();
+        let public_key = PublicKey::from_secret_key(&secp, &secret_key).serialize_uncompressed();
+        let address = public_key_address(&public_key);
+        let mut recovery_ids = std::collections::HashSet::new();
+        for i in 0..16u8 {
+            let digest = keccak256(&[i]);
+            let message = Message::from_digest_slice(&digest).unwrap();
+            let compact = secp.sign_ecdsa(&message, &secret_key).serialize_compact();
+            for raw in [compact, high_s(&compact, SECP256K1_ORDER)] {
+                let signature = recoverable_signature(&secp, &public_key, &digest, &raw).unwrap();
+                assert_eq!(signature.s.as_bytes(), &compact[32..]);
+                let rs = [signature.r.as_bytes(), signature.s.as_bytes()].concat();
+                assert_eq!(recover(&digest, &rs, signature.v as i32).unwrap(), address);
+                recovery_ids.insert(signature.v);
+            }
+        }
+        assert_eq!(recovery_ids.len(), 2);
+
+        let digest = keccak256(b"other key");
+        let message = Message::from_digest_slice(&digest).unwrap();
+        let compact = secp.sign_ecdsa(&message, &secret_key).serialize_compact();
+        let other_key = secp256k1::SecretKey::from_slice(&keccak256(b"other key")).unwrap();
+        let other_public_key =
+            PublicKey::from_secret_key(&secp, &other_key).serialize_uncompressed();
+        assert!(recoverable_signature(&secp, &other_public_key, &digest, &compact).is_err());
+    }
+
+    #[test]
+    fn normalises_p256_token_signatures() {
+        let signing_key = p256::ecdsa::SigningKey::random(&mut OsRng);
+        let public_key = p256::ecdsa::VerifyingKey::from(&signing_key)
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec();
+        let digest = Sha256::digest(b"pkcs11 p256");
+        let signature: p256::ecdsa::Signature = signing_key.sign_prehash(&digest).unwrap();
+        let signature = signature.normalize_s().unwrap_or(signature);
+        let compact: [u8; 64] = signature.to_bytes().into();
+        for raw in [compact, high_s(&compact, P256_ORDER)] {
+            assert_eq!(
+                p256_signature(&public_key, &digest, &raw).unwrap(),
+                signature
+            );
+        }
+        let other_digest = Sha256::digest(b"other message");
+        assert!(p256_signature(&public_key, &other_digest, &compact).is_err());
+    }
+
+    #[test]
+    fn accepts_raw_and_der_signatures() {
+        let secp = Secp256k1::new();
+        let secret_key = secp256k1::SecretKey::from_slice(&keccak256(b"token key")).unwrap();
+        let message = Message::from_digest_slice(&keccak256(b"der")).unwrap();
+        let signature = secp.sign_ecdsa(&message, &secret_key);
+        let compact = signature.serialize_compact();
+        assert_eq!(
+            compact_signature(Curve::Secp256k1, &compact).unwrap(),
+            compact
+        );
+        assert_eq!(
+            compact_signature(Curve::Secp256k1, &signature.serialize_der()).unwrap(),
+            compact
+        );
+
+        let signing_key = p256::ecdsa::SigningKey::random(&mut OsRng);
+        let signature: p256::ecdsa::Signature =
+            signing_key.sign_prehash(&Sha256::digest(b"der")).unwrap();
+        let compact: [u8; 64] = signature.to_bytes().into();
+        assert_eq!(
+            compact_signature(Curve::P256, signature.to_der().as_bytes()).unwrap(),
+            compact
+        );
+        assert!(compact_signature(Curve::P256, &compact[..63]).is_err());
+        assert!(compact_signature(Curve::Secp256k1, &[0x30, 0x02, 0x00, 0x00]).is_err());
+    }
+
+    /// Token from the module docs, or `None` when `PKCS11_MODULE` is unset.
+    fn softhsm_store(labels: &[&str]) -> Option<Pkcs11KeyStore> {
+        let module = std::env::var("PKCS11_MODULE").ok()?;
+        let pin = std::env::var("PKCS11_PIN").unwrap_or_else(|_| "1234".to_string());
+        let labels: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+        Some(Pkcs11KeyStore::load(&module, None, &pin, &labels).unwrap())
+    }
+
+    #[test]
+    #[ignore = "needs a SoftHSMv2 token, see the module docs"]
+    fn pkcs11_signs_secp256k1_with_recoverable_v() {
+        let Some(store) = softhsm_store(&["bridge"]) else {
+            return;
+        };
+        let digest = keccak256(b"pkcs11 secp256k1");
+        let signature = store.sign_digest("bridge", &digest).unwrap();
+        let rs = [signature.r.as_bytes(), signature.s.as_bytes()].concat();
+        let recovered = recover(&digest, &rs, signature.v as i32).unwrap();
+        assert_eq!(recovered, store.address("bridge").unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs a SoftHSMv2 token, see the module docs"]
+    fn pkcs11_signs_p256() {
+        let Some(store) = softhsm_store(&["bridge-p256"]) else {
+            return;
+        };
+        assert_eq!(store.curve("bridge-p256").unwrap(), Curve::P256);
+        let digest = Sha256::digest(b"pkcs11 p256");
+        let signature = store.sign_p256("bridge-p256", &digest).unwrap();
+        let public_key = store.public_key("bridge-p256").unwrap();
+        p256::ecdsa::VerifyingKey::from_sec1_bytes(&public_key)
+            .unwrap()
+            .verify_prehash(&digest, &signature)
+            .unwrap();
+        assert!(store.sign_digest("bridge-p256", &digest).is_err());
+    }
+}
@@ -0,0 +1,454 @@
+use crate::utils::{
+    app_state::AppState,
+    eip712::TypedData,
+    hd_wallet::ETH_ACCOUNT_PATH,
+    hsm_utils::{
+        decode_signed_tx, sign_message, sign_transaction_request, sign_typed_data, DecodeTxRequest,
+        TxRequest, TypedDataRequest,
+    },
+    key_store::KeyStore,
+};
+use anyhow::Error;
+use p256::ecdsa::{signature::Verifier, Signature};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use web3::types::{AccessList, Address, Bytes, H256, U256, U64};
+
+/// Operation enrolled clients need to be allowed to call `/rpc`.
+pub const RPC_OPERATION: &str = "rpc";
+/// HD accounts `m/44'/60'/0'/0/i` offered when `RPC_HD_ACCOUNTS` is unset.
+const DEFAULT_HD_ACCOUNTS: u32 = 10;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(err: anyhow::Error) -> Self {
+        RpcError::new(SERVER_ERROR, err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for RpcError {
+    fn from(err: serde_json::Error) -> Self {
+        RpcError::new(SERVER_ERROR, err.to_string())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// Transaction object of `eth_signTransaction`. The signer has no network
+/// access, so `nonce`, `gas` and `chainId` must be given.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RpcTransaction {
+    from: Address,
+    to: Option<Address>,
+    nonce: Option<U256>,
+    #[serde(alias = "gasLimit")]
+    gas: Option<U256>,
+    gas_price: Option<U256>,
+    max_fee_per_gas: Option<U256>,
+    max_priority_fee_per_gas: Option<U256>,
+    value: Option<U256>,
+    data: Option<Bytes>,
+    input: Option<Bytes>,
+    chain_id: Option<U256>,
+    #[serde(rename = "type")]
+    tx_type: Option<U64>,
+    access_list: Option<AccessList>,
+}
+
+impl RpcTransaction {
+    fn into_request(self) -> Result<(TxRequest, Vec<u8>), RpcError> {
+        let required = |value: Option<U256>, field: &str| {
+            value.map(|value| value.to_string()).ok_or_else(|| {
+                RpcError::new(INVALID_PARAMS, format!("Transaction {} is required", field))
+            })
+        };
+        let tx_type = match self.tx_type {
+            Some(tx_type) => tx_type.as_u64(),
+            None if self.max_fee_per_gas.is_some() => 2,
+            None if self.access_list.is_some() => 1,
+            None => 0,
+        };
+        let tx = TxRequest {
+            chain_id: required(self.chain_id, "chainId")?,
+            to: self.to.map(|to| format!("{:?}", to)).unwrap_or_default(),
+            nonce: required(self.nonce, "nonce")?,
+            value: self.value.unwrap_or_default().to_string(),
+            gas: required(self.gas, "gas")?,
+            gas_price: self.gas_price.map(|p| p.to_string()).unwrap_or_default(),
+            tx_type: Some(tx_type.to_string()),
+            max_fee_per_gas: self.max_fee_per_gas.map(|fee| fee.to_string()),
+            max_priority_fee_per_gas: self.max_priority_fee_per_gas.map(|fee| fee.to_string()),
+            access_list: self.access_list,
+        };
+        let data = self.input.or(self.data).unwrap_or_default();
+        Ok((tx, data.0))
+    }
+}
+
+/// Result of `eth_signTransaction`, shaped like Clef's.
+#[derive(Debug, Serialize)]
+pub struct RpcSignedTransaction {
+    pub raw: Bytes,
+    pub tx: RpcTransactionResult,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcTransactionResult {
+    #[serde(rename = "type")]
+    pub tx_type: U64,
+    pub chain_id: U64,
+    pub nonce: U256,
+    pub gas: U256,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gas_price: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_fee_per_gas: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_priority_fee_per_gas: Option<U256>,
+    pub to: Option<Address>,
+    pub value: U256,
+    pub input: Bytes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_list: Option<AccessList>,
+    pub v: U64,
+    pub r: H256,
+    pub s: H256,
+    pub hash: H256,
+}
+
+impl RpcSignedTransaction {
+    fn new(raw: Bytes) -> Result<Self, Error> {
+        let signed = decode_signed_tx(&DecodeTxRequest {
+            raw_tx: format!("0x{}", hex::encode(&raw.0)),
+        })?;
+        let tx = signed.decoded;
+        Ok(RpcSignedTransaction {
+            tx: RpcTransactionResult {
+                tx_type: tx.tx_type.into(),
+                chain_id: tx.chain_id.into(),
+                nonce: tx.nonce,
+                gas: tx.gas,
+                gas_price: tx.gas_price,
+                max_fee_per_gas: tx.max_fee_per_gas,
+                max_priority_fee_per_gas: tx.max_priority_fee_per_gas,
+                to: tx.to,
+                value: tx.value,
+                input: tx.data,
+                access_list: (tx.tx_type != 0).then_some(tx.access_list),
+                v: signed.signature.v.into(),
+                r: signed.signature.r,
+                s: signed.signature.s,
+                hash: signed.tx_hash,
+            },
+            raw,
+        })
+    }
+}
+
+/// Check that `body` was signed by the enrolled client `client_id` allowed
+/// [`RPC_OPERATION`]. `signature` is the client's P-256 `r ‖ s` over
+/// `timestamp ‖ "." ‖ body`, and `timestamp` must be within the request age
+/// window of the sessions. Each signature is accepted once.
+pub fn authenticate(
+    state: &AppState,
+    client_id: &str,
+    timestamp: u64,
+    signature: &[u8],
+    body: &str,
+) -> Result<(), Error> {
+    let verifying_key = state.clients.verifying_key(client_id, RPC_OPERATION)?;
+    let signature = Signature::from_slice(signature)
+        .map_err(|err| Error::msg(format!("Invalid client signature: {}", err)))?;
+    verifying_key
+        .verify(format!("{}.{}", timestamp, body).as_bytes(), &signature)
+        .map_err(|_| Error::msg("Client signature does not match"))?;
+    // `s` and `n - s` both verify, so remember the low-s form
+    let signature = signature.normalize_s().unwrap_or(signature);
+    state.sessions.accept_once(
+        &format!("{}:{}", client_id, hex::encode(signature.to_bytes())),
+        timestamp,
+    )
+}
+
+/// Handle a JSON-RPC 2.0 request body, single or batch.
+pub fn handle_body(state: &AppState, body: &str) -> Value {
+    match serde_json::from_str::<Value>(body) {
+        Ok(Value::Array(requests)) if !requests.is_empty() => Value::Array(
+            requests
+                .into_iter()
+                .map(|request| handle_request(state, request))
+                .collect(),
+        ),
+        Ok(request) => handle_request(state, request),
+        Err(err) => response(
+            Value::Null,
+            Err(RpcError::new(PARSE_ERROR, format!("Parse error: {}", err))),
+        ),
+    }
+}
+
+fn handle_request(state: &AppState, request: Value) -> Value {
+    let request: RpcRequest = match serde_json::from_value(request) {
+        Ok(request) => request,
+        Err(err) => {
+            return response(
+                Value::Null,
+                Err(RpcError::new(
+                    INVALID_REQUEST,
+                    format!("Invalid request: {}", err),
+                )),
+            )
+        }
+    };
+    if request.jsonrpc != "2.0" {
+        let err = RpcError::new(INVALID_REQUEST, "Only JSON-RPC 2.0 is supported");
+        return response(request.id, Err(err));
+    }
+    println!("JSON-RPC call: {}", request.method);
+    let result = call(state, &request.method, request.params);
+    response(request.id, result)
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => serde_json::json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+    }
+}
+
+fn call(state: &AppState, method: &str, params: Value) -> Result<Value, RpcError> {
+    let key_store = state.key_store.as_ref();
+    match method {
+        "eth_accounts" | "account_list" => Ok(serde_json::to_value(accounts(key_store))?),
+        "eth_signTransaction" => {
+            let (tx,): (RpcTransaction,) = parse_params(params)?;
+            let key_id = key_id_for(key_store, tx.from)?;
+            let (tx, data) = tx.into_request()?;
+            let signed = sign_transaction_request(&tx, data, key_store, &key_id)?;
+            Ok(serde_json::to_value(RpcSignedTransaction::new(
+                signed.r_tx,
+            )?)?)
+        }
+        "eth_sign" => {
+            let (address, data): (Address, Bytes) = parse_params(params)?;
+            let key_id = key_id_for(key_store, address)?;
+            personal_sign(key_store, &key_id, &data.0)
+        }
+        "eth_signTypedData_v4" => {
+            let (address, typed_data): (Address, Value) = parse_params(params)?;
+            typed_sign(state, address, typed_data)
+        }
+        "account_signData" => {
+            let (content_type, address, data): (String, Address, Value) = parse_params(params)?;
+            match content_type.as_str() {
+                "text/plain" => {
+                    let data: Bytes = serde_json::from_value(data)
+                        .map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))?;
+                    let key_id = key_id_for(key_store, address)?;
+                    personal_sign(key_store, &key_id, &data.0)
+                }
+                "data/typed" => typed_sign(state, address, data),
+                other => Err(RpcError::new(
+                    INVALID_PARAMS,
+                    format!("Unsupported content type: {}", other),
+                )),
+            }
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Method not found: {}", method),
+        )),
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params)
+        .map_err(|err| RpcError::new(INVALID_PARAMS, format!("Invalid params: {}", err)))
+}
+
+/// Registered key ids followed by the first `RPC_HD_ACCOUNTS` accounts of an
+/// HD store, which derives any path on request.
+fn account_key_ids(key_store: &dyn KeyStore) -> Vec<String> {
+    let hd_accounts = dotenvy::var("RPC_HD_ACCOUNTS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(DEFAULT_HD_ACCOUNTS);
+    let mut key_ids = key_store.key_ids();
+    for index in 0..hd_accounts {
+        let path = format!("{}/{}", ETH_ACCOUNT_PATH, index);
+        if !key_ids.contains(&path) {
+            key_ids.push(path);
+        }
+    }
+    key_ids
+}
+
+fn accounts(key_store: &dyn KeyStore) -> Vec<Address> {
+    let mut addresses: Vec<Address> = Vec::new();
+    for key_id in account_key_ids(key_store) {
+        if let Ok(address) = key_store.address(&key_id) {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+    }
+    addresses
+}
+
+/// Key id whose active version signs for `address`.
+fn key_id_for(key_store: &dyn KeyStore, address: Address) -> Result<String, RpcError> {
+    account_key_ids(key_store)
+        .into_iter()
+        .find(|key_id| key_store.address(key_id).ok() == Some(address))
+        .ok_or_else(|| RpcError::new(SERVER_ERROR, format!("Unknown account: {:?}", address)))
+}
+
+fn personal_sign(key_store: &dyn KeyStore, key_id: &str, data: &[u8]) -> Result<Value, RpcError> {
+    let (signer_id, _) = key_store.pin_version(key_id)?;
+    let signature = sign_message(data, key_store, &signer_id)?;
+    Ok(serde_json::to_value(Bytes(signature.to_vec()))?)
+}
+
+/// `eth_signTypedData_v4` takes the document as an object or a JSON string.
+fn typed_sign(state: &AppState, address: Address, typed_data: Value) -> Result<Value, RpcError> {
+    let typed_data: TypedData = match typed_data {
+        Value::String(json) => serde_json::from_str(&json),
+        value => serde_json::from_value(value),
+    }
+    .map_err(|err| RpcError::new(INVALID_PARAMS, format!("Invalid typed data: {}", err)))?;
+    let request = TypedDataRequest {
+        key_id: key_id_for(state.key_store.as_ref(), address)?,
+        typed_data,
+    };
+    let signed = sign_typed_data(&request, &state.eip712_domains, state.key_store.as_ref())?;
+    Ok(serde_json::to_value(signed.signature)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{hd_wallet::HdKeyStore, key_store::InMemoryKeyStore};
+    use p256::ecdsa::{signature::Signer, SigningKey};
+    use std::{
+        sync::Arc,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    #[test]
+    fn finds_derived_hd_accounts() {
+        let store = HdKeyStore::from_mnemonic(
+            "abandon abandon abandon abandon abandon abandon \
+             abandon abandon abandon abandon abandon about",
+            "",
+        )
+        .unwrap();
+        let path = format!("{}/3", ETH_ACCOUNT_PATH);
+        let address = store.address(&path).unwrap();
+        assert!(accounts(&store).contains(&address));
+        assert_eq!(key_id_for(&store, address).unwrap(), path);
+    }
+
+    #[test]
+    fn returns_raw_and_decoded_transaction() {
+        let mut store = InMemoryKeyStore::new();
+        store
+            .insert_hex(
+                "default",
+                "4646464646464646464646464646464646464646464646464646464646464646",
+            )
+            .unwrap();
+        let tx: RpcTransaction = serde_json::from_value(serde_json::json!({
+            "from": "0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f",
+            "to": "0x3535353535353535353535353535353535353535",
+            "nonce": "0x9",
+            "gas": "0x5208",
+            "gasPrice": "0x4a817c800",
+            "value": "0xde0b6b3a7640000",
+            "chainId": "0x1"
+        }))
+        .unwrap();
+        let (request, data) = tx.into_request().unwrap();
+        let signed = sign_transaction_request(&request, data, &store, "default").unwrap();
+        let result = serde_json::to_value(RpcSignedTransaction::new(signed.r_tx).unwrap()).unwrap();
+        // EIP-155 example transaction
+        assert_eq!(
+            result["raw"],
+            "0xf86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"
+        );
+        assert_eq!(result["tx"]["nonce"], "0x9");
+        assert_eq!(result["tx"]["gasPrice"], "0x4a817c800");
+        assert_eq!(result["tx"]["v"], "0x25");
+        assert_eq!(
+            result["tx"]["hash"],
+            "0x33469b22e9f636356c4160a87eb19df52b7412e8eac32a4a55ffe88ea8350788"
+        );
+    }
+
+    #[test]
+    fn authenticates_each_signature_once() {
+        let state = AppState::for_tests(Arc::new(InMemoryKeyStore::new()), None);
+        let client = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let verifying_key = client.verifying_key().to_encoded_point(false);
+        state
+            .clients
+            .enroll(
+                "bridge",
+                "Bridge",
+                &hex::encode(verifying_key.as_bytes()),
+                vec![RPC_OPERATION.to_string()],
+            )
+            .unwrap();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"eth_accounts"}"#;
+        let sign = |timestamp: u64| -> Signature {
+            client.sign(format!("{}.{}", timestamp, body).as_bytes())
+        };
+
+        let signature = sign(timestamp);
+        authenticate(&state, "bridge", timestamp, &signature.to_bytes(), body).unwrap();
+        assert!(authenticate(&state, "bridge", timestamp, &signature.to_bytes(), body).is_err());
+        // The high-s form of the same signature is the same request
+        let (r, s) = signature.split_scalars();
+        let flipped = Signature::from_scalars(r, -s).unwrap();
+        assert_ne!(flipped, signature);
+        assert!(authenticate(&state, "bridge", timestamp, &flipped.to_bytes(), body).is_err());
+
+        let signature = sign(timestamp - 1);
+        assert!(authenticate(&state, "bridge", timestamp, &signature.to_bytes(), body).is_err());
+        authenticate(&state, "bridge", timestamp - 1, &signature.to_bytes(), body).unwrap();
+        assert!(authenticate(&state, "other", timestamp - 1, &signature.to_bytes(), body).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use crate::utils::eip712::{TypedData, TypedField};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use web3::types::Address;
+
+const SAFE_TX_FIELDS: [(&str, &str); 10] = [
+    ("to", "address"),
+    ("value", "uint256"),
+    ("data", "bytes"),
+    ("operation", "uint8"),
+    ("safeTxGas", "uint256"),
+    ("baseGas", "uint256"),
+    ("gasPrice", "uint256"),
+    ("gasToken", "address"),
+    ("refundReceiver", "address"),
+    ("nonce", "uint256"),
+];
+
+/// Version assumed when the caller does not give one.
+pub const DEFAULT_SAFE_VERSION: &str = "1.3.0";
+
+const ZERO_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
+
+fn zero() -> String {
+    "0".to_string()
+}
+
+fn zero_address() -> String {
+    ZERO_ADDRESS.to_string()
+}
+
+fn data() -> String {
+    "0x".to_string()
+}
+
+/// Safe `operation`: `CALL` or `DELEGATECALL`.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SafeOperation {
+    #[default]
+    Call,
+    DelegateCall,
+}
+
+/// `SafeTx` as hashed by `GnosisSafe.getTransactionHash`. Gas refund fields
+/// default to zero, i.e. no refund.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SafeTransaction {
+    pub to: String,
+    #[serde(default = "zero")]
+    pub value: String,
+    /// Call data, `0x` hex.
+    #[serde(default = "data")]
+    pub data: String,
+    #[serde(default)]
+    pub operation: SafeOperation,
+    #[serde(default = "zero")]
+    pub safe_tx_gas: String,
+    #[serde(default = "zero")]
+    pub base_gas: String,
+    #[serde(default = "zero")]
+    pub gas_price: String,
+    #[serde(default = "zero_address")]
+    pub gas_token: String,
+    #[serde(default = "zero_address")]
+    pub refund_receiver: String,
+    pub nonce: String,
+}
+
+impl SafeTransaction {
+    /// Typed-data document of the transaction for the Safe at `safe`. Safes
+    /// older than 1.3.0 leave `chainId` out of their domain.
+    pub fn typed_data(
+        &self,
+        chain_id: u64,
+        safe: Address,
+        safe_version: &str,
+    ) -> Result<TypedData, Error> {
+        let domain = if binds_chain_id(safe_version)? {
+            json!({ "chainId": chain_id, "verifyingContract": format!("{:?}", safe) })
+        } else {
+            json!({ "verifyingContract": format!("{:?}", safe) })
+        };
+        let operation = match self.operation {
+            SafeOperation::Call => 0,
+            SafeOperation::DelegateCall => 1,
+        };
+        let message = json!({
+            "to": self.to,
+            "value": self.value,
+            "data": self.data,
+            "operation": operation,
+            "safeTxGas": self.safe_tx_gas,
+            "baseGas": self.base_gas,
+            "gasPrice": self.gas_price,
+            "gasToken": self.gas_token,
+            "refundReceiver": self.refund_receiver,
+            "nonce": self.nonce,
+        });
+        let fields = SAFE_TX_FIELDS
+            .iter()
+            .map(|(name, kind)| TypedField {
+                name: name.to_string(),
+                kind: kind.to_string(),
+            })
+            .collect();
+        Ok(TypedData {
+            types: BTreeMap::from([("SafeTx".to_string(), fields)]),
+            primary_type: "SafeTx".to_string(),
+            domain,
+            message,
+        })
+    }
+}
+
+/// Whether a Safe of `version` (e.g. `1.3.0`) includes `chainId` in its domain.
+/// Versions before 1.0.0 hash `dataGas` instead of `baseGas` and are refused.
+fn binds_chain_id(version: &str) -> Result<bool, Error> {
+    let invalid = || Error::msg(format!("Invalid Safe version: {}", version));
+    let mut parts = version.trim_start_matches('v').split('.');
+    let major: u32 = parts
+        .next()
+        .and_then(|part| part.parse().ok())
+        .ok_or_else(invalid)?;
+    let minor: u32 = parts
+        .next()
+        .and_then(|part| part.parse().ok())
+        .ok_or_else(invalid)?;
+    if major < 1 {
+        return Err(Error::msg(format!(
+            "Unsupported Safe version: {}, at least 1.0.0 is required",
+            version
+        )));
+    }
+    Ok((major, minor) >= (1, 3))
+}
+
+/// How the owner signature is produced, which Safe tells apart by `v`.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SafeSignatureType {
+    /// `safeTxHash` signed directly, `v` 27 or 28.
+    #[default]
+    Eip712,
+    /// `safeTxHash` signed with the `eth_sign` prefix, `v` 31 or 32.
+    EthSign,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use web3::types::H256;
+
+    const SAFE: &str = "0x1111111111111111111111111111111111111111";
+
+    fn safe_tx() -> SafeTransaction {
+        serde_json::from_value(json!({
+            "to": "0x3535353535353535353535353535353535353535",
+            "value": "1000000000000000000",
+            "data": "0xa9059cbb",
+            "nonce": "7",
+        }))
+        .unwrap()
+    }
+
+    fn safe_tx_hash(version: &str) -> Result<H256, Error> {
+        safe_tx()
+            .typed_data(1, Address::from_str(SAFE).unwrap(), version)?
+            .digest()
+    }
+
+    #[test]
+    fn hashes_safe_tx_for_chain_bound_domain() {
+        let typed_data = safe_tx()
+            .typed_data(1, Address::from_str(SAFE).unwrap(), "1.3.0")
+            .unwrap();
+        // SAFE_TX_TYPEHASH of GnosisSafe
+        assert_eq!(
+            H256(typed_data.type_hash("SafeTx").unwrap()),
+            H256::from_str("0xbb8310d486368db6bd6f849402fdd73ad53d316b5a4b2644ad6efe0f941286d8")
+                .unwrap()
+        );
+        assert_eq!(
+            H256(typed_data.domain_separator().unwrap()),
+            H256::from_str("0xf0dcfe86ad4a409690a57dbaae9b1e14c5ea1750a48271a0a3a6037a8100624d")
+                .unwrap()
+        );
+        assert_eq!(
+            safe_tx_hash("1.3.0").unwrap(),
+            H256::from_str("0xf0d708ae149b3d7037cbd8f36f1cfe6c0b8983393a7105fb4ee89687cb518851")
+                .unwrap()
+        );
+        assert_eq!(
+            safe_tx_hash("v1.4.1").unwrap(),
+            safe_tx_hash("1.3.0").unwrap()
+        );
+    }
+
+    #[test]
+    fn hashes_safe_tx_for_legacy_domain() {
+        let expected =
+            H256::from_str("0x8895a020c013a2311e0d42b2dc9a71a58ccb50c8ca23b98574a5f26715a8455e")
+                .unwrap();
+        assert_eq!(safe_tx_hash("1.1.1").unwrap(), expected);
+        assert_eq!(safe_tx_hash("1.0.0").unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_pre_1_0_versions() {
+        for version in ["0.1.0", "v0.9", "1", "one.two"] {
+            assert!(safe_tx_hash(version).is_err(), "{}", version);
+        }
+    }
+}
@@ -0,0 +1,445 @@
+use crate::utils::{
+    app_state::AppState,
+    encryption,
+    key_store::{InMemoryKeyStore, KeyStore},
+};
+use anyhow::Error;
+use axum::{
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+    Json,
+};
+use blahaj::{Share, Sharks};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    io::BufRead,
+    sync::{Mutex, PoisonError, RwLock},
+};
+use web3::{signing::Signature, types::Address};
+use zeroize::Zeroizing;
+
+/// Bytes of the master key, and so of every share after its x coordinate.
+const MASTER_KEY_LEN: usize = 32;
+
+/// Key file encrypted under a master key that is split into Shamir shares.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SealedKeys {
+    pub threshold: u8,
+    pub shares: u8,
+    pub ciphertext: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SealStatus {
+    pub sealed: bool,
+    pub threshold: u8,
+    pub shares: u8,
+    pub progress: usize,
+}
+
+/// Encrypt `keys_json` under a fresh master key and split the master key into
+/// `shares` hex shares, any `threshold` of which unseal it.
+pub fn seal_keys(
+    keys_json: &str,
+    threshold: u8,
+    shares: u8,
+) -> Result<(SealedKeys, Vec<String>), Error> {
+    if threshold == 0 || threshold > shares {
+        return Err(Error::msg(
+            "Threshold must be between 1 and the share count",
+        ));
+    }
+    InMemoryKeyStore::from_json(keys_json)?;
+
+    let mut master_key = Zeroizing::new([0u8; MASTER_KEY_LEN]);
+    OsRng.fill_bytes(master_key.as_mut());
+    let ciphertext = encryption::encrypt(keys_json, master_key.as_ref());
+    let key_shares = Sharks(threshold)
+        .dealer(master_key.as_ref())
+        .take(shares as usize)
+        .map(|share| hex::encode(Vec::from(&share)))
+        .collect();
+    let sealed = SealedKeys {
+        threshold,
+        shares,
+        ciphertext: hex::encode(ciphertext),
+    };
+    Ok((sealed, key_shares))
+}
+
+/// Recover the master key from `shares` and decrypt the sealed key file.
+pub fn unseal_keys(sealed: &SealedKeys, shares: &[Share]) -> Result<Zeroizing<String>, Error> {
+    let mut seen = HashSet::new();
+    for share in shares {
+        let share = Zeroizing::new(Vec::from(share));
+        if share.len() != 1 + MASTER_KEY_LEN {
+            return Err(Error::msg("Invalid share length"));
+        }
+        if !seen.insert(share[0]) {
+            return Err(Error::msg("Duplicate share"));
+        }
+    }
+    let master_key = Zeroizing::new(
+        Sharks(sealed.threshold)
+            .recover(shares)
+            .map_err(|err| Error::msg(format!("Error recovering master key: {}", err)))?,
+    );
+    let plaintext = encryption::try_decrypt(&hex::decode(&sealed.ciphertext)?, &master_key)
+        .map_err(|_| Error::msg("Shares do not unseal this key file"))?;
+    Ok(Zeroizing::new(String::from_utf8(plaintext)?))
+}
+
+/// Decrypt the sealed key file with the current `shares` and seal it again
+/// under a fresh master key, so the current shares no longer unseal it.
+pub fn resplit_keys(
+    sealed: &SealedKeys,
+    shares: &[Share],
+    threshold: u8,
+    share_count: u8,
+) -> Result<(SealedKeys, Vec<String>), Error> {
+    let keys_json = unseal_keys(sealed, shares)?;
+    seal_keys(&keys_json, threshold, share_count)
+}
+
+fn parse_share(share: &str) -> Result<Share, Error> {
+    let bytes = Zeroizing::new(hex::decode(share.trim().trim_start_matches("0x"))?);
+    if bytes.len() != 1 + MASTER_KEY_LEN {
+        return Err(Error::msg("Invalid share length"));
+    }
+    Share::try_from(bytes.as_slice()).map_err(|err| Error::msg(format!("Invalid share: {}", err)))
+}
+
+/// Key store that refuses every operation until enough operators submitted
+/// their shares of the master key.
+pub struct SealedKeyStore {
+    sealed: SealedKeys,
+    unsealed: RwLock<Option<InMemoryKeyStore>>,
+    pending_shares: Mutex<Vec<Share>>,
+}
+
+impl SealedKeyStore {
+    pub fn new(sealed: SealedKeys) -> Self {
+        SealedKeyStore {
+            sealed,
+            unsealed: RwLock::new(None),
+            pending_shares: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| Error::msg(format!("Error reading sealed keys {}: {}", path, err)))?;
+        let sealed: SealedKeys = serde_json::from_str(&content)
+            .map_err(|err| Error::msg(format!("Error parsing sealed keys {}: {}", path, err)))?;
+        Ok(Self::new(sealed))
+    }
+
+    pub fn from_env() -> Result<Self, Error> {
+        let path = dotenvy::var("SEALED_KEYS_PATH")
+            .map_err(|_| Error::msg("Sealed keys path not found"))?;
+        Self::load(&path)
+    }
+
+    pub fn is_sealed(&self) -> Result<bool, Error> {
+        Ok(self.unsealed.read().map_err(poisoned)?.is_none())
+    }
+
+    pub fn status(&self) -> Result<SealStatus, Error> {
+        Ok(SealStatus {
+            sealed: self.is_sealed()?,
+            threshold: self.sealed.threshold,
+            shares: self.sealed.shares,
+            progress: self.pending_shares.lock().map_err(poisoned)?.len(),
+        })
+    }
+
+    /// Add one operator share. Once the threshold is reached the key file is
+    /// decrypted; a failed attempt discards every pending share.
+    pub fn submit_share(&self, share: &str) -> Result<SealStatus, Error> {
+        if !self.is_sealed()? {
+            return Err(Error::msg("HSM is already unsealed"));
+        }
+        let share = parse_share(share)?;
+        let mut pending_shares = self.pending_shares.lock().map_err(poisoned)?;
+        let share_bytes = Vec::from(&share);
+        if pending_shares
+            .iter()
+            .any(|pending| Vec::from(pending)[0] == share_bytes[0])
+        {
+            return Err(Error::msg("Share was already submitted"));
+        }
+        pending_shares.push(share);
+
+        if pending_shares.len() >= self.sealed.threshold as usize {
+            let result = unseal_keys(&self.sealed, &pending_shares)
+                .and_then(|keys_json| InMemoryKeyStore::from_json(&keys_json));
+            pending_shares.clear();
+            let store = result?;
+            println!("HSM unsealed with keys: {:?}", store.key_ids());
+            *self.unsealed.write().map_err(poisoned)? = Some(store);
+        }
+        drop(pending_shares);
+        self.status()
+    }
+
+    /// Drop the decrypted keys and any pending shares immediately, even when
+    /// a panic poisoned the locks.
+    pub fn seal(&self) {
+        self.pending_shares
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        *self
+            .unsealed
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        println!("HSM sealed");
+    }
+
+    fn with_store<T>(
+        &self,
+        f: impl FnOnce(&InMemoryKeyStore) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        match self.unsealed.read().map_err(poisoned)?.as_ref() {
+            Some(store) => f(store),
+            None => Err(Error::msg("HSM is sealed")),
+        }
+    }
+}
+
+impl KeyStore for SealedKeyStore {
+    fn key_ids(&self) -> Vec<String> {
+        self.with_store(|store| Ok(store.key_ids()))
+            .unwrap_or_default()
+    }
+
+    fn address(&self, key_id: &str) -> Result<Address, Error> {
+        self.with_store(|store| store.address(key_id))
+    }
+
+    fn public_key(&self, key_id: &str) -> Result<Vec<u8>, Error> {
+        self.with_store(|store| store.public_key(key_id))
+    }
+
+    fn sign_digest(&self, key_id: &str, digest: &[u8]) -> Result<Signature, Error> {
+        self.with_store(|store| store.sign_digest(key_id, digest))
+    }
+}
+
+fn poisoned<T>(_: PoisonError<T>) -> Error {
+    Error::msg("Seal state lock poisoned")
+}
+
+/// Reject requests while the HSM is sealed.
+pub async fn require_unsealed<B>(
+    State(state): State<AppState>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    // a poisoned seal state counts as sealed
+    if state
+        .seal
+        .as_ref()
+        .is_some_and(|seal| seal.is_sealed().unwrap_or(true))
+    {
+        let json_response = serde_json::json!({
+            "status": "fail",
+            "data": "HSM is sealed"
+        });
+        return Err((StatusCode::SERVICE_UNAVAILABLE, Json(json_response)));
+    }
+    Ok(next.run(req).await)
+}
+
+const CLI_USAGE: &str = "usage:
+  hsm-jaamlong seal init <keys.json> <sealed.json> <threshold> <shares>
+  hsm-jaamlong seal resplit <sealed.json> <threshold> <shares>   (current shares on stdin, one per line)";
+
+/// `seal` subcommand: create a sealed key file, or re-key an existing one and
+/// split the new master key into a fresh set of shares.
+pub fn run_cli(args: &[String]) -> Result<(), Error> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (sealed_path, sealed, key_shares, threshold, shares) = match args.as_slice() {
+        ["init", keys_path, sealed_path, threshold, shares] => {
+            let keys_json = Zeroizing::new(std::fs::read_to_string(keys_path)?);
+            let (threshold, shares) = (threshold.parse()?, shares.parse()?);
+            let (sealed, key_shares) = seal_keys(&keys_json, threshold, shares)?;
+            (*sealed_path, sealed, key_shares, threshold, shares)
+        }
+        ["resplit", sealed_path, threshold, shares] => {
+            let content = std::fs::read_to_string(sealed_path)?;
+            let sealed: SealedKeys = serde_json::from_str(&content)?;
+            eprintln!("Enter {} current shares, one per line:", sealed.threshold);
+            let current_shares = std::io::stdin()
+                .lock()
+                .lines()
+                .take(sealed.threshold as usize)
+                .map(|line| parse_share(&line?))
+                .collect::<Result<Vec<Share>, Error>>()?;
+            let (threshold, shares) = (threshold.parse()?, shares.parse()?);
+            let (sealed, key_shares) = resplit_keys(&sealed, &current_shares, threshold, shares)?;
+            (*sealed_path, sealed, key_shares, threshold, shares)
+        }
+        _ => return Err(Error::msg(CLI_USAGE)),
+    };
+
+    std::fs::write(sealed_path, serde_json::to_vec_pretty(&sealed)?)?;
+    println!(
+        "Sealed keys written to {} ({} of {} shares unseal):",
+        sealed_path, threshold, shares
+    );
+    for share in key_shares {
+        println!("{}", share);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    const KEYS_JSON: &str =
+        r#"{"default": "0x4646464646464646464646464646464646464646464646464646464646464646"}"#;
+
+    fn shares(key_shares: &[String]) -> Vec<Share> {
+        key_shares
+            .iter()
+            .map(|share| parse_share(share).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn recovers_with_threshold_shares() {
+        let (sealed, key_shares) = seal_keys(KEYS_JSON, 3, 5).unwrap();
+        assert_eq!(key_shares.len(), 5);
+        for subset in [[0, 1, 2], [2, 3, 4], [4, 0, 3]] {
+            let subset: Vec<String> = subset.iter().map(|i| key_shares[*i].clone()).collect();
+            let keys_json = unseal_keys(&sealed, &shares(&subset)).unwrap();
+            assert_eq!(keys_json.as_str(), KEYS_JSON);
+        }
+        assert_eq!(
+            unseal_keys(&sealed, &shares(&key_shares)).unwrap().as_str(),
+            KEYS_JSON
+        );
+    }
+
+    #[test]
+    fn fails_below_threshold() {
+        let (sealed, key_shares) = seal_keys(KEYS_JSON, 3, 5).unwrap();
+        assert!(unseal_keys(&sealed, &shares(&key_shares[..2])).is_err());
+        assert!(unseal_keys(&sealed, &[]).is_err());
+        // Shares of another seal do not unseal this one
+        let (_, other_shares) = seal_keys(KEYS_JSON, 3, 5).unwrap();
+        assert!(unseal_keys(&sealed, &shares(&other_shares[..3])).is_err());
+        assert!(seal_keys(KEYS_JSON, 0, 5).is_err());
+        assert!(seal_keys(KEYS_JSON, 6, 5).is_err());
+        assert!(seal_keys("{}", 1, 1).is_ok());
+        assert!(seal_keys(r#"{"default": "0x00"}"#, 1, 1).is_err());
+    }
+
+    #[test]
+    fn rejects_duplicate_and_malformed_shares() {
+        let (sealed, key_shares) = seal_keys(KEYS_JSON, 2, 3).unwrap();
+        let duplicate = shares(&[key_shares[0].clone(), key_shares[0].clone()]);
+        let err = unseal_keys(&sealed, &duplicate).unwrap_err();
+        assert!(err.to_string().contains("Duplicate share"));
+
+        let truncated = &key_shares[1][..key_shares[1].len() - 2];
+        assert!(parse_share(truncated).is_err());
+        assert!(parse_share(&format!("{}00", key_shares[1])).is_err());
+        assert!(parse_share("not hex").is_err());
+        assert!(parse_share(&format!("0x{}", key_shares[1])).is_ok());
+        let short = Share::try_from(&hex::decode(truncated).unwrap()[..]).unwrap();
+        let mixed = vec![parse_share(&key_shares[0]).unwrap(), short];
+        let err = unseal_keys(&sealed, &mixed).unwrap_err();
+        assert!(err.to_string().contains("Invalid share length"));
+    }
+
+    #[test]
+    fn resplit_invalidates_old_shares() {
+        let (sealed, old_shares) = seal_keys(KEYS_JSON, 2, 3).unwrap();
+        let (resealed, new_shares) =
+            resplit_keys(&sealed, &shares(&old_shares[..2]), 3, 4).unwrap();
+        assert_eq!((resealed.threshold, resealed.shares), (3, 4));
+        assert_ne!(resealed.ciphertext, sealed.ciphertext);
+        assert!(unseal_keys(&resealed, &shares(&old_shares)).is_err());
+        assert_eq!(
+            unseal_keys(&resealed, &shares(&new_shares[1..]))
+                .unwrap()
+                .as_str(),
+            KEYS_JSON
+        );
+        assert!(resplit_keys(&sealed, &shares(&old_shares[..1]), 2, 3).is_err());
+    }
+
+    #[test]
+    fn unseals_after_threshold_submissions() {
+        let (sealed, key_shares) = seal_keys(KEYS_JSON, 2, 3).unwrap();
+        let store = SealedKeyStore::new(sealed);
+        assert!(store.address("default").is_err());
+        assert!(store.key_ids().is_empty());
+
+        let status = store.submit_share(&key_shares[0]).unwrap();
+        assert!(status.sealed);
+        assert_eq!(status.progress, 1);
+        assert!(store.submit_share(&key_shares[0]).is_err());
+        assert!(store.submit_share("00").is_err());
+        assert_eq!(store.status().unwrap().progress, 1);
+
+        let status = store.submit_share(&key_shares[2]).unwrap();
+        assert!(!status.sealed);
+        assert_eq!(status.progress, 0);
+        assert_eq!(store.key_ids(), vec!["default"]);
+        assert!(store.sign_digest("default", &[1u8; 32]).is_ok());
+        assert!(store.submit_share(&key_shares[1]).is_err());
+
+        store.seal();
+        assert!(store.is_sealed().unwrap());
+        assert!(store.sign_digest("default", &[1u8; 32]).is_err());
+    }
+
+    #[test]
+    fn failed_unseal_discards_pending_shares() {
+        let (sealed, key_shares) = seal_keys(KEYS_JSON, 2, 3).unwrap();
+        let (_, other_shares) = seal_keys(KEYS_JSON, 2, 3).unwrap();
+        let store = SealedKeyStore::new(sealed);
+        store.submit_share(&key_shares[0]).unwrap();
+        assert!(store.submit_share(&other_shares[1]).is_err());
+        let status = store.status().unwrap();
+        assert!(status.sealed);
+        assert_eq!(status.progress, 0);
+        store.submit_share(&key_shares[1]).unwrap();
+        assert!(!store.submit_share(&key_shares[2]).unwrap().sealed);
+    }
+
+    #[tokio::test]
+    async fn middleware_refuses_requests_while_sealed() {
+        let (sealed, key_shares) = seal_keys(KEYS_JSON, 1, 1).unwrap();
+        let seal = Arc::new(SealedKeyStore::new(sealed));
+        let state = AppState::for_tests(seal.clone(), Some(seal.clone()));
+        let app = Router::new()
+            .route("/", get(|| async { "signed" }))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_unsealed,
+            ))
+            .with_state(state);
+        let request = || Request::builder().uri("/").body(Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        seal.submit_share(&key_shares[0]).unwrap();
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        seal.seal();
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,404 @@
+use crate::utils::key_store::{public_key_address, InMemoryKeyStore};
+use aes::cipher::{KeyIvInit, StreamCipher};
+use anyhow::Error;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::Deserialize;
+use std::{path::Path, str::FromStr};
+use subtle::ConstantTimeEq;
+use web3::{signing, types::Address};
+use zeroize::Zeroizing;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+/// Largest scrypt working memory (`128 r (n + p)` bytes) a keystore may ask
+/// for; geth's standard params need 256 MiB.
+const MAX_SCRYPT_MEMORY: u64 = 1 << 30;
+
+/// Ethereum Web3 Secret Storage (V3) keystore file.
+#[derive(Debug, Deserialize)]
+pub struct KeystoreV3 {
+    #[serde(alias = "Crypto")]
+    pub crypto: KeystoreCrypto,
+    pub id: Option<String>,
+    pub address: Option<String>,
+    pub version: u8,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeystoreCrypto {
+    pub cipher: String,
+    pub cipherparams: CipherParams,
+    pub ciphertext: String,
+    #[serde(flatten)]
+    pub kdf: KdfParams,
+    pub mac: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CipherParams {
+    pub iv: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kdf", content = "kdfparams", rename_all = "lowercase")]
+pub enum KdfParams {
+    Scrypt {
+        dklen: usize,
+        n: u32,
+        r: u32,
+        p: u32,
+        salt: String,
+    },
+    Pbkdf2 {
+        dklen: usize,
+        c: u32,
+        prf: String,
+        salt: String,
+    },
+}
+
+/// Decrypt a V3 keystore with `passphrase`. The returned key only lives in memory.
+pub fn decrypt_keystore(keystore: &KeystoreV3, passphrase: &str) -> Result<SecretKey, Error> {
+    if keystore.version != 3 {
+        return Err(Error::msg(format!(
+            "Unsupported keystore version: {}",
+            keystore.version
+        )));
+    }
+    let crypto = &keystore.crypto;
+    if crypto.cipher != "aes-128-ctr" {
+        return Err(Error::msg(format!("Unsupported cipher: {}", crypto.cipher)));
+    }
+
+    let derived_key = derive_key(&crypto.kdf, passphrase)?;
+    if derived_key.len() < 32 {
+        return Err(Error::msg("Derived key must be at least 32 bytes"));
+    }
+    let mut ciphertext = hex::decode(&crypto.ciphertext)?;
+    let mac = hex::decode(&crypto.mac)?;
+    let expected_mac = signing::keccak256(&[&derived_key[16..32], ciphertext.as_slice()].concat());
+    if !bool::from(mac.ct_eq(&expected_mac)) {
+        return Err(Error::msg("Invalid keystore passphrase"));
+    }
+
+    let iv = hex::decode(&crypto.cipherparams.iv)?;
+    let mut cipher = Aes128Ctr::new_from_slices(&derived_key[..16], &iv)
+        .map_err(|err| Error::msg(format!("Invalid cipher params: {}", err)))?;
+    cipher.apply_keystream(&mut ciphertext);
+    let plaintext = Zeroizing::new(ciphertext);
+
+    let key = SecretKey::from_slice(&plaintext)
+        .map_err(|err| Error::msg(format!("Invalid keystore secret: {}", err)))?;
+    if let Some(address) = &keystore.address {
+        let expected = Address::from_str(address.trim_start_matches("0x"))
+            .map_err(|err| Error::msg(format!("Invalid keystore address: {}", err)))?;
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &key);
+        if public_key_address(&public_key.serialize_uncompressed()) != expected {
+            return Err(Error::msg(format!(
+                "Keystore key does not match its address {:?}",
+                expected
+            )));
+        }
+    }
+    Ok(key)
+}
+
+fn derive_key(kdf: &KdfParams, passphrase: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+    match kdf {
+        KdfParams::Scrypt {
+            dklen,
+            n,
+            r,
+            p,
+            salt,
+        } => {
+            if *r == 0 || *p == 0 {
+                return Err(Error::msg("Scrypt r and p must be positive"));
+            }
+            if !n.is_power_of_two() {
+                return Err(Error::msg("Scrypt n must be a power of two"));
+            }
+            // RFC 7914 bounds n below 2^(16 r); Go keystores do not enforce it
+            if u64::from(n.trailing_zeros()) >= 16 * u64::from(*r) {
+                return Err(Error::msg(format!(
+                    "Unsupported scrypt params: n {} must be below 2^(16 r) for r {}",
+                    n, r
+                )));
+            }
+            let memory = (u64::from(*n) + u64::from(*p))
+                .checked_mul(128 * u64::from(*r))
+                .filter(|memory| *memory <= MAX_SCRYPT_MEMORY);
+            if memory.is_none() {
+                return Err(Error::msg(format!(
+                    "Unsupported scrypt params: n {}, r {}, p {} need more than {} bytes",
+                    n, r, p, MAX_SCRYPT_MEMORY
+                )));
+            }
+            let params = scrypt::Params::new(n.trailing_zeros() as u8, *r, *p, *dklen)
+                .map_err(|err| Error::msg(format!("Invalid scrypt params: {}", err)))?;
+            let mut derived_key = Zeroizing::new(vec![0u8; *dklen]);
+            scrypt::scrypt(
+                passphrase.as_bytes(),
+                &hex::decode(salt)?,
+                &params,
+                &mut derived_key,
+            )
+            .map_err(|err| Error::msg(format!("Error deriving key: {}", err)))?;
+            Ok(derived_key)
+        }
+        KdfParams::Pbkdf2 {
+            dklen,
+            c,
+            prf,
+            salt,
+        } => {
+            if prf != "hmac-sha256" {
+                return Err(Error::msg(format!("Unsupported prf: {}", prf)));
+            }
+            let mut derived_key = Zeroizing::new(vec![0u8; *dklen]);
+            pbkdf2::pbkdf2_hmac::<sha2::Sha256>(
+                passphrase.as_bytes(),
+                &hex::decode(salt)?,
+                *c,
+                &mut derived_key,
+            );
+            Ok(derived_key)
+        }
+    }
+}
+
+/// Read the unlock passphrase from `KEYSTORE_PASSWORD_FILE`, or prompt on the
+/// TTY when it is unset or empty.
+pub fn read_passphrase() -> Result<Zeroizing<String>, Error> {
+    match dotenvy::var("KEYSTORE_PASSWORD_FILE")
+        .ok()
+        .filter(|path| !path.is_empty())
+    {
+        Some(path) => {
+            let content = Zeroizing::new(std::fs::read_to_string(&path).map_err(|err| {
+                Error::msg(format!("Error reading passphrase file {}: {}", path, err))
+            })?);
+            Ok(Zeroizing::new(
+                content.trim_end_matches(['\r', '\n']).to_string(),
+            ))
+        }
+        None => Ok(Zeroizing::new(rpassword::prompt_password(
+            "Keystore passphrase: ",
+        )?)),
+    }
+}
+
+/// Unlock every `*.json` keystore in `dir`. Each key is registered under its
+/// file stem as key id.
+pub fn load_keystore_dir(dir: &str, passphrase: &str) -> Result<InMemoryKeyStore, Error> {
+    let mut store = InMemoryKeyStore::new();
+    let entries = std::fs::read_dir(dir)
+        .map_err(|err| Error::msg(format!("Error reading keystore dir {}: {}", dir, err)))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        let key_id = key_id_from_path(&path)?;
+        let content = std::fs::read_to_string(&path)?;
+        let keystore: KeystoreV3 = serde_json::from_str(&content).map_err(|err| {
+            Error::msg(format!(
+                "Error parsing keystore {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        let key = decrypt_keystore(&keystore, passphrase).map_err(|err| {
+            Error::msg(format!(
+                "Error unlocking keystore {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        store.insert(&key_id, key);
+        println!("Unlocked keystore: {}", key_id);
+    }
+    Ok(store)
+}
+
+fn key_id_from_path(path: &Path) -> Result<String, Error> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(|stem| stem.to_string())
+        .ok_or_else(|| Error::msg(format!("Invalid keystore file name: {}", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "testpassword";
+    const SECRET: &str = "7a28b5ba57c53603b0b07b56bba752f7784bf506fa95edc395f5cf6c7514fe9d";
+
+    // Test vectors of the Web3 Secret Storage Definition
+    const PBKDF2_KEYSTORE: &str = r#"{
+        "crypto": {
+            "cipher": "aes-128-ctr",
+            "cipherparams": { "iv": "6087dab2f9fdbbfaddc31a909735c1e6" },
+            "ciphertext": "5318b4d5bcd28de64ee5559e671353e16f075ecae9f99c7a79a38af5f869aa46",
+            "kdf": "pbkdf2",
+            "kdfparams": {
+                "c": 262144,
+                "dklen": 32,
+                "prf": "hmac-sha256",
+                "salt": "ae3cd4e7013836a3df6bd7241b12db061dbe2c6785853cce422d148a624ce0bd"
+            },
+            "mac": "517ead924a9d0dc3124507e3393d175ce3ff7c1e96529c6c555ce9e51205e9b2"
+        },
+        "id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+        "version": 3
+    }"#;
+
+    // The spec's scrypt vector (n 262144, r 1) breaks the RFC 7914 bound, so
+    // the same key is sealed with geth's light scrypt params instead
+    const SCRYPT_KEYSTORE: &str = r#"{
+        "crypto": {
+            "cipher": "aes-128-ctr",
+            "cipherparams": { "iv": "83dbcc02d8ccb40e466191a123791e0e" },
+            "ciphertext": "584f4eb2783472ec149b334c22d77ddbf118a0783ea24e66ca8a7883cd2b4bf5",
+            "kdf": "scrypt",
+            "kdfparams": {
+                "dklen": 32,
+                "n": 4096,
+                "r": 8,
+                "p": 6,
+                "salt": "ab0c7876052600dd703518d6fc3fe8984592145b591fc8fb5c6d43190334ba19"
+            },
+            "mac": "860ad3ce9642f99d709abc696e25ede7cd35daf6a6041e975d14c53dae118886"
+        },
+        "id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+        "version": 3
+    }"#;
+
+    // Same key under a single PBKDF2 round, for tests that only need a
+    // keystore that decrypts
+    const FAST_KEYSTORE: &str = r#"{
+        "crypto": {
+            "cipher": "aes-128-ctr",
+            "cipherparams": { "iv": "6087dab2f9fdbbfaddc31a909735c1e6" },
+            "ciphertext": "2bf4aacce1be4c2d156d85ea8acec6a07d81bb5835d80a977a94a2515c0e831e",
+            "kdf": "pbkdf2",
+            "kdfparams": {
+                "c": 1,
+                "dklen": 32,
+                "prf": "hmac-sha256",
+                "salt": "ae3cd4e7013836a3df6bd7241b12db061dbe2c6785853cce422d148a624ce0bd"
+            },
+            "mac": "74d823bf9806963e681f87edd6930b888eabe06b2d663a2e5fea5dbd82d0c038"
+        },
+        "version": 3
+    }"#;
+
+    const SPEC_SCRYPT_KEYSTORE: &str = r#"{
+        "crypto": {
+            "cipher": "aes-128-ctr",
+            "cipherparams": { "iv": "83dbcc02d8ccb40e466191a123791e0e" },
+            "ciphertext": "d172bf743a674da9cdad04534d56926ef8358534d458fffccd4e6ad2fbde479c",
+            "kdf": "scrypt",
+            "kdfparams": {
+                "dklen": 32,
+                "n": 262144,
+                "r": 1,
+                "p": 8,
+                "salt": "ab0c7876052600dd703518d6fc3fe8984592145b591fc8fb5c6d43190334ba19"
+            },
+            "mac": "2103ac29920d71da29f15d75b4a16dbe95cfd7ff8faea1056c33131d846e3097"
+        },
+        "id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+        "version": 3
+    }"#;
+
+    fn decrypt(keystore: &str, passphrase: &str) -> Result<SecretKey, Error> {
+        decrypt_keystore(&serde_json::from_str(keystore)?, passphrase)
+    }
+
+    #[test]
+    fn decrypts_pbkdf2_vector() {
+        let key = decrypt(PBKDF2_KEYSTORE, PASSWORD).unwrap();
+        assert_eq!(hex::encode(key.secret_bytes()), SECRET);
+    }
+
+    #[test]
+    fn decrypts_scrypt_vector() {
+        let key = decrypt(SCRYPT_KEYSTORE, PASSWORD).unwrap();
+        assert_eq!(hex::encode(key.secret_bytes()), SECRET);
+    }
+
+    #[test]
+    fn rejects_scrypt_params_out_of_rfc_bounds() {
+        let err = decrypt(SPEC_SCRYPT_KEYSTORE, PASSWORD).unwrap_err();
+        assert!(err.to_string().contains("Unsupported scrypt params"));
+    }
+
+    #[test]
+    fn rejects_wrong_passphrase() {
+        assert!(decrypt(PBKDF2_KEYSTORE, "wrongpassword").is_err());
+    }
+
+    fn with(keystore: &str, pointer: &str, value: serde_json::Value) -> String {
+        let mut keystore: serde_json::Value = serde_json::from_str(keystore).unwrap();
+        *keystore.pointer_mut(pointer).unwrap() = value;
+        keystore.to_string()
+    }
+
+    #[test]
+    fn checks_keystore_address() {
+        let keystore = |address: &str| {
+            let mut keystore: serde_json::Value = serde_json::from_str(FAST_KEYSTORE).unwrap();
+            keystore["address"] = address.into();
+            keystore.to_string()
+        };
+        let key = decrypt(
+            &keystore("008aeeda4d805471df9b2a5b0f38a0c3bcba786b"),
+            PASSWORD,
+        )
+        .unwrap();
+        assert_eq!(hex::encode(key.secret_bytes()), SECRET);
+        assert!(decrypt(
+            &keystore("0x008AEEDA4D805471DF9B2A5B0F38A0C3BCBA786B"),
+            PASSWORD
+        )
+        .is_ok());
+        let err = decrypt(
+            &keystore("9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f"),
+            PASSWORD,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("does not match"));
+        assert!(decrypt(&keystore("not an address"), PASSWORD).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_mac() {
+        let keystore = with(
+            FAST_KEYSTORE,
+            "/crypto/mac",
+            "74d823bf9806963e681f87edd6930b888eabe06b2d663a2e5fea5dbd82d0c0".into(),
+        );
+        assert!(decrypt(FAST_KEYSTORE, PASSWORD).is_ok());
+        assert!(decrypt(&keystore, PASSWORD).is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_scrypt_params() {
+        for (n, r, p) in [
+            (2u64, u32::MAX as u64, 1u64),
+            (2, 1 << 28, 1),
+            (16, 1, u32::MAX as u64),
+            (16, 0, 1),
+            (16, 1, 0),
+            (0, 8, 1),
+            (3, 8, 1),
+        ] {
+            let keystore = with(SCRYPT_KEYSTORE, "/crypto/kdfparams/n", n.into());
+            let keystore = with(&keystore, "/crypto/kdfparams/r", r.into());
+            let keystore = with(&keystore, "/crypto/kdfparams/p", p.into());
+            assert!(decrypt(&keystore, PASSWORD).is_err(), "{} {} {}", n, r, p);
+        }
+    }
+}
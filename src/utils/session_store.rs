@@ -0,0 +1,680 @@
+use crate::utils::key_schedule::SessionKeys;
+use anyhow::Error;
+use rand_core::{OsRng, RngCore};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const REDIS_PREFIX: &str = "hsm:session:";
+const REDIS_NONCE_PREFIX: &str = "hsm:nonce:";
+const DEFAULT_TTL_SECS: u64 = 3600;
+const DEFAULT_MAX_MESSAGES: u64 = 1000;
+const DEFAULT_MAX_REQUEST_AGE_SECS: u64 = 120;
+/// Sequence numbers below the highest accepted one that may still arrive.
+const SEQ_WINDOW: u64 = 64;
+
+/// ECDH session negotiated through `/pk`, addressed by its opaque id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    /// SEC1 ephemeral public key the client exchanged.
+    pub client_key: Vec<u8>,
+    pub keys: SessionKeys,
+    pub created_at: u64,
+    pub expires_at: u64,
+    /// Requests opened on the session so far.
+    pub messages: u64,
+    /// Highest request sequence number accepted, 0 before the first request.
+    #[serde(default)]
+    pub last_seq: u64,
+    /// Bit `i` is set once `last_seq - i` was accepted.
+    #[serde(default)]
+    pub seq_window: u64,
+}
+
+impl Session {
+    /// Record `seq`, rejecting numbers already seen or too far behind the
+    /// highest one, so requests may arrive out of order but only once.
+    fn accept_seq(&mut self, seq: u64) -> Result<(), Error> {
+        if seq == 0 {
+            return Err(Error::msg("Sequence numbers start at 1"));
+        }
+        if seq > self.last_seq {
+            let shift = seq - self.last_seq;
+            self.seq_window = if shift >= SEQ_WINDOW {
+                0
+            } else {
+                self.seq_window << shift
+            };
+            self.seq_window |= 1;
+            self.last_seq = seq;
+            return Ok(());
+        }
+        let offset = self.last_seq - seq;
+        if offset >= SEQ_WINDOW {
+            return Err(Error::msg(format!("Stale sequence number {}", seq)));
+        }
+        if self.seq_window & (1 << offset) != 0 {
+            return Err(Error::msg(format!("Replayed sequence number {}", seq)));
+        }
+        self.seq_window |= 1 << offset;
+        Ok(())
+    }
+}
+
+/// Session as listed to administrators, without its keys.
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub client_key: String,
+    pub confirmed: bool,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub messages: u64,
+    pub last_seq: u64,
+}
+
+impl From<&Session> for SessionInfo {
+    fn from(session: &Session) -> Self {
+        SessionInfo {
+            id: session.id.clone(),
+            client_key: format!("0x{}", hex::encode(&session.client_key)),
+            confirmed: session.keys.confirmed,
+            created_at: session.created_at,
+            expires_at: session.expires_at,
+            messages: session.messages,
+            last_seq: session.last_seq,
+        }
+    }
+}
+
+/// Backend holding the sessions. Expired sessions must never be returned.
+pub trait SessionStore: Send + Sync {
+    /// Store a new session until its `expires_at`.
+    fn insert(&self, session: &Session) -> Result<(), Error>;
+
+    fn get(&self, id: &str) -> Result<Option<Session>, Error>;
+
+    /// Apply `update` to the session under `id` and store the result, without
+    /// interleaving with other updates of the same session.
+    fn update(
+        &self,
+        id: &str,
+        update: &mut dyn FnMut(&mut Session) -> Result<(), Error>,
+    ) -> Result<Session, Error>;
+
+    /// Drop the session under `id`, returning whether it existed.
+    fn remove(&self, id: &str) -> Result<bool, Error>;
+
+    fn list(&self) -> Result<Vec<Session>, Error>;
+
+    /// Remember `nonce` for `ttl` seconds, returning `false` if it is
+    /// already remembered.
+    fn remember(&self, nonce: &str, ttl: u64) -> Result<bool, Error>;
+}
+
+/// Sessions held in process memory; lost on restart.
+#[derive(Default)]
+pub struct InMemorySessionStore {
+    sessions: Mutex<HashMap<String, Session>>,
+    /// Remembered nonces with the time they expire at.
+    nonces: Mutex<HashMap<String, u64>>,
+}
+
+impl InMemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sessions not yet expired, dropping the expired ones.
+    fn live(&self) -> Result<MutexGuard<'_, HashMap<String, Session>>, Error> {
+        let mut sessions = self
+            .sessions
+            .lock()
+            .map_err(|_| Error::msg("Session store lock poisoned"))?;
+        let now = now();
+        sessions.retain(|_, session| session.expires_at > now);
+        Ok(sessions)
+    }
+}
+
+impl SessionStore for InMemorySessionStore {
+    fn insert(&self, session: &Session) -> Result<(), Error> {
+        self.live()?.insert(session.id.clone(), session.clone());
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Session>, Error> {
+        Ok(self.live()?.get(id).cloned())
+    }
+
+    fn update(
+        &self,
+        id: &str,
+        update: &mut dyn FnMut(&mut Session) -> Result<(), Error>,
+    ) -> Result<Session, Error> {
+        let mut sessions = self.live()?;
+        let session = sessions
+            .get_mut(id)
+            .ok_or_else(|| Error::msg("Unknown or expired session"))?;
+        let mut updated = session.clone();
+        update(&mut updated)?;
+        *session = updated.clone();
+        Ok(updated)
+    }
+
+    fn remove(&self, id: &str) -> Result<bool, Error> {
+        Ok(self.live()?.remove(id).is_some())
+    }
+
+    fn list(&self) -> Result<Vec<Session>, Error> {
+        Ok(self.live()?.values().cloned().collect())
+    }
+
+    fn remember(&self, nonce: &str, ttl: u64) -> Result<bool, Error> {
+        let mut nonces = self
+            .nonces
+            .lock()
+            .map_err(|_| Error::msg("Session store lock poisoned"))?;
+        let now = now();
+        nonces.retain(|_, expires_at| *expires_at > now);
+        if nonces.contains_key(nonce) {
+            return Ok(false);
+        }
+        nonces.insert(nonce.to_string(), now.saturating_add(ttl));
+        Ok(true)
+    }
+}
+
+/// Sessions stored as JSON under `hsm:session:<id>`, expired by Redis itself.
+pub struct RedisSessionStore {
+    client: redis::Client,
+}
+
+impl RedisSessionStore {
+    pub fn open(url: &str) -> Result<Self, Error> {
+        Ok(RedisSessionStore {
+            client: redis::Client::open(url)?,
+        })
+    }
+
+    /// Connect to `RED_URL`.
+    pub fn from_env() -> Result<Self, Error> {
+        let url = dotenvy::var("RED_URL").map_err(|_| Error::msg("Redis URL not found"))?;
+        Self::open(&url)
+    }
+
+    fn connection(&self) -> Result<redis::Connection, Error> {
+        Ok(self.client.get_connection()?)
+    }
+}
+
+impl SessionStore for RedisSessionStore {
+    fn insert(&self, session: &Session) -> Result<(), Error> {
+        let ttl = remaining_secs(session)?;
+        let key = format!("{}{}", REDIS_PREFIX, session.id);
+        self.connection()?
+            .set_ex::<_, _, ()>(key, serde_json::to_string(session)?, ttl)?;
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Session>, Error> {
+        let stored: Option<String> = self.connection()?.get(format!("{}{}", REDIS_PREFIX, id))?;
+        match stored {
+            Some(stored) => Ok(Some(serde_json::from_str(&stored)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn update(
+        &self,
+        id: &str,
+        update: &mut dyn FnMut(&mut Session) -> Result<(), Error>,
+    ) -> Result<Session, Error> {
+        let key = format!("{}{}", REDIS_PREFIX, id);
+        let mut con = self.connection()?;
+        let mut outcome = None;
+        // WATCH the key so a concurrent update aborts this one and it is retried
+        redis::transaction(&mut con, &[&key], |con, pipe| {
+            let stored: Option<String> = con.get(&key)?;
+            let result = stored
+                .ok_or_else(|| Error::msg("Unknown or expired session"))
+                .and_then(|stored| Ok(serde_json::from_str::<Session>(&stored)?))
+                .and_then(|mut session| {
+                    update(&mut session)?;
+                    let ttl = remaining_secs(&session)?;
+                    Ok((session, ttl))
+                });
+            let (session, ttl) = match result {
+                Ok(updated) => updated,
+                Err(err) => {
+                    outcome = Some(Err(err));
+                    return Ok(Some(()));
+                }
+            };
+            let stored = match serde_json::to_string(&session) {
+                Ok(stored) => stored,
+                Err(err) => {
+                    outcome = Some(Err(err.into()));
+                    return Ok(Some(()));
+                }
+            };
+            let committed: Option<()> = pipe.set_ex(&key, stored, ttl).ignore().query(con)?;
+            if committed.is_some() {
+                outcome = Some(Ok(session));
+            }
+            Ok(committed)
+        })?;
+        outcome.unwrap_or_else(|| Err(Error::msg("Session update did not complete")))
+    }
+
+    fn remove(&self, id: &str) -> Result<bool, Error> {
+        let removed: u64 = self.connection()?.del(format!("{}{}", REDIS_PREFIX, id))?;
+        Ok(removed > 0)
+    }
+
+    fn list(&self) -> Result<Vec<Session>, Error> {
+        let mut con = self.connection()?;
+        let keys: Vec<String> = con
+            .scan_match::<_, String>(format!("{}*", REDIS_PREFIX))?
+            .collect();
+        let mut sessions = Vec::with_capacity(keys.len());
+        for key in keys {
+            // the session may have expired since the scan
+            if let Some(stored) = con.get::<_, Option<String>>(&key)? {
+                sessions.push(serde_json::from_str(&stored)?);
+            }
+        }
+        Ok(sessions)
+    }
+
+    fn remember(&self, nonce: &str, ttl: u64) -> Result<bool, Error> {
+        // SET NX only stores the key if it is not there yet
+        let stored: Option<String> = redis::cmd("SET")
+            .arg(format!("{}{}", REDIS_NONCE_PREFIX, nonce))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl)
+            .query(&mut self.connection()?)?;
+        Ok(stored.is_some())
+    }
+}
+
+/// Session store with the lifetime and message limits sessions are held to.
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    /// Lifetime of a session in seconds.
+    ttl: u64,
+    /// Requests a session accepts before the client must exchange keys again.
+    max_messages: u64,
+    /// Largest difference between a request timestamp and the server clock.
+    max_request_age: u64,
+}
+
+impl Sessions {
+    pub fn new(
+        store: Arc<dyn SessionStore>,
+        ttl: u64,
+        max_messages: u64,
+        max_request_age: u64,
+    ) -> Self {
+        Sessions {
+            store,
+            ttl,
+            max_messages,
+            max_request_age,
+        }
+    }
+
+    /// Build from `SESSION_STORE` (redis | memory), `SESSION_TTL_SECS`,
+    /// `SESSION_MAX_MESSAGES` and `REQUEST_MAX_AGE_SECS`.
+    pub fn from_env() -> Result<Self, Error> {
+        let kind = dotenvy::var("SESSION_STORE").unwrap_or_else(|_| "redis".to_string());
+        let store: Arc<dyn SessionStore> = match kind.as_str() {
+            "redis" => Arc::new(RedisSessionStore::from_env()?),
+            "memory" => Arc::new(InMemorySessionStore::new()),
+            other => return Err(Error::msg(format!("Unknown session store: {}", other))),
+        };
+        let ttl = env_u64("SESSION_TTL_SECS", DEFAULT_TTL_SECS)?;
+        let max_messages = env_u64("SESSION_MAX_MESSAGES", DEFAULT_MAX_MESSAGES)?;
+        let max_request_age = env_u64("REQUEST_MAX_AGE_SECS", DEFAULT_MAX_REQUEST_AGE_SECS)?;
+        Ok(Sessions::new(store, ttl, max_messages, max_request_age))
+    }
+
+    /// Start a session over `keys`, awaiting the client's key confirmation.
+    pub fn open(&self, client_key: &[u8], keys: SessionKeys) -> Result<Session, Error> {
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        let created_at = now();
+        let session = Session {
+            id: hex::encode(id),
+            client_key: client_key.to_vec(),
+            keys,
+            created_at,
+            expires_at: created_at.saturating_add(self.ttl),
+            messages: 0,
+            last_seq: 0,
+            seq_window: 0,
+        };
+        self.store.insert(&session)?;
+        Ok(session)
+    }
+
+    /// Check the client's key confirmation tag and accept requests on `id`.
+    pub fn confirm(&self, id: &str, tag: &[u8]) -> Result<(), Error> {
+        self.store.update(id, &mut |session| {
+            session.keys.verify_client_confirmation(tag)?;
+            session.keys.confirmed = true;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Keys of the confirmed session `id`.
+    pub fn keys(&self, id: &str) -> Result<SessionKeys, Error> {
+        let session = self
+            .store
+            .get(id)?
+            .ok_or_else(|| Error::msg("Unknown or expired session"))?;
+        if !session.keys.confirmed {
+            return Err(Error::msg("Session key not confirmed"));
+        }
+        Ok(session.keys)
+    }
+
+    /// Accept an authenticated request numbered `seq` and sent at `timestamp` on
+    /// session `id`, rejecting replays, stale requests and requests past the
+    /// session's message limit.
+    pub fn next_message(&self, id: &str, seq: u64, timestamp: u64) -> Result<(), Error> {
+        self.check_timestamp(timestamp)?;
+        let max_messages = self.max_messages;
+        self.store.update(id, &mut |session| {
+            if !session.keys.confirmed {
+                return Err(Error::msg("Session key not confirmed"));
+            }
+            if session.messages >= max_messages {
+                return Err(Error::msg("Session message limit reached"));
+            }
+            session.accept_seq(seq)?;
+            session.messages += 1;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Reject a request `timestamp` further than `REQUEST_MAX_AGE_SECS` from
+    /// the server clock.
+    pub fn check_timestamp(&self, timestamp: u64) -> Result<(), Error> {
+        if now().abs_diff(timestamp) > self.max_request_age {
+            return Err(Error::msg(format!(
+                "Request timestamp {} is outside the allowed window",
+                timestamp
+            )));
+        }
+        Ok(())
+    }
+
+    /// Accept a request sent at `timestamp` and identified by `nonce` once:
+    /// the nonce is remembered for as long as the timestamp is within the
+    /// window, so the same request is refused if it is sent again.
+    pub fn accept_once(&self, nonce: &str, timestamp: u64) -> Result<(), Error> {
+        self.check_timestamp(timestamp)?;
+        let ttl = timestamp
+            .saturating_add(self.max_request_age)
+            .saturating_sub(now())
+            .saturating_add(1);
+        if !self.store.remember(nonce, ttl)? {
+            return Err(Error::msg("Replayed request"));
+        }
+        Ok(())
+    }
+
+    pub fn list(&self) -> Result<Vec<SessionInfo>, Error> {
+        let mut sessions: Vec<SessionInfo> =
+            self.store.list()?.iter().map(SessionInfo::from).collect();
+        sessions.sort_by_key(|session| session.created_at);
+        Ok(sessions)
+    }
+
+    pub fn revoke(&self, id: &str) -> Result<bool, Error> {
+        self.store.remove(id)
+    }
+}
+
+fn env_u64(name: &str, default: u64) -> Result<u64, Error> {
+    match dotenvy::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| Error::msg(format!("Invalid {}: {}", name, value))),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Seconds until `session` expires, failing once it has.
+fn remaining_secs(session: &Session) -> Result<usize, Error> {
+    match session.expires_at.checked_sub(now()) {
+        Some(remaining) if remaining > 0 => Ok(remaining as usize),
+        _ => Err(Error::msg("Unknown or expired session")),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> SessionKeys {
+        SessionKeys::derive(&[7u8; 32], b"transcript").unwrap()
+    }
+
+    fn sessions(ttl: u64) -> Sessions {
+        Sessions::new(Arc::new(InMemorySessionStore::new()), ttl, 10, 60)
+    }
+
+    #[test]
+    fn expires_sessions_after_ttl() {
+        let store = InMemorySessionStore::new();
+        let mut session = sessions(60).open(b"client", keys()).unwrap();
+        store.insert(&session).unwrap();
+        session.id = "expired".to_string();
+        session.expires_at = now() - 1;
+        store.insert(&session).unwrap();
+        assert!(store.get("expired").unwrap().is_none());
+        assert!(store.update("expired", &mut |_| Ok(())).is_err());
+        assert!(!store.remove("expired").unwrap());
+        assert_eq!(store.list().unwrap().len(), 1);
+
+        // A zero TTL expires the session as soon as it is opened
+        let sessions = sessions(0);
+        let session = sessions.open(b"client", keys()).unwrap();
+        assert!(sessions.keys(&session.id).is_err());
+        assert!(sessions.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn lists_and_revokes_sessions() {
+        let sessions = sessions(60);
+        let first = sessions.open(b"first", keys()).unwrap();
+        let second = sessions.open(b"second", keys()).unwrap();
+        assert_ne!(first.id, second.id);
+        let listed = sessions.list().unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(listed.iter().all(|info| !info.confirmed));
+        let info = listed.iter().find(|info| info.id == first.id).unwrap();
+        assert_eq!(info.client_key, format!("0x{}", hex::encode(b"first")));
+        assert_eq!(info.expires_at, first.created_at + 60);
+
+        assert!(sessions.revoke(&first.id).unwrap());
+        assert!(!sessions.revoke(&first.id).unwrap());
+        assert!(sessions.keys(&first.id).is_err());
+        let listed = sessions.list().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, second.id);
+    }
+
+    #[test]
+    fn reports_poisoned_lock() {
+        let store = Arc::new(InMemorySessionStore::new());
+        let poisoner = store.clone();
+        let _ = std::thread::spawn(move || {
+            let _sessions = poisoner.sessions.lock().unwrap();
+            panic!("poison the session lock");
+        })
+        .join();
+        assert!(store.list().is_err());
+        assert!(store.get("any").is_err());
+    }
+
+    fn session() -> Session {
+        sessions(60).open(b"client", keys()).unwrap()
+    }
+
+    #[test]
+    fn accepts_sequence_numbers_in_order() {
+        let mut session = session();
+        for seq in 1..=100 {
+            session.accept_seq(seq).unwrap();
+            assert_eq!(session.last_seq, seq);
+        }
+    }
+
+    #[test]
+    fn accepts_out_of_order_within_window() {
+        let mut session = session();
+        session.accept_seq(10).unwrap();
+        session.accept_seq(5).unwrap();
+        session.accept_seq(9).unwrap();
+        session.accept_seq(1).unwrap();
+        assert_eq!(session.last_seq, 10);
+        session.accept_seq(11).unwrap();
+        session.accept_seq(8).unwrap();
+        // 7 is the oldest number still inside the window of 70
+        session.accept_seq(70).unwrap();
+        session.accept_seq(7).unwrap();
+        assert!(session.accept_seq(6).is_err());
+    }
+
+    #[test]
+    fn rejects_replays_and_zero() {
+        let mut session = session();
+        assert!(session.accept_seq(0).is_err());
+        session.accept_seq(3).unwrap();
+        assert!(session.accept_seq(3).is_err());
+        session.accept_seq(2).unwrap();
+        assert!(session.accept_seq(2).is_err());
+        session.accept_seq(4).unwrap();
+        assert!(session.accept_seq(2).is_err());
+        assert!(session.accept_seq(3).is_err());
+        assert!(session.accept_seq(4).is_err());
+        assert!(session.accept_seq(0).is_err());
+    }
+
+    #[test]
+    fn rejects_numbers_outside_window() {
+        let mut session = session();
+        session.accept_seq(100).unwrap();
+        assert!(session.accept_seq(100 - SEQ_WINDOW).is_err());
+        assert!(session.accept_seq(1).is_err());
+        session.accept_seq(100 - (SEQ_WINDOW - 1)).unwrap();
+    }
+
+    #[test]
+    fn jump_past_window_clears_it() {
+        let mut session = session();
+        session.accept_seq(1).unwrap();
+        session.accept_seq(2).unwrap();
+        session.accept_seq(2 + SEQ_WINDOW).unwrap();
+        assert_eq!(session.seq_window, 1);
+        assert!(session.accept_seq(2).is_err());
+        // Numbers skipped by the jump are still accepted once
+        session.accept_seq(3).unwrap();
+        assert!(session.accept_seq(3).is_err());
+        session.accept_seq(u64::MAX).unwrap();
+        assert_eq!(session.seq_window, 1);
+        assert!(session.accept_seq(2 + SEQ_WINDOW).is_err());
+    }
+
+    fn confirmed(sessions: &Sessions) -> String {
+        let session = sessions.open(b"client", keys()).unwrap();
+        sessions
+            .store
+            .update(&session.id, &mut |session| {
+                session.keys.confirmed = true;
+                Ok(())
+            })
+            .unwrap();
+        session.id
+    }
+
+    #[test]
+    fn next_message_checks_timestamp_window() {
+        let sessions = sessions(60);
+        let id = confirmed(&sessions);
+        let now = now();
+        // Stay clear of the 60 second boundary the clock may cross meanwhile
+        sessions.next_message(&id, 1, now).unwrap();
+        sessions.next_message(&id, 2, now - 55).unwrap();
+        sessions.next_message(&id, 3, now + 55).unwrap();
+        assert!(sessions.next_message(&id, 4, now - 65).is_err());
+        assert!(sessions.next_message(&id, 5, now + 65).is_err());
+        // Rejected requests do not use up their sequence numbers
+        sessions.next_message(&id, 4, now).unwrap();
+        assert_eq!(sessions.store.get(&id).unwrap().unwrap().messages, 4);
+    }
+
+    #[test]
+    fn next_message_enforces_message_limit() {
+        let sessions = sessions(60);
+        let id = confirmed(&sessions);
+        for seq in 1..=10 {
+            sessions.next_message(&id, seq, now()).unwrap();
+        }
+        assert!(sessions.next_message(&id, 11, now()).is_err());
+        // Replays are rejected without counting against the limit
+        let id = confirmed(&sessions);
+        sessions.next_message(&id, 1, now()).unwrap();
+        assert!(sessions.next_message(&id, 1, now()).is_err());
+        assert_eq!(sessions.store.get(&id).unwrap().unwrap().messages, 1);
+    }
+
+    #[test]
+    fn accepts_requests_once() {
+        let sessions = sessions(60);
+        let now = now();
+        sessions.accept_once("client:first", now).unwrap();
+        assert!(sessions.accept_once("client:first", now).is_err());
+        sessions.accept_once("client:second", now - 30).unwrap();
+        assert!(sessions.accept_once("client:second", now - 30).is_err());
+        // Requests outside the window are refused without being remembered
+        assert!(sessions.accept_once("client:third", now - 120).is_err());
+        sessions.accept_once("client:third", now).unwrap();
+    }
+
+    #[test]
+    fn forgets_nonces_after_ttl() {
+        let store = InMemorySessionStore::new();
+        assert!(store.remember("expired", 0).unwrap());
+        assert!(store.remember("expired", 60).unwrap());
+        assert!(!store.remember("expired", 60).unwrap());
+        assert!(store.remember("other", 60).unwrap());
+    }
+
+    #[test]
+    fn next_message_requires_confirmed_session() {
+        let sessions = sessions(60);
+        let session = sessions.open(b"client", keys()).unwrap();
+        assert!(sessions.next_message(&session.id, 1, now()).is_err());
+        assert!(sessions.next_message("unknown", 1, now()).is_err());
+    }
+}